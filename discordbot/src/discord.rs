@@ -0,0 +1,262 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Sink, Stream, IntoFuture};
+use futures::sync::mpsc;
+use reqwest::async::Client;
+use reqwest::header::AUTHORIZATION;
+use serde_json::{self, Value};
+use tokio;
+use tokio::timer::Interval;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use url::Url;
+
+static API_BASE: &str = "https://discord.com/api/v10";
+
+// GUILDS | GUILD_MESSAGES | DIRECT_MESSAGES | MESSAGE_CONTENT
+const INTENTS: u64 = 1 | 1 << 9 | 1 << 12 | 1 << 15;
+
+pub type BoxFuture<T> = Box<dyn Future<Item = T, Error = String> + Send>;
+pub type BoxStream<T> = Box<dyn Stream<Item = T, Error = String> + Send>;
+
+#[derive(Clone, Deserialize, PartialEq, Debug)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    #[serde(default)]
+    pub bot: bool
+}
+
+#[derive(Clone, Deserialize, PartialEq, Debug)]
+pub struct Member {
+    pub user: Option<User>
+}
+
+#[derive(Clone, Deserialize, PartialEq, Debug)]
+pub struct Message {
+    pub id: String,
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+    pub author: User,
+    #[serde(default)]
+    pub content: String
+}
+
+#[derive(Clone, Deserialize, PartialEq, Debug)]
+pub struct CommandOption {
+    pub name: String,
+    pub value: Option<Value>
+}
+
+#[derive(Clone, Deserialize, PartialEq, Debug)]
+pub struct CommandData {
+    pub name: String,
+    #[serde(default)]
+    pub options: Vec<CommandOption>
+}
+
+#[derive(Clone, Deserialize, PartialEq, Debug)]
+pub struct Interaction {
+    pub id: String,
+    pub application_id: String,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub token: String,
+    pub channel_id: Option<String>,
+    pub guild_id: Option<String>,
+    pub member: Option<Member>,
+    pub user: Option<User>,
+    pub data: Option<CommandData>
+}
+
+impl Interaction {
+    pub fn user(&self) -> Option<&User> {
+        self.member.as_ref().and_then(|m| m.user.as_ref()).or(self.user.as_ref())
+    }
+
+    pub fn option(&self, name: &str) -> Option<&Value> {
+        self.data.as_ref()
+            .and_then(|d| d.options.iter().find(|o| o.name == name))
+            .and_then(|o| o.value.as_ref())
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Event {
+    Ready { application_id: String },
+    MessageCreate(Message),
+    InteractionCreate(Interaction)
+}
+
+#[derive(Clone, Serialize, PartialEq, Debug)]
+pub struct SlashCommandOption {
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub name: String,
+    pub description: String,
+    pub required: bool
+}
+
+#[derive(Clone, Serialize, PartialEq, Debug)]
+pub struct SlashCommand {
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub name: String,
+    pub description: String,
+    pub options: Vec<SlashCommandOption>
+}
+
+/// A source of gateway events. Each call to `events` is one gateway session; the stream ends
+/// or fails when the session does.
+pub trait Gateway: Send + Sync + 'static {
+    fn events(&self) -> BoxStream<Event>;
+}
+
+/// The subset of the Discord REST API the bot uses.
+pub trait Rest: Send + Sync + 'static {
+    fn create_message(&self, channel_id: &str, reply_to: Option<&str>, content: String) -> BoxFuture<()>;
+    fn overwrite_commands(&self, application_id: &str, commands: Vec<SlashCommand>) -> BoxFuture<()>;
+    fn defer_interaction(&self, interaction_id: &str, token: &str) -> BoxFuture<()>;
+    fn edit_interaction_response(&self, application_id: &str, token: &str, content: String) -> BoxFuture<()>;
+    fn leave_guild(&self, guild_id: &str) -> BoxFuture<()>;
+}
+
+pub struct WsGateway {
+    url: String,
+    token: String
+}
+
+impl WsGateway {
+    pub fn new(url: String, token: String) -> Self {
+        WsGateway { url, token }
+    }
+}
+
+fn decode_event(kind: &str, data: Value) -> Result<Option<Event>, String> {
+    match kind {
+        "READY" => data.get("application")
+            .and_then(|a| a.get("id"))
+            .and_then(Value::as_str)
+            .map(|id| Some(Event::Ready { application_id: id.to_owned() }))
+            .ok_or_else(|| "READY without application ID".to_owned()),
+        "MESSAGE_CREATE" => serde_json::from_value(data)
+            .map(|m| Some(Event::MessageCreate(m)))
+            .map_err(|e| format!("could not decode message: {}", e)),
+        "INTERACTION_CREATE" => serde_json::from_value(data)
+            .map(|i| Some(Event::InteractionCreate(i)))
+            .map_err(|e| format!("could not decode interaction: {}", e)),
+        _ => Ok(None)
+    }
+}
+
+impl Gateway for WsGateway {
+    fn events(&self) -> BoxStream<Event> {
+        let url = match Url::parse(&self.url) {
+            Ok(url) => url,
+            Err(e) => return Box::new(Err(format!("invalid gateway URL: {}", e)).into_future().into_stream())
+        };
+        let token = self.token.clone();
+        Box::new(connect_async(url)
+            .map_err(|e| format!("error connecting to gateway: {}", e))
+            .map(move |(ws, _)| {
+                let (sink, stream) = ws.split();
+                let (tx, rx) = mpsc::unbounded::<WsMessage>();
+                tokio::spawn(rx.forward(sink.sink_map_err(|e| error!("error writing to gateway: {}", e)))
+                    .map(|_| ()));
+                let seq = Arc::new(Mutex::new(None::<u64>));
+                stream
+                    .map_err(|e| format!("error reading from gateway: {}", e))
+                    .and_then(move |msg| {
+                        let text = match msg {
+                            WsMessage::Text(text) => text,
+                            WsMessage::Close(frame) => return Err(format!("gateway closed: {:?}", frame)),
+                            _ => return Ok(None)
+                        };
+                        let payload: Value = serde_json::from_str(&text)
+                            .map_err(|e| format!("could not parse gateway payload: {}", e))?;
+                        if let (Some(s), Ok(mut seq)) = (payload["s"].as_u64(), seq.lock()) {
+                            *seq = Some(s);
+                        }
+                        match payload["op"].as_u64() {
+                            Some(0) => decode_event(payload["t"].as_str().unwrap_or(""), payload["d"].clone()),
+                            Some(7) => Err("gateway requested reconnect".to_owned()),
+                            Some(9) => Err("gateway invalidated session".to_owned()),
+                            Some(10) => {
+                                let interval = Duration::from_millis(payload["d"]["heartbeat_interval"]
+                                    .as_u64().unwrap_or(41250));
+                                let (hb_tx, hb_seq) = (tx.clone(), seq.clone());
+                                tokio::spawn(Interval::new(Instant::now() + interval, interval)
+                                    .map_err(|e| error!("heartbeat timer failed: {}", e))
+                                    .for_each(move |_| {
+                                        let seq = hb_seq.lock().ok().and_then(|s| *s);
+                                        hb_tx.unbounded_send(WsMessage::Text(json!({ "op": 1, "d": seq }).to_string()))
+                                            .map_err(|_| debug!("gateway session ended, stopping heartbeat"))
+                                    }));
+                                tx.unbounded_send(WsMessage::Text(json!({
+                                    "op": 2,
+                                    "d": {
+                                        "token": token,
+                                        "intents": INTENTS,
+                                        "properties": { "os": "linux", "browser": "evalbot", "device": "evalbot" }
+                                    }
+                                }).to_string())).map_err(|e| format!("error sending identify: {}", e))?;
+                                Ok(None)
+                            }
+                            _ => Ok(None)
+                        }
+                    })
+                    .filter_map(|e| e)
+            })
+            .flatten_stream())
+    }
+}
+
+pub struct HttpRest {
+    client: Client,
+    token: String
+}
+
+impl HttpRest {
+    pub fn new(token: String) -> Self {
+        HttpRest { client: Client::new(), token }
+    }
+
+    fn send(&self, req: reqwest::async::RequestBuilder, what: &'static str) -> BoxFuture<()> {
+        Box::new(req.header(AUTHORIZATION, format!("Bot {}", self.token))
+            .send()
+            .and_then(|r| r.error_for_status())
+            .map(|_| ())
+            .map_err(move |e| format!("error {}: {}", what, e)))
+    }
+}
+
+impl Rest for HttpRest {
+    fn create_message(&self, channel_id: &str, reply_to: Option<&str>, content: String) -> BoxFuture<()> {
+        let mut body = json!({ "content": content, "allowed_mentions": { "parse": [] } });
+        if let Some(id) = reply_to {
+            body["message_reference"] = json!({ "message_id": id, "fail_if_not_exists": false });
+        }
+        self.send(self.client.post(&format!("{}/channels/{}/messages", API_BASE, channel_id)).json(&body),
+            "creating message")
+    }
+
+    fn overwrite_commands(&self, application_id: &str, commands: Vec<SlashCommand>) -> BoxFuture<()> {
+        self.send(self.client.put(&format!("{}/applications/{}/commands", API_BASE, application_id)).json(&commands),
+            "registering commands")
+    }
+
+    fn defer_interaction(&self, interaction_id: &str, token: &str) -> BoxFuture<()> {
+        self.send(self.client.post(&format!("{}/interactions/{}/{}/callback", API_BASE, interaction_id, token))
+            .json(&json!({ "type": 5 })), "deferring interaction")
+    }
+
+    fn edit_interaction_response(&self, application_id: &str, token: &str, content: String) -> BoxFuture<()> {
+        self.send(self.client.patch(&format!("{}/webhooks/{}/{}/messages/@original", API_BASE, application_id, token))
+            .json(&json!({ "content": content, "allowed_mentions": { "parse": [] } })), "editing interaction response")
+    }
+
+    fn leave_guild(&self, guild_id: &str) -> BoxFuture<()> {
+        self.send(self.client.delete(&format!("{}/users/@me/guilds/{}", API_BASE, guild_id)), "leaving guild")
+    }
+}
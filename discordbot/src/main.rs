@@ -0,0 +1,563 @@
+extern crate evalbotlib as backend;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
+#[macro_use] extern crate log;
+extern crate toml;
+extern crate futures;
+extern crate tokio;
+extern crate tokio_tungstenite;
+extern crate reqwest;
+extern crate url;
+extern crate env_logger;
+
+mod discord;
+
+use backend::{EvalService, Language, smoke, util};
+use backend::chat::{Command, Dispatcher, Frontend, MessageSource, Reply, ReplyFuture, ReplySink, Whitelist};
+use backend::quota::{QuotaCfg, Quotas, Usage};
+use discord::{Event, Gateway, HttpRest, Interaction, Message, Rest, SlashCommand, SlashCommandOption, WsGateway};
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream, IntoFuture};
+use futures::future::{self, Loop};
+use tokio::timer::Delay;
+
+macro_rules! nullify_future {
+    ($task:expr, $fut:expr) => ($fut.map(|_| ())
+        .or_else(|e| Ok(error!("error {}: {}", $task, e))));
+}
+
+static WHITELIST_FILENAME: &str = "dcwhitelist.toml";
+static QUOTA_FILENAME: &str = "dcquota.toml";
+static DEFAULT_GATEWAY_URL: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+
+const MAX_MESSAGE_LEN: usize = 2000;
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+struct DcCfg {
+    owners: HashSet<String>,
+    bot_token: String,
+    gateway_url: Option<String>,
+    prefix: String,
+    allow_direct: bool,
+    allowed_guilds: HashSet<String>,
+    allowed_channels: HashSet<String>,
+    quota: Option<QuotaCfg>
+}
+
+impl DcCfg {
+    fn channel_ok(&self, guild_id: Option<&str>, channel_id: &str) -> bool {
+        self.allowed_channels.contains(channel_id) || match guild_id {
+            Some(guild_id) => self.allowed_guilds.contains(guild_id),
+            None => self.allow_direct
+        }
+    }
+}
+
+struct DcFrontend {
+    prefix: String
+}
+
+impl Frontend for DcFrontend {
+    fn context_prefix(&self) -> &str {
+        "dc"
+    }
+
+    fn command_prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn extract_code(&self, text: &str) -> String {
+        extract_code(text)
+    }
+}
+
+enum DcTarget {
+    Message { channel_id: String, message_id: String },
+    Interaction { application_id: String, token: String }
+}
+
+struct DcSink<R> {
+    rest: Arc<R>,
+    target: DcTarget
+}
+
+impl<R: Rest> ReplySink for DcSink<R> {
+    fn reply(&self, reply: Reply) -> ReplyFuture {
+        let content = match reply {
+            Reply::Output(r) => discord_wrap_result(&r),
+            // no way to ask for access from Discord yet
+            Reply::Text(t) | Reply::NotAllowed(t) => discord_wrap_error(&t)
+        };
+        Box::new(nullify_future!("sending message", match self.target {
+            DcTarget::Message { ref channel_id, ref message_id } =>
+                self.rest.create_message(channel_id, Some(message_id), content),
+            DcTarget::Interaction { ref application_id, ref token } =>
+                self.rest.edit_interaction_response(application_id, token, content)
+        }))
+    }
+
+    fn leave_chat(&self, chat_id: i64) -> ReplyFuture {
+        Box::new(nullify_future!("leaving guild", self.rest.leave_guild(&chat_id.to_string())))
+    }
+}
+
+struct DcSvc<R> {
+    config: DcCfg,
+    rest: Arc<R>,
+    dispatcher: Arc<Dispatcher<DcFrontend>>,
+    commands: HashMap<String, Arc<Language>>
+}
+
+/// Discord's IDs as the `Dispatcher` keeps them; `None` for anything that isn't one, whose event is
+/// then dropped.
+fn snowflake(id: &str) -> Option<i64> {
+    let r = id.parse().ok();
+    if r.is_none() {
+        warn!("dropping event with invalid ID {:?}", id);
+    }
+    r
+}
+
+fn discord_wrap_result(s: &str) -> String {
+    // FIXME configurable max-lines
+    if s.is_empty() {
+        return "no output".to_owned();
+    }
+
+    static TRUNCATED: &str = "... (truncated)";
+    let cleaned = s
+        .replace(|c: char| c == '\u{FFFD}' || (c.is_control() && c != '\n' && c != '\t'), "")
+        .replace("```", "`\u{200B}`\u{200B}`");
+    let cleaned = cleaned.trim_end_matches('\n');
+    let budget = MAX_MESSAGE_LEN - "```\n\n```".len();
+    if cleaned.chars().count() <= budget {
+        format!("```\n{}\n```", cleaned)
+    } else {
+        let cut = cleaned.chars().take(budget - TRUNCATED.len()).collect::<String>();
+        format!("```\n{}\n```{}", cut, TRUNCATED)
+    }
+}
+
+fn discord_wrap_error(s: &str) -> String {
+    if s.chars().count() <= MAX_MESSAGE_LEN {
+        s.to_owned()
+    } else {
+        s.chars().take(MAX_MESSAGE_LEN).collect()
+    }
+}
+
+/// Takes the code out of the first triple-backtick block in `text`, dropping the block's
+/// language tag if it has one. Without a block, a single pair of backticks is stripped.
+fn extract_code(text: &str) -> String {
+    let text = text.trim();
+    let mut code = if let Some(start) = text.find("```") {
+        let rest = &text[start + 3..];
+        let block = &rest[..rest.find("```").unwrap_or(rest.len())];
+        match block.find('\n') {
+            Some(nl) if block[..nl].trim().is_empty() || (block[..nl].starts_with(char::is_alphabetic)
+                && block[..nl].chars().all(|c| c.is_alphanumeric() || "+#-_!".contains(c))) =>
+                block[nl + 1..].to_owned(),
+            _ => block.to_owned()
+        }
+    } else if text.len() >= 2 && text.starts_with('`') && text.ends_with('`') {
+        text[1..text.len() - 1].to_owned()
+    } else {
+        text.to_owned()
+    };
+    code.push('\n');
+    code
+}
+
+/// Slash command names may only contain lowercase letters, digits, `-` and `_`.
+fn slash_command_name(lang: &str) -> Option<String> {
+    let name = lang.chars().map(|c| match c {
+        '+' => "p".to_owned(),
+        '#' => "sharp".to_owned(),
+        '!' => "_".to_owned(),
+        c => c.to_lowercase().collect()
+    }).collect::<String>();
+    if !name.is_empty() && name.len() <= 32
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+fn handle_event<R: Rest>(me: &Arc<DcSvc<R>>, event: Event) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    match event {
+        Event::Ready { application_id } => handle_ready(me, application_id),
+        Event::MessageCreate(msg) => handle_message(me, msg),
+        Event::InteractionCreate(int) => handle_interaction(me, int)
+    }
+}
+
+fn handle_ready<R: Rest>(me: &Arc<DcSvc<R>>, application_id: String) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    info!("gateway ready, application ID {}", application_id);
+    let mut commands = me.commands.iter().map(|(name, lang)| SlashCommand {
+        kind: 1,
+        name: name.clone(),
+        description: format!("Evaluate {}", lang.name()),
+        options: vec![
+            SlashCommandOption {
+                kind: 3,
+                name: "code".to_owned(),
+                description: "The code to evaluate".to_owned(),
+                required: true
+            },
+            SlashCommandOption {
+                kind: 5,
+                name: "nolimit".to_owned(),
+                description: "Disable the time limit (owners only)".to_owned(),
+                required: false
+            }
+        ]
+    }).collect::<Vec<_>>();
+    commands.sort_by(|a, b| a.name.cmp(&b.name));
+    Box::new(nullify_future!("registering commands", me.rest.overwrite_commands(&application_id, commands)))
+}
+
+fn handle_message<R: Rest>(me: &Arc<DcSvc<R>>, msg: Message) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    if msg.author.bot || !msg.content.starts_with(&me.config.prefix) {
+        return Box::new(Ok(()).into_future());
+    }
+    let (chat_id, user_id) = match (snowflake(&msg.channel_id), snowflake(&msg.author.id)) {
+        (Some(chat_id), Some(user_id)) => (chat_id, user_id),
+        _ => return Box::new(Ok(()).into_future())
+    };
+
+    let (cmd, args) = match me.dispatcher.parse(&msg.content[me.config.prefix.len()..]) {
+        Some(parsed) => parsed,
+        None => return Box::new(Ok(()).into_future())
+    };
+    let sink = DcSink {
+        rest: me.rest.clone(),
+        target: DcTarget::Message { channel_id: msg.channel_id.clone(), message_id: msg.id.clone() }
+    };
+    if let Command::Eval(..) = cmd {
+        if !me.config.channel_ok(msg.guild_id.as_deref(), &msg.channel_id) {
+            return sink.reply(Reply::Text(format!("This server or channel is not on the allowlist. Seek help. ID: {}",
+                msg.guild_id.as_ref().unwrap_or(&msg.channel_id))));
+        }
+    }
+
+    let src = MessageSource {
+        chat_id,
+        user_id: Some(user_id),
+        user_name: Some(msg.author.username.clone()),
+        group: msg.guild_id.is_some()
+    };
+    Dispatcher::dispatch(&me.dispatcher, cmd, src, args, sink)
+}
+
+fn handle_interaction<R: Rest>(me: &Arc<DcSvc<R>>, int: Interaction)
+    -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    // only application commands
+    if int.kind != 2 {
+        return Box::new(Ok(()).into_future());
+    }
+    let lang = match int.data.as_ref().and_then(|d| me.commands.get(&d.name)) {
+        Some(lang) => lang.clone(),
+        None => return Box::new(Ok(()).into_future())
+    };
+    let channel_id = int.channel_id.clone().unwrap_or_default();
+    let code = int.option("code").and_then(|v| v.as_str()).unwrap_or("").to_owned();
+    let no_limit = int.option("nolimit").and_then(|v| v.as_bool()).unwrap_or(false);
+    let (chat_id, user_id) = match (snowflake(&channel_id), int.user().map(|u| snowflake(&u.id))) {
+        (Some(chat_id), Some(Some(user_id))) => (chat_id, Some(user_id)),
+        (Some(chat_id), None) => (chat_id, None),
+        _ => return Box::new(Ok(()).into_future())
+    };
+    let src = MessageSource {
+        chat_id,
+        user_id,
+        user_name: int.user().map(|u| u.username.clone()),
+        group: int.guild_id.is_some()
+    };
+    let sink = DcSink {
+        rest: me.rest.clone(),
+        target: DcTarget::Interaction { application_id: int.application_id.clone(), token: int.token.clone() }
+    };
+
+    let me = me.clone();
+    let defer = me.rest.defer_interaction(&int.id, &int.token);
+    Box::new(defer.map_err(|e| error!("error deferring interaction: {}", e)).and_then(move |_| {
+        if !me.config.channel_ok(int.guild_id.as_deref(), &channel_id) {
+            return sink.reply(Reply::Text(format!("This server or channel is not on the allowlist. Seek help. ID: {}",
+                int.guild_id.as_ref().unwrap_or(&channel_id))));
+        }
+        Dispatcher::dispatch(&me.dispatcher, Command::Eval(lang, no_limit), src, &code, sink)
+    }))
+}
+
+impl<R: Rest> DcSvc<R> {
+    fn new(config: DcCfg, service: EvalService, whitelist: Whitelist, usage: Usage, rest: R) -> Self {
+        let mut commands = HashMap::new();
+        for (name, lang) in service.langs() {
+            match slash_command_name(name) {
+                Some(cmd) => if let Some(other) = commands.insert(cmd.clone(), lang.clone()) {
+                    warn!("slash command /{} for {} shadows {}", cmd, name, other.name());
+                },
+                None => warn!("cannot make a slash command for {}", name)
+            }
+        }
+        let frontend = DcFrontend { prefix: config.prefix.clone() };
+        let mut dispatcher = Dispatcher::new(frontend, service, config.owners.clone(), whitelist,
+            WHITELIST_FILENAME.to_owned());
+        if let Some(ref quota) = config.quota {
+            dispatcher = dispatcher.with_quotas(Quotas::new(quota.clone(), usage, QUOTA_FILENAME.to_owned()));
+        }
+        DcSvc {
+            dispatcher: Arc::new(dispatcher),
+            config,
+            rest: Arc::new(rest),
+            commands
+        }
+    }
+
+    /// Handles events from a single gateway session until it ends.
+    fn serve<G: Gateway>(me: Arc<Self>, gateway: &G) -> impl Future<Item = (), Error = String> {
+        gateway.events().for_each(move |event| {
+            tokio::spawn(handle_event(&me, event));
+            Ok(())
+        })
+    }
+}
+
+fn run() -> impl Future<Item = (), Error = ()> {
+    let cfgf = util::decode::<DcCfg, _>("evalbot.discord.toml")
+        .map(|cfg| {
+            debug!("Loaded config: {:?}", cfg);
+            cfg
+        })
+        .map_err(|e| {
+            error!("failed to read evalbot.discord.toml: {}", e);
+        });
+    cfgf.join3(Whitelist::load(WHITELIST_FILENAME), Usage::load(QUOTA_FILENAME))
+        .join(EvalService::from_toml_file("evalbot.toml")
+            .map_err(|e| {
+                error!("failed to read evalbot.toml: {}", e);
+            }))
+        .and_then(|((cfg, wl, usage), es)| {
+            es.start();
+            let gateway = WsGateway::new(
+                cfg.gateway_url.clone().unwrap_or_else(|| DEFAULT_GATEWAY_URL.to_owned()),
+                cfg.bot_token.clone());
+            let rest = HttpRest::new(cfg.bot_token.clone());
+            let me = Arc::new(DcSvc::new(cfg, es, wl, usage, rest));
+            tokio::spawn(Dispatcher::whitelist_expiries(&me.dispatcher, Duration::from_secs(60))
+                .for_each(|expiry| {
+                    info!("whitelist entry lapsed: {:?}", expiry);
+                    Ok(())
+                }));
+            future::loop_fn((me, gateway), |(me, gateway)| {
+                DcSvc::serve(me.clone(), &gateway)
+                    .then(|r| {
+                        match r {
+                            Ok(()) => warn!("gateway session ended; reconnecting"),
+                            Err(e) => error!("gateway session failed: {}; reconnecting", e)
+                        }
+                        Delay::new(Instant::now() + Duration::from_secs(5))
+                            .map_err(|e| error!("timer failed: {}", e))
+                    })
+                    .map(|_| Loop::Continue((me, gateway)))
+            })
+        })
+}
+
+fn main() {
+    env_logger::init();
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("smoke") {
+        process::exit(smoke::run_cli("evalbot.toml", &args[1..]));
+    }
+    tokio::run(run());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use discord::{BoxFuture, BoxStream, CommandData, CommandOption, User};
+    use backend::EvalBackend;
+    use backend::test_support::{self, ScriptedBackend};
+    use std::sync::Mutex;
+    use futures::stream;
+
+    #[derive(Clone, PartialEq, Debug)]
+    enum Call {
+        CreateMessage(String, Option<String>, String),
+        OverwriteCommands(String, Vec<String>),
+        Defer(String),
+        EditResponse(String, String),
+        LeaveGuild(String)
+    }
+
+    #[derive(Clone, Default)]
+    struct MockRest {
+        calls: Arc<Mutex<Vec<Call>>>
+    }
+
+    impl MockRest {
+        fn record(&self, call: Call) -> BoxFuture<()> {
+            self.calls.lock().unwrap().push(call);
+            Box::new(Ok(()).into_future())
+        }
+    }
+
+    impl Rest for MockRest {
+        fn create_message(&self, channel_id: &str, reply_to: Option<&str>, content: String) -> BoxFuture<()> {
+            self.record(Call::CreateMessage(channel_id.to_owned(), reply_to.map(str::to_owned), content))
+        }
+
+        fn overwrite_commands(&self, application_id: &str, commands: Vec<SlashCommand>) -> BoxFuture<()> {
+            self.record(Call::OverwriteCommands(application_id.to_owned(),
+                commands.into_iter().map(|c| c.name).collect()))
+        }
+
+        fn defer_interaction(&self, interaction_id: &str, _: &str) -> BoxFuture<()> {
+            self.record(Call::Defer(interaction_id.to_owned()))
+        }
+
+        fn edit_interaction_response(&self, _: &str, token: &str, content: String) -> BoxFuture<()> {
+            self.record(Call::EditResponse(token.to_owned(), content))
+        }
+
+        fn leave_guild(&self, guild_id: &str) -> BoxFuture<()> {
+            self.record(Call::LeaveGuild(guild_id.to_owned()))
+        }
+    }
+
+    struct MockGateway(Vec<Event>);
+
+    impl Gateway for MockGateway {
+        fn events(&self) -> BoxStream<Event> {
+            Box::new(stream::iter_ok(self.0.clone()))
+        }
+    }
+
+    fn config() -> DcCfg {
+        DcCfg {
+            owners: vec!["5".to_owned()].into_iter().collect(),
+            bot_token: "xyz".to_owned(),
+            gateway_url: None,
+            prefix: "!".to_owned(),
+            allow_direct: false,
+            allowed_guilds: vec!["1".to_owned()].into_iter().collect(),
+            allowed_channels: HashSet::new(),
+            quota: None
+        }
+    }
+
+    fn message(guild_id: &str, content: &str) -> Event {
+        Event::MessageCreate(Message {
+            id: "100".to_owned(),
+            channel_id: "10".to_owned(),
+            guild_id: Some(guild_id.to_owned()),
+            author: User { id: "5".to_owned(), username: "someone".to_owned(), bot: false },
+            content: content.to_owned()
+        })
+    }
+
+    fn run_events(events: Vec<Event>) -> Vec<Call> {
+        let echo: Arc<dyn EvalBackend> = Arc::new(ScriptedBackend::new());
+        let service = test_support::service(vec![("cat", echo.clone()), ("c++", echo)]);
+        let rest = MockRest::default();
+        let me = Arc::new(DcSvc::new(config(), service, Whitelist::default(), Usage::default(), rest.clone()));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(DcSvc::serve(me, &MockGateway(events))).unwrap();
+        rt.shutdown_on_idle().wait().unwrap();
+        let calls = rest.calls.lock().unwrap().clone();
+        calls
+    }
+
+    #[test]
+    fn test_extract_code() {
+        assert_eq!(extract_code("```rust\nfn main() {}\n```"), "fn main() {}\n\n");
+        assert_eq!(extract_code("```1 + 1```"), "1 + 1\n");
+        assert_eq!(extract_code("`1 + 1`"), "1 + 1\n");
+        assert_eq!(extract_code(" 1 + 1 "), "1 + 1\n");
+    }
+
+    #[test]
+    fn test_wrap_result() {
+        assert_eq!(discord_wrap_result(""), "no output");
+        assert_eq!(discord_wrap_result("hi\n"), "```\nhi\n```");
+        let long = discord_wrap_result(&"x".repeat(5000));
+        assert_eq!(long.chars().count(), MAX_MESSAGE_LEN);
+        assert!(long.ends_with("```... (truncated)"));
+    }
+
+    #[test]
+    fn test_slash_command_name() {
+        assert_eq!(slash_command_name("rs"), Some("rs".to_owned()));
+        assert_eq!(slash_command_name("c++"), Some("cpp".to_owned()));
+        assert_eq!(slash_command_name("pl!"), Some("pl_".to_owned()));
+        assert_eq!(slash_command_name("a b"), None);
+    }
+
+    #[test]
+    fn test_message_eval() {
+        let calls = run_events(vec![message("1", "!cat ```\nhello\n```"), message("1", "not a command")]);
+        assert_eq!(calls, vec![
+            Call::CreateMessage("10".to_owned(), Some("100".to_owned()), "```\nhello\n```".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn test_message_admin() {
+        let calls = run_events(vec![message("2", "!leave 42")]);
+        assert!(calls.contains(&Call::LeaveGuild("42".to_owned())));
+        assert!(calls.contains(&Call::CreateMessage("10".to_owned(), Some("100".to_owned()), "OK".to_owned())));
+    }
+
+    #[test]
+    fn test_message_not_allowed() {
+        let calls = run_events(vec![message("2", "!cat hello")]);
+        assert_eq!(calls, vec![
+            Call::CreateMessage("10".to_owned(), Some("100".to_owned()),
+                "This server or channel is not on the allowlist. Seek help. ID: 2".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn test_invalid_ids() {
+        let event = match message("1", "!cat hello") {
+            Event::MessageCreate(msg) => Event::MessageCreate(Message { channel_id: "general".to_owned(), ..msg }),
+            _ => unreachable!()
+        };
+        assert_eq!(run_events(vec![event]), vec![]);
+    }
+
+    #[test]
+    fn test_interaction_eval() {
+        let calls = run_events(vec![
+            Event::Ready { application_id: "app".to_owned() },
+            Event::InteractionCreate(Interaction {
+                id: "200".to_owned(),
+                application_id: "app".to_owned(),
+                kind: 2,
+                token: "tok".to_owned(),
+                channel_id: Some("10".to_owned()),
+                guild_id: Some("1".to_owned()),
+                member: None,
+                user: Some(User { id: "5".to_owned(), username: "someone".to_owned(), bot: false }),
+                data: Some(CommandData {
+                    name: "cpp".to_owned(),
+                    options: vec![CommandOption { name: "code".to_owned(), value: Some(json!("`42`")) }]
+                })
+            })
+        ]);
+        assert!(calls.contains(&Call::OverwriteCommands("app".to_owned(), vec!["cat".to_owned(), "cpp".to_owned()])));
+        let defer = calls.iter().position(|c| *c == Call::Defer("200".to_owned())).unwrap();
+        let edit = calls.iter().position(|c| *c == Call::EditResponse("tok".to_owned(), "```\n42\n```".to_owned()))
+            .unwrap();
+        assert!(defer < edit);
+    }
+}
@@ -0,0 +1,277 @@
+//! Handles for stopping evaluations that are already running.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::{Future, future};
+use futures::future::Either;
+use futures::sync::oneshot;
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct State {
+    cancelled: bool,
+    hooks: Vec<Hook>,
+    cpu_time: Duration,
+    started: Option<Instant>
+}
+
+/// Cancels the evaluations it is passed to. Clones share the same state, so a frontend keeps one
+/// clone to cancel with and gives the other to `EvalService::eval_cancellable`.
+///
+/// Backends mark the handle once they hand the code over, and those that can measure the CPU time
+/// of an evaluation also charge it to its handle.
+///
+/// A handle belongs to a single evaluation: the hooks that evaluation registers are only dropped on
+/// cancellation or with the last clone, and its start time and CPU time would carry over. Make a new
+/// one for every evaluation rather than reusing it.
+#[derive(Clone, Default)]
+pub struct Cancel(Arc<Mutex<State>>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Cancel::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.0.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Stops everything this was passed to. Only the first call does anything.
+    pub fn cancel(&self) {
+        let hooks = {
+            let mut state = self.lock();
+            if state.cancelled {
+                return;
+            }
+            state.cancelled = true;
+            state.hooks.split_off(0)
+        };
+        for hook in hooks {
+            hook();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.lock().cancelled
+    }
+
+    /// Runs `f` on cancellation, or right away if that already happened. Until then `f` is kept, even
+    /// after the evaluation that registered it has finished.
+    pub fn on_cancel<F: FnOnce() + Send + 'static>(&self, f: F) {
+        {
+            let mut state = self.lock();
+            if !state.cancelled {
+                state.hooks.push(Box::new(f));
+                return;
+            }
+        }
+        f();
+    }
+
+    /// Marks the moment the backend took the code. Only the first call counts.
+    pub fn start(&self) {
+        let mut state = self.lock();
+        if state.started.is_none() {
+            state.started = Some(Instant::now());
+        }
+    }
+
+    /// When a backend started running the evaluation; `None` if none ever did.
+    pub fn started(&self) -> Option<Instant> {
+        self.lock().started
+    }
+
+    pub fn charge(&self, cpu_time: Duration) {
+        self.lock().cpu_time += cpu_time;
+    }
+
+    /// CPU time charged so far; zero for backends that don't measure it.
+    pub fn cpu_time(&self) -> Duration {
+        self.lock().cpu_time
+    }
+
+    /// Resolves on cancellation, and never if every clone is dropped first.
+    pub fn cancelled(&self) -> impl Future<Item = (), Error = ()> + Send {
+        let (tx, rx) = oneshot::channel();
+        self.on_cancel(move || {
+            let _ = tx.send(());
+        });
+        rx.then(|r| match r {
+            Ok(()) => Either::A(future::ok(())),
+            Err(_) => Either::B(future::empty())
+        })
+    }
+}
+
+/// Two handles are equal if they are clones of each other.
+impl PartialEq for Cancel {
+    fn eq(&self, other: &Cancel) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for Cancel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cancel").field("cancelled", &self.is_cancelled()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tokio::prelude::FutureExt;
+    use tokio::runtime::Runtime;
+    use crate::{EvalService, eval};
+    use crate::test_support::{DaemonRequest, FakeDaemon, block_on, service};
+
+    #[test]
+    fn test_cancel() {
+        let cancel = Cancel::new();
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let tx2 = tx.clone();
+        cancel.on_cancel(move || tx.send(1).unwrap());
+        cancel.clone().cancel();
+        cancel.cancel();
+        cancel.on_cancel(move || tx2.send(2).unwrap());
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+        assert!(block_on(cancel.cancelled()).is_ok());
+    }
+
+    #[test]
+    fn test_cancel_exec() {
+        let service = EvalService::from_toml(r#"
+timeout = 20
+
+[languages.sh]
+cmdline = ["sh"]
+"#).unwrap();
+        let lang = service.get("sh").unwrap();
+        let marker = ::std::env::temp_dir().join(format!("evalbot-cancel-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&marker);
+        let cancel = Cancel::new();
+        let canceller = cancel.clone();
+        let start = Instant::now();
+        let code = format!("(sleep 1; touch {}) & sleep 5", marker.display());
+        let r = block_on(lang.eval_cancellable(code, None, None::<&str>, &cancel)
+            .select2(tokio::timer::Delay::new(Instant::now() + Duration::from_millis(200))
+                .then(move |_| {
+                    canceller.cancel();
+                    future::empty::<(), ()>()
+                }))
+            .then(|r| match r {
+                Ok(future::Either::A((output, _))) => Ok(output),
+                Err(future::Either::A((e, _))) => Err(e),
+                _ => unreachable!()
+            }));
+        assert_eq!(r, Err("cancelled".to_owned()));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // the background subshell went down with the rest of the process group
+        ::std::thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_exec_kills_command() {
+        let service = EvalService::from_toml(r#"
+timeout = 20
+
+[languages.sh]
+cmdline = ["sh"]
+"#).unwrap();
+        let lang = service.get("sh").unwrap();
+        let pidfile = ::std::env::temp_dir().join(format!("evalbot-pid-{}", ::std::process::id()));
+        let code = format!("echo $$ > {}; sleep 5", pidfile.display());
+        let pid = || ::std::fs::read_to_string(&pidfile).unwrap().trim().parse::<libc::pid_t>().unwrap();
+        let gone = |pid| (0..50).any(|_| {
+            ::std::thread::sleep(Duration::from_millis(20));
+            unsafe { libc::kill(pid, 0) == -1 }
+        });
+
+        let cancel = Cancel::new();
+        let canceller = cancel.clone();
+        let r = block_on(lang.eval_cancellable(code.clone(), None, None::<&str>, &cancel)
+            .select2(tokio::timer::Delay::new(Instant::now() + Duration::from_millis(200))
+                .then(move |_| {
+                    canceller.cancel();
+                    future::empty::<(), ()>()
+                }))
+            .then(|r| match r {
+                Ok(future::Either::A((output, _))) => Ok(output),
+                Err(future::Either::A((e, _))) => Err(e),
+                _ => unreachable!()
+            }));
+        assert_eq!(r, Err("cancelled".to_owned()));
+        assert!(gone(pid()));
+
+        // an evaluation that times out is dropped, which takes the command with it
+        let r = block_on(lang.eval(code, None, None::<&str>)
+            .timeout(Duration::from_millis(200))
+            .map_err(|e| e.is_elapsed()));
+        assert_eq!(r, Err(true));
+        assert!(gone(pid()));
+        let _ = ::std::fs::remove_file(&pidfile);
+    }
+
+    #[test]
+    fn test_cancel_unix() {
+        let daemon = FakeDaemon::start(|req| {
+            if req.code == "sleep" {
+                ::std::thread::sleep(Duration::from_millis(500));
+            }
+            req.code.clone()
+        }).unwrap();
+        let service = service(vec![("d", daemon.backend())]);
+        let lang = service.get("d").unwrap();
+        let cancel = Cancel::new();
+        let canceller = cancel.clone();
+        let mut rt = Runtime::new().unwrap();
+        let r = rt.block_on(lang.eval_cancellable("sleep", None, Some("ctx"), &cancel)
+            .select2(tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))
+                .then(move |_| {
+                    canceller.cancel();
+                    future::empty::<(), ()>()
+                }))
+            .then(|r| match r {
+                Ok(future::Either::A((output, _))) => Ok(output),
+                Err(future::Either::A((e, _))) => Err(e),
+                _ => unreachable!()
+            }));
+        assert_eq!(r, Err("cancelled".to_owned()));
+        let _ = rt.block_on(tokio::timer::Delay::new(Instant::now() + Duration::from_millis(300)));
+        // only the cancelled chat's context is cleared
+        assert!(daemon.requests().contains(&DaemonRequest {
+            timeout_ms: eval::RESET,
+            context: "ctx".to_owned(),
+            code: String::new()
+        }), "{:?}", daemon.requests());
+    }
+
+    #[test]
+    fn test_cpu_time() {
+        let service = EvalService::from_toml(r#"
+timeout = 20
+
+[languages.sh]
+cmdline = ["sh"]
+"#).unwrap();
+        let lang = service.get("sh").unwrap();
+        let cancel = Cancel::new();
+        // the busy loop runs in a grandchild, which still counts
+        let r = block_on(lang.eval_cancellable("(i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done); exit 3",
+            None, None::<&str>, &cancel));
+        assert_eq!(r, Ok("\nexited with status 3\n".to_owned()));
+        assert!(cancel.cpu_time() > Duration::from_millis(50), "{:?}", cancel.cpu_time());
+
+        let cancel = Cancel::new();
+        let r = block_on(lang.eval_cancellable("kill -SEGV $$", None, None::<&str>, &cancel));
+        assert_eq!(r, Ok("\nsignalled with Segmentation fault (SIGSEGV)\n".to_owned()));
+        assert_eq!(block_on(lang.eval("echo hi", None, None::<&str>)), Ok("hi\n".to_owned()));
+    }
+}
@@ -0,0 +1,1670 @@
+//! Chat commands shared by all frontends: evaluation, the `#` no-limit variant, owner checks,
+//! whitelist administration and quotas.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{Future, IntoFuture, Stream};
+use futures::future::{self, Either};
+use futures::stream;
+use tokio::timer::Interval;
+
+use crate::{Cancel, EvalRequest, EvalService, Language, Priority, util};
+use crate::quota::{Account, Limits, QuotaOverride, Quotas};
+
+pub type ReplyFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+// how long shutdown waits for cancelled evaluations to be replied to
+const REPLY_GRACE: Duration = Duration::from_secs(5);
+// how long an undecided access request keeps the chat from asking again
+const ACCESS_REQUEST_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+macro_rules! nullify_future {
+    ($task:expr, $fut:expr) => ($fut.map(|_| ())
+        .or_else(|e| Ok(error!("error {}: {}", $task, e))));
+}
+
+/// Where a command came from.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MessageSource {
+    pub chat_id: i64,
+    pub user_id: Option<i64>,
+    pub user_name: Option<String>,
+    pub group: bool
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Reply {
+    /// Output of an evaluation, which the frontend should format (code block, truncation).
+    Output(String),
+    /// An error or informational message, sent as is.
+    Text(String),
+    /// A refusal to a chat that is neither whitelisted nor blocked, sent as is. Frontends that can should
+    /// offer to ask the owners for access, through `Dispatcher::request_access`.
+    NotAllowed(String)
+}
+
+/// Somebody asking the owners to whitelist a chat.
+#[derive(Clone, PartialEq, Debug)]
+pub struct AccessRequest {
+    pub chat_id: i64,
+    pub group: bool,
+    /// Who asked, as the frontend describes them.
+    pub requester: String,
+    pub at: Instant
+}
+
+/// The way back to the chat a command came from.
+pub trait ReplySink: Send + 'static {
+    fn reply(&self, reply: Reply) -> ReplyFuture;
+    fn leave_chat(&self, chat_id: i64) -> ReplyFuture;
+
+    /// Whether `user_id` administers the group `chat_id`. Frontends that can't tell say no.
+    fn is_admin(&self, _chat_id: i64, _user_id: i64) -> Box<dyn Future<Item = bool, Error = ()> + Send> {
+        Box::new(Ok(false).into_future())
+    }
+
+    /// A readable name for the user or chat `id`, such as a group's title, if the frontend can find one.
+    fn chat_name(&self, _id: i64) -> Box<dyn Future<Item = Option<String>, Error = ()> + Send> {
+        Box::new(Ok(None).into_future())
+    }
+}
+
+pub trait Frontend: Send + Sync + 'static {
+    /// Prefixed to the chat ID to form the persistent context key, so that chats on different
+    /// frontends never share state.
+    fn context_prefix(&self) -> &str;
+
+    /// What users type before a command name, e.g. `/`, for help output.
+    fn command_prefix(&self) -> &str {
+        ""
+    }
+
+    /// Whether `id` is a group's, which the bot leaves once the group may no longer use it. Frontends
+    /// that can't tell from the ID say no.
+    fn is_group_id(&self, _id: i64) -> bool {
+        false
+    }
+
+    fn extract_code(&self, text: &str) -> String {
+        let mut r = text.trim_start().to_owned();
+        r.push('\n');
+        r
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Evaluate with the given language; `true` for the `#` variant, which has no time limit when
+    /// used by an owner.
+    Eval(Arc<Language>, bool),
+    TogglePrivate,
+    ToggleGroup,
+    Allow,
+    Unallow,
+    Block,
+    Unblock,
+    Leave,
+    /// Health of every language.
+    Status,
+    /// Cancel every evaluation running for the chat.
+    Stop,
+    /// View, reset or override a user's or chat's quota.
+    Quota,
+    /// Commands and languages.
+    Help,
+    /// Languages only.
+    Langs,
+    /// Discard the chat's state in a persistent language.
+    Reset,
+    /// Show the whitelist.
+    Whitelist
+}
+
+static BUILTIN_COMMANDS: [(&str, Command); 14] = [
+    ("help", Command::Help),
+    ("langs", Command::Langs),
+    ("privwl", Command::TogglePrivate),
+    ("groupwl", Command::ToggleGroup),
+    ("allow", Command::Allow),
+    ("unallow", Command::Unallow),
+    ("block", Command::Block),
+    ("unblock", Command::Unblock),
+    ("whitelist", Command::Whitelist),
+    ("leave", Command::Leave),
+    ("status", Command::Status),
+    ("stop", Command::Stop),
+    ("quota", Command::Quota),
+    ("reset", Command::Reset)
+];
+
+impl Command {
+    /// The built-in command called `name`, if any.
+    pub fn builtin(name: &str) -> Option<Command> {
+        BUILTIN_COMMANDS.iter().find(|(n, _)| *n == name).map(|(_, cmd)| cmd.clone())
+    }
+
+    /// What the command does, for help output and command lists.
+    pub fn description(&self) -> String {
+        match *self {
+            Command::Eval(ref lang, false) => lang.describe(),
+            Command::Eval(ref lang, true) => format!("{} without time limit (owners only)", lang.describe()),
+            Command::TogglePrivate => "Toggle the whitelist for private chats".to_owned(),
+            Command::ToggleGroup => "Toggle the whitelist for groups".to_owned(),
+            Command::Allow => "Whitelist users or chats: allow <id>... [duration] [-- note]".to_owned(),
+            Command::Unallow => "Remove users or chats from the whitelist: unallow <id>...".to_owned(),
+            Command::Block => "Block users or chats: block <id>... [duration] [-- note]".to_owned(),
+            Command::Unblock => "Unblock users or chats: unblock <id>...".to_owned(),
+            Command::Whitelist => "Show the whitelist".to_owned(),
+            Command::Leave => "Leave a group: leave <id>".to_owned(),
+            Command::Status => "Show the health of every language".to_owned(),
+            Command::Stop => "Stop everything running in this chat".to_owned(),
+            Command::Quota => "Show, reset or set a quota: quota [reset|set|unset] <id> [evals=N wall=S cpu=S]"
+                .to_owned(),
+            Command::Help => "Show commands and languages".to_owned(),
+            Command::Langs => "Show languages".to_owned(),
+            Command::Reset => "Clear this chat's state in a language: reset <language>".to_owned()
+        }
+    }
+
+    /// Whether only owners may use the command; others are ignored.
+    pub fn owner_only(&self) -> bool {
+        match *self {
+            Command::TogglePrivate | Command::ToggleGroup | Command::Allow | Command::Unallow | Command::Block
+                | Command::Unblock | Command::Whitelist | Command::Leave | Command::Quota => true,
+            Command::Eval(_, is_hash) => is_hash,
+            // group admins may reset too, which only the frontend can tell
+            Command::Status | Command::Stop | Command::Help | Command::Langs | Command::Reset => false
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Default, Debug)]
+pub struct Whitelist {
+    pub priv_enabled: bool,
+    pub group_enabled: bool,
+    pub allowed: HashSet<i64>,
+    pub blocked: HashSet<i64>,
+    /// Quotas that replace the defaults for particular users and chats.
+    #[serde(default)]
+    pub quotas: Vec<QuotaOverride>,
+    /// Who allowed or blocked an ID, and why.
+    // an empty array after the quota tables is more than toml can write
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<WhitelistNote>,
+    /// When allowed and blocked IDs given a duration lapse.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expiries: Vec<WhitelistExpiry>,
+    /// Chats whose ID changed, e.g. Telegram groups upgraded to supergroups.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub migrations: Vec<ChatMigration>
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub struct ChatMigration {
+    pub from: i64,
+    pub to: i64
+}
+
+/// When an allowed or blocked ID lapses.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub struct WhitelistExpiry {
+    pub id: i64,
+    /// Whether it's the block that lapses rather than the allowance.
+    #[serde(default)]
+    pub blocked: bool,
+    /// Seconds since the Unix epoch.
+    pub at: u64
+}
+
+// access requests are plain data, so a panic while holding them leaves nothing half done
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Who allowed or blocked an ID, and why.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct WhitelistNote {
+    pub id: i64,
+    pub by: String,
+    #[serde(default)]
+    pub text: String
+}
+
+impl Whitelist {
+    pub fn load<P>(path: P) -> impl Future<Item = Self, Error = ()>
+        where P: AsRef<Path> + Send + Display + 'static {
+        util::decode(path).or_else(|e| {
+            warn!("failed to read whitelist: {}; using empty whitelist", e);
+            Ok(Whitelist::default())
+        })
+    }
+
+    pub fn priv_ok(&self, id: i64) -> bool {
+        (!self.priv_enabled || self.allowed.contains(&id)) && !self.blocked.contains(&id)
+    }
+
+    pub fn group_ok(&self, id: i64) -> bool {
+        (!self.group_enabled || self.allowed.contains(&id)) && !self.blocked.contains(&id)
+    }
+
+    pub fn source_ok(&self, src: &MessageSource) -> bool {
+        if src.group {
+            self.group_ok(src.chat_id)
+        } else {
+            self.priv_ok(src.chat_id)
+        }
+    }
+
+    /// Allows `id` for good; see `set_expiry` for a while only.
+    pub fn allow(&mut self, id: i64) {
+        self.allowed.insert(id);
+        self.set_expiry(id, false, None);
+    }
+
+    pub fn unallow(&mut self, id: i64) {
+        self.allowed.remove(&id);
+        self.set_expiry(id, false, None);
+    }
+
+    /// Blocks `id` for good; see `set_expiry` for a while only.
+    pub fn block(&mut self, id: i64) {
+        self.blocked.insert(id);
+        self.set_expiry(id, true, None);
+    }
+
+    pub fn unblock(&mut self, id: i64) {
+        self.blocked.remove(&id);
+        self.set_expiry(id, true, None);
+    }
+
+    /// When `id`'s block, or allowance if not `blocked`, lapses, in seconds since the Unix epoch.
+    pub fn expiry(&self, id: i64, blocked: bool) -> Option<u64> {
+        self.expiries.iter().find(|e| e.id == id && e.blocked == blocked).map(|e| e.at)
+    }
+
+    /// Makes `id`'s block, or allowance if not `blocked`, lapse `at` (seconds since the Unix epoch), or
+    /// never if `None`.
+    pub fn set_expiry(&mut self, id: i64, blocked: bool, at: Option<u64>) {
+        self.expiries.retain(|e| e.id != id || e.blocked != blocked);
+        if let Some(at) = at {
+            self.expiries.push(WhitelistExpiry { id, blocked, at });
+        }
+    }
+
+    /// Removes the allowances and blocks that lapsed by `now` (seconds since the Unix epoch), and returns
+    /// them.
+    pub fn expire(&mut self, now: u64) -> Vec<WhitelistExpiry> {
+        let (lapsed, kept) = self.expiries.iter().partition::<Vec<_>, _>(|e| e.at <= now);
+        self.expiries = kept;
+        for e in &lapsed {
+            if e.blocked {
+                self.blocked.remove(&e.id);
+            } else {
+                self.allowed.remove(&e.id);
+            }
+        }
+        self.drop_stale_notes();
+        lapsed
+    }
+
+    pub fn note(&self, id: i64) -> Option<&WhitelistNote> {
+        self.notes.iter().find(|n| n.id == id)
+    }
+
+    /// Replaces the note on `id`. Notes on IDs that are neither allowed nor blocked are dropped.
+    pub fn set_note(&mut self, id: i64, by: String, text: String) {
+        self.notes.retain(|n| n.id != id);
+        self.notes.push(WhitelistNote { id, by, text });
+        self.drop_stale_notes();
+    }
+
+    fn drop_stale_notes(&mut self) {
+        let (allowed, blocked) = (&self.allowed, &self.blocked);
+        self.notes.retain(|n| allowed.contains(&n.id) || blocked.contains(&n.id));
+    }
+
+    /// Moves everything about `from` to `to` and records the move, e.g. when a group's ID changes. Returns
+    /// whether `from` was allowed or blocked.
+    pub fn migrate(&mut self, from: i64, to: i64) -> bool {
+        let mut listed = false;
+        for set in &mut [&mut self.allowed, &mut self.blocked] {
+            if set.remove(&from) {
+                set.insert(to);
+                listed = true;
+            }
+        }
+        for note in self.notes.iter_mut().filter(|n| n.id == from) {
+            note.id = to;
+        }
+        for expiry in self.expiries.iter_mut().filter(|e| e.id == from) {
+            expiry.id = to;
+        }
+        for quota in self.quotas.iter_mut().filter(|q| q.id == from) {
+            quota.id = to;
+        }
+        self.migrations.push(ChatMigration { from, to });
+        listed
+    }
+
+    /// The ID `id` had before any migrations.
+    pub fn original_id(&self, id: i64) -> i64 {
+        let mut id = id;
+        // bounded, in case a hand-edited file has a cycle
+        for _ in 0..self.migrations.len() {
+            match self.migrations.iter().find(|m| m.to == id) {
+                Some(m) => id = m.from,
+                None => break
+            }
+        }
+        id
+    }
+
+    /// Gives `id` its own quota, or the default one again if `limits` is `None`.
+    pub fn set_quota(&mut self, id: i64, limits: Option<Limits>) {
+        self.quotas.retain(|o| o.id != id);
+        if let Some(limits) = limits {
+            self.quotas.push(QuotaOverride { id, limits });
+        }
+    }
+
+    pub fn save(&self, path: String) -> impl Future<Item = (), Error = ()> {
+        nullify_future!("saving whitelist", util::encode(self, path))
+    }
+}
+
+pub struct Dispatcher<F> {
+    frontend: F,
+    service: EvalService,
+    owner_names: HashSet<String>,
+    owner_ids: HashSet<i64>,
+    whitelist: RwLock<Whitelist>,
+    whitelist_path: String,
+    running: Mutex<HashMap<i64, Vec<Cancel>>>,
+    draining: AtomicBool,
+    quotas: Option<Quotas>,
+    access_requests: Mutex<HashMap<i64, AccessRequest>>,
+    /// Groups offered to request access when they added the bot, and when.
+    access_offers: Mutex<HashMap<i64, Instant>>
+}
+
+fn parse_id(args: &str) -> Option<i64> {
+    args.split_whitespace().next().and_then(|arg| arg.parse().ok())
+}
+
+// IDs, then maybe a duration, then `--` and a note; a note may start with a number, so it needs the `--`
+fn parse_ids(args: &str) -> Result<(Vec<i64>, Option<u64>, String), String> {
+    let mut words = args.split_whitespace();
+    let (mut ids, mut duration) = (Vec::new(), None);
+    for word in words.by_ref() {
+        if word == "--" {
+            break;
+        }
+        match (word.parse(), duration) {
+            (Ok(id), None) => ids.push(id),
+            _ if ids.is_empty() => return Err("Invalid ID".to_owned()),
+            (Err(_), None) if parse_duration(word).is_some() => duration = parse_duration(word),
+            _ => return Err(format!("Expected an ID or a duration, got {:?}; a note goes after --", word))
+        }
+    }
+    Ok((ids, duration, words.collect::<Vec<_>>().join(" ")))
+}
+
+// e.g. 90m or 7d, in seconds
+fn parse_duration(word: &str) -> Option<u64> {
+    let unit = match word.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None
+    };
+    word[..word.len() - 1].parse::<u64>().ok().filter(|&n| n > 0).and_then(|n| n.checked_mul(unit))
+}
+
+// the largest two units, e.g. 6d 23h
+fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s >= 24 * 60 * 60 => format!("{}d {}h", s / (24 * 60 * 60), s % (24 * 60 * 60) / (60 * 60)),
+        s if s >= 60 * 60 => format!("{}h {}m", s / (60 * 60), s % (60 * 60) / 60),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s)
+    }
+}
+
+fn join_ids(ids: &[i64]) -> String {
+    ids.iter().map(i64::to_string).collect::<Vec<_>>().join(", ")
+}
+
+impl<F: Frontend> Dispatcher<F> {
+    /// Owners that parse as numbers are user IDs, the rest user names.
+    pub fn new(frontend: F, service: EvalService, owners: HashSet<String>, whitelist: Whitelist,
+        whitelist_path: String) -> Self {
+        let (mut owner_names, mut owner_ids) = (HashSet::new(), HashSet::new());
+        for owner in owners {
+            match owner.parse() {
+                Ok(id) => owner_ids.insert(id),
+                Err(_) => owner_names.insert(owner)
+            };
+        }
+        Dispatcher {
+            frontend,
+            service,
+            owner_names,
+            owner_ids,
+            whitelist: RwLock::new(whitelist),
+            whitelist_path,
+            running: Mutex::new(HashMap::new()),
+            draining: AtomicBool::new(false),
+            quotas: None,
+            access_requests: Mutex::new(HashMap::new()),
+            access_offers: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Enforces `quotas` on everybody but the owners.
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    pub fn frontend(&self) -> &F {
+        &self.frontend
+    }
+
+    pub fn service(&self) -> &EvalService {
+        &self.service
+    }
+
+    pub fn whitelist(&self) -> &RwLock<Whitelist> {
+        &self.whitelist
+    }
+
+    /// Reads the whitelist file again, e.g. after editing it by hand. The whitelist in use is kept if
+    /// that fails.
+    pub fn reload_whitelist(me: &Arc<Self>) -> impl Future<Item = (), Error = String> {
+        let me = me.clone();
+        util::decode::<Whitelist, _>(me.whitelist_path.clone()).and_then(move |wl| match me.whitelist.write() {
+            Ok(mut current) => {
+                *current = wl;
+                Ok(())
+            }
+            Err(_) => Err("whitelist lock poisoned".to_owned())
+        })
+    }
+
+    /// Removes the allowances and blocks that have lapsed, saving the whitelist if there were any, and
+    /// returns them.
+    pub fn expire_whitelist(&self) -> Vec<WhitelistExpiry> {
+        match self.whitelist.write() {
+            Ok(mut wl) => {
+                let lapsed = wl.expire(unix_now());
+                if !lapsed.is_empty() {
+                    tokio::spawn(wl.save(self.whitelist_path.clone()));
+                }
+                lapsed
+            }
+            Err(err) => {
+                error!("error while acquiring RwLock: {}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Runs `expire_whitelist` now and every `every` after, yielding what lapsed.
+    pub fn whitelist_expiries(me: &Arc<Self>, every: Duration) -> impl Stream<Item = WhitelistExpiry, Error = ()> {
+        let me = me.clone();
+        Interval::new(Instant::now(), every)
+            .map_err(|e| error!("whitelist expiry timer failed: {}", e))
+            .map(move |_| stream::iter_ok(me.expire_whitelist()))
+            .flatten()
+    }
+
+    /// Records that group `chat_id` was offered to request access, so that it is left if nobody does
+    /// within a day.
+    pub fn offer_access(&self, chat_id: i64) {
+        lock(&self.access_offers).insert(chat_id, Instant::now());
+    }
+
+    /// Drops the offers and requests older than a day, and returns the groups they were for that still
+    /// aren't allowed, for the frontend to leave.
+    pub fn expire_access(&self, now: Instant) -> Vec<i64> {
+        let mut gone = Vec::new();
+        lock(&self.access_offers).retain(|&id, at| {
+            let live = now.duration_since(*at) < ACCESS_REQUEST_TTL;
+            if !live {
+                gone.push(id);
+            }
+            live
+        });
+        lock(&self.access_requests).retain(|&id, req| {
+            let live = now.duration_since(req.at) < ACCESS_REQUEST_TTL;
+            if !live && req.group {
+                gone.push(id);
+            }
+            live
+        });
+        match self.whitelist.read() {
+            Ok(wl) => gone.retain(|&id| !wl.group_ok(id)),
+            Err(_) => gone.clear()
+        }
+        gone
+    }
+
+    /// Runs `expire_access` now and every `every` after, yielding the groups to leave.
+    pub fn access_expiries(me: &Arc<Self>, every: Duration) -> impl Stream<Item = i64, Error = ()> {
+        let me = me.clone();
+        Interval::new(Instant::now(), every)
+            .map_err(|e| error!("access request timer failed: {}", e))
+            .map(move |now| stream::iter_ok(me.expire_access(now)))
+            .flatten()
+    }
+
+    /// Records `req` for the owners to decide on. Chats that are blocked, already allowed, or asked within
+    /// the last day and haven't been answered are refused.
+    pub fn request_access(&self, req: AccessRequest) -> Result<(), String> {
+        match self.whitelist.read() {
+            Ok(wl) => {
+                if wl.blocked.contains(&req.chat_id) {
+                    return Err("This chat may not use the bot".to_owned());
+                }
+                let src = MessageSource { chat_id: req.chat_id, user_id: None, user_name: None, group: req.group };
+                if wl.source_ok(&src) {
+                    return Err("This chat can already use the bot".to_owned());
+                }
+            }
+            Err(_) => return Err("whitelist lock poisoned".to_owned())
+        }
+        let mut requests = lock(&self.access_requests);
+        match requests.get(&req.chat_id) {
+            Some(prev) if prev.at.elapsed() < ACCESS_REQUEST_TTL => Err("Access has already been requested".to_owned()),
+            _ => {
+                // the request has its own day to be decided in
+                lock(&self.access_offers).remove(&req.chat_id);
+                requests.insert(req.chat_id, req);
+                Ok(())
+            }
+        }
+    }
+
+    /// Approves or denies the pending request of `chat_id`, whitelisting the chat and saving the whitelist
+    /// if approved. Returns the request so that the requester can be told.
+    pub fn decide_access(&self, chat_id: i64, approve: bool) -> Result<AccessRequest, String> {
+        let req = lock(&self.access_requests).remove(&chat_id).ok_or_else(|| format!("No pending access request from {}", chat_id))?;
+        if approve {
+            match self.whitelist.write() {
+                Ok(mut wl) => {
+                    wl.allow(chat_id);
+                    tokio::spawn(wl.save(self.whitelist_path.clone()));
+                }
+                Err(_) => return Err("whitelist lock poisoned".to_owned())
+            }
+        }
+        Ok(req)
+    }
+
+    /// The persistent context key of a chat. Chats whose ID changed keep the key of their first ID, and
+    /// with it their state.
+    pub fn context(&self, chat_id: i64) -> String {
+        let id = self.whitelist.read().map(|wl| wl.original_id(chat_id)).unwrap_or(chat_id);
+        format!("{}{}", self.frontend.context_prefix(), id)
+    }
+
+    /// Carries everything known about chat `from` over to its new ID `to`: whitelist entries, notes,
+    /// quotas and usage, a pending access request, and the persistent context. The whitelist is saved, and
+    /// the note of a listed chat records the move.
+    pub fn migrate_chat(&self, from: i64, to: i64) {
+        match self.whitelist.write() {
+            Ok(mut wl) => {
+                if wl.migrate(from, to) {
+                    let (by, text) = match wl.note(to) {
+                        Some(note) if note.text.is_empty() => (note.by.clone(), format!("migrated from {}", from)),
+                        Some(note) => (note.by.clone(), format!("{}; migrated from {}", note.text, from)),
+                        None => ("migration".to_owned(), format!("migrated from {}", from))
+                    };
+                    wl.set_note(to, by, text);
+                }
+                tokio::spawn(wl.save(self.whitelist_path.clone()));
+            }
+            Err(err) => {
+                error!("error while acquiring RwLock: {}", err);
+                return;
+            }
+        }
+        if let Some(ref quotas) = self.quotas {
+            quotas.migrate(Account::Chat(from), Account::Chat(to));
+        }
+        let mut requests = lock(&self.access_requests);
+        if let Some(req) = requests.remove(&from) {
+            requests.insert(to, AccessRequest { chat_id: to, ..req });
+        }
+        let mut offers = lock(&self.access_offers);
+        if let Some(at) = offers.remove(&from) {
+            offers.insert(to, at);
+        }
+        info!("chat {} migrated to {}", from, to);
+    }
+
+    /// Owners are listed by user name or by user ID, whichever the frontend finds stable. Names are only
+    /// compared with names, so that nobody becomes an owner by taking an owner's ID as their name.
+    pub fn is_owner(&self, src: &MessageSource) -> bool {
+        src.user_name.as_ref().map(|n| self.owner_names.contains(n)).unwrap_or(false)
+            || src.user_id.map(|id| self.owner_ids.contains(&id)).unwrap_or(false)
+    }
+
+    /// Owners first, then chats and users on the whitelist, then everybody else, with messages from no
+    /// known user last.
+    pub fn priority(&self, src: &MessageSource) -> Priority {
+        if self.is_owner(src) {
+            return Priority::Owner;
+        }
+        if src.user_id.is_none() && src.user_name.is_none() {
+            return Priority::Anonymous;
+        }
+        let trusted = self.whitelist.read()
+            .map(|wl| wl.allowed.contains(&src.chat_id)
+                || src.user_id.map(|id| wl.allowed.contains(&id)).unwrap_or(false))
+            .unwrap_or(false);
+        if trusted { Priority::Trusted } else { Priority::Normal }
+    }
+
+    /// Every command name the dispatcher handles, aliases included, without any frontend prefix.
+    pub fn commands(&self) -> Vec<(String, Command)> {
+        let mut r = Vec::new();
+        for (name, lang) in self.service.langs().chain(self.service.aliases()) {
+            r.push((name.to_owned(), Command::Eval(lang.clone(), false)));
+            r.push((format!("{}#", name), Command::Eval(lang.clone(), true)));
+        }
+        r.extend(BUILTIN_COMMANDS.iter().map(|(name, cmd)| ((*name).to_owned(), cmd.clone())));
+        r
+    }
+
+    /// Splits `text` (with any frontend prefix already removed) into a command and its arguments.
+    pub fn parse<'a>(&self, text: &'a str) -> Option<(Command, &'a str)> {
+        let end = text.find(char::is_whitespace).unwrap_or(text.len());
+        let (name, args) = text.split_at(end);
+        let (lang_name, is_hash) = match name.strip_suffix('#') {
+            Some(n) => (n, true),
+            None => (name, false)
+        };
+        if let Some(lang) = self.service.get(lang_name) {
+            return Some((Command::Eval(lang.clone(), is_hash), args));
+        }
+        Command::builtin(name).map(|cmd| (cmd, args))
+    }
+
+    pub fn dispatch<S: ReplySink>(me: &Arc<Self>, cmd: Command, src: MessageSource, args: &str, sink: S)
+        -> ReplyFuture {
+        if me.draining.load(Ordering::SeqCst) {
+            return sink.reply(Reply::Text("bot restarting".to_owned()));
+        }
+        match cmd {
+            Command::Eval(lang, is_hash) => Dispatcher::eval(me, &lang, is_hash, src, args, sink),
+            Command::TogglePrivate | Command::ToggleGroup => me.whitelist_toggle(&cmd, &src, &sink),
+            Command::Allow | Command::Unallow | Command::Block | Command::Unblock =>
+                me.whitelist_mod(&cmd, &src, args, &sink),
+            Command::Whitelist => me.show_whitelist(&src, sink),
+            Command::Leave => me.leave(&src, args, &sink),
+            Command::Status => me.status(&src, &sink),
+            Command::Stop => me.stop(&src, &sink),
+            Command::Quota => me.quota(&src, args, &sink),
+            Command::Help => me.help(&src, true, &sink),
+            Command::Langs => me.help(&src, false, &sink),
+            Command::Reset => Dispatcher::reset(me, src, args, sink)
+        }
+    }
+
+    /// Cancels the evaluations running for `chat_id`, e.g. when the chat is gone, and returns how many
+    /// there were.
+    pub fn cancel_chat(&self, chat_id: i64) -> usize {
+        self.cancel_where(|id| id == chat_id)
+    }
+
+    /// Cancels every running evaluation and returns how many there were.
+    pub fn cancel_all(&self) -> usize {
+        self.cancel_where(|_| true)
+    }
+
+    // evaluations stay in `running` until their reply is sent, so that shutdown can wait for it
+    fn cancel_where<P: Fn(i64) -> bool>(&self, pred: P) -> usize {
+        let cancels = match self.running.lock() {
+            Ok(running) => running.iter()
+                .filter(|&(&id, _)| pred(id))
+                .flat_map(|(_, cancels)| cancels.iter().filter(|c| !c.is_cancelled()).cloned())
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new()
+        };
+        for cancel in &cancels {
+            cancel.cancel();
+        }
+        cancels.len()
+    }
+
+    fn running(&self) -> usize {
+        self.running.lock().map(|running| running.values().map(Vec::len).sum()).unwrap_or(0)
+    }
+
+    /// Stops accepting commands, waits up to `deadline` for running evaluations to finish, cancels the
+    /// rest (telling their users the bot is restarting), saves the whitelist and quota usage and stops
+    /// the backends.
+    pub fn shutdown(me: &Arc<Self>, deadline: Duration) -> impl Future<Item = (), Error = ()> {
+        me.draining.store(true, Ordering::SeqCst);
+        info!("draining {} running evaluations", me.running());
+        let (me, cancelling) = (me.clone(), me.clone());
+        Dispatcher::idle(&me, Instant::now() + deadline)
+            .and_then(move |_| {
+                let n = cancelling.cancel_all();
+                if n > 0 {
+                    warn!("cancelled {} evaluations still running", n);
+                }
+                Dispatcher::idle(&cancelling, Instant::now() + REPLY_GRACE)
+            })
+            .and_then(move |_| {
+                me.service.stop();
+                let wl = me.whitelist.read().map(|wl| wl.clone()).unwrap_or_default();
+                let usage = match me.quotas {
+                    Some(ref quotas) => Either::A(quotas.save()),
+                    None => Either::B(Ok(()).into_future())
+                };
+                wl.save(me.whitelist_path.clone()).join(usage).map(|_| ())
+            })
+    }
+
+    /// Resolves once nothing is running or at `until`, whichever is first.
+    fn idle(me: &Arc<Self>, until: Instant) -> impl Future<Item = (), Error = ()> {
+        let me = me.clone();
+        Interval::new_interval(Duration::from_millis(100))
+            .map_err(|e| error!("shutdown timer failed: {}", e))
+            .take_while(move |_| Ok(me.running() > 0 && Instant::now() < until))
+            .for_each(|_| Ok(()))
+    }
+
+    fn finished(&self, chat_id: i64, cancel: &Cancel) {
+        if let Ok(mut running) = self.running.lock() {
+            let empty = running.get_mut(&chat_id).map(|cancels| {
+                cancels.retain(|c| c != cancel);
+                cancels.is_empty()
+            });
+            if empty == Some(true) {
+                running.remove(&chat_id);
+            }
+        }
+    }
+
+    /// Takes one evaluation from the quotas of `accounts`, or says why they have none left.
+    fn reserve_quota(&self, accounts: &[Account]) -> Result<(), String> {
+        match self.quotas {
+            Some(ref quotas) if !accounts.is_empty() => {
+                let overrides = self.whitelist.read().map(|wl| wl.quotas.clone()).unwrap_or_default();
+                quotas.reserve(accounts, &overrides)
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// The reply for a source that may not use the bot, if it may not.
+    fn refusal(&self, src: &MessageSource) -> Option<Reply> {
+        match self.whitelist.read() {
+            Ok(wl) => if wl.source_ok(src) {
+                None
+            } else {
+                let text = format!("You or this group is not on the whitelist. Seek help. ID: {}", src.chat_id);
+                Some(if wl.blocked.contains(&src.chat_id) { Reply::Text(text) } else { Reply::NotAllowed(text) })
+            },
+            Err(_) => {
+                error!("Failed to acquire RwLock");
+                Some(Reply::Text("Internal error occurred".to_owned()))
+            }
+        }
+    }
+
+    fn eval<S: ReplySink>(me: &Arc<Self>, lang: &Arc<Language>, is_hash: bool, src: MessageSource, args: &str,
+        sink: S) -> ReplyFuture {
+        if let Some(refusal) = me.refusal(&src) {
+            return sink.reply(refusal);
+        }
+
+        // owners are exempt from quotas, and not charged either
+        let accounts = match me.quotas {
+            Some(_) if !me.is_owner(&src) => src.user_id.map(Account::User).into_iter()
+                .chain(Some(Account::Chat(src.chat_id)))
+                .collect::<Vec<_>>(),
+            _ => Vec::new()
+        };
+        if let Err(exceeded) = me.reserve_quota(&accounts) {
+            return sink.reply(Reply::Text(exceeded));
+        }
+
+        let no_limit = is_hash && me.is_owner(&src);
+        info!("({}) evaluating {} from {:?}: {:?}", src.chat_id, lang.name(), src.user_name, args);
+        let chat_id = src.chat_id;
+        let req = EvalRequest {
+            timeout: if no_limit { Some(0) } else { None },
+            context: Some(me.context(chat_id)),
+            priority: me.priority(&src),
+            user: src.user_id,
+            chat: Some(chat_id),
+            ..EvalRequest::new(lang.name(), me.frontend.extract_code(args))
+        };
+        let cancel = Cancel::new();
+        if let Ok(mut running) = me.running.lock() {
+            running.entry(chat_id).or_insert_with(Vec::new).push(cancel.clone());
+        }
+        let me = me.clone();
+        Box::new(me.service.eval_cancellable(req, &cancel)
+            .then(move |e| {
+                info!("({}) result: {:?}", chat_id, e);
+                // only what the backend actually ran is charged, from when it took the code
+                match (&me.quotas, cancel.started()) {
+                    (Some(quotas), Some(started)) if !accounts.is_empty() =>
+                        quotas.settle(&accounts, started.elapsed(), cancel.cpu_time()),
+                    (Some(quotas), None) if !accounts.is_empty() => quotas.release(&accounts),
+                    _ => ()
+                }
+                let reply = match e {
+                    Ok(r) => Some(Reply::Output(r)),
+                    Err(_) if cancel.is_cancelled() && me.draining.load(Ordering::SeqCst) =>
+                        Some(Reply::Text("bot restarting".to_owned())),
+                    // middleware chose not to answer
+                    Err(ref e) if e.is_empty() => None,
+                    Err(e) => Some(Reply::Text(e))
+                };
+                let sent = match reply {
+                    Some(reply) => Either::A(sink.reply(reply)),
+                    None => Either::B(Ok(()).into_future())
+                };
+                sent.then(move |r| {
+                    me.finished(chat_id, &cancel);
+                    r
+                })
+            }))
+    }
+
+    fn whitelist_toggle<S: ReplySink>(&self, cmd: &Command, src: &MessageSource, sink: &S) -> ReplyFuture {
+        if !self.is_owner(src) {
+            return Box::new(Ok(()).into_future());
+        }
+
+        let resp = match self.whitelist.write() {
+            Ok(mut wl) => {
+                let resp = if let Command::TogglePrivate = *cmd {
+                    wl.priv_enabled = !wl.priv_enabled;
+                    format!("Private whitelist enabled: {}", wl.priv_enabled)
+                } else {
+                    wl.group_enabled = !wl.group_enabled;
+                    format!("Group whitelist enabled: {}", wl.group_enabled)
+                };
+                tokio::spawn(wl.save(self.whitelist_path.clone()));
+                resp
+            }
+            Err(err) => {
+                error!("error while acquiring RwLock: {}", err);
+                "error acquiring RwLock".to_owned()
+            }
+        };
+        sink.reply(Reply::Text(resp))
+    }
+
+    fn whitelist_mod<S: ReplySink>(&self, cmd: &Command, src: &MessageSource, args: &str, sink: &S)
+        -> ReplyFuture {
+        if !self.is_owner(src) {
+            return Box::new(Ok(()).into_future());
+        }
+
+        let (ids, duration, note) = match parse_ids(args) {
+            Ok((ref ids, _, _)) if ids.is_empty() => return sink.reply(Reply::Text("Invalid ID".to_owned())),
+            Ok(parsed) => parsed,
+            Err(e) => return sink.reply(Reply::Text(e))
+        };
+        let blocking = match *cmd {
+            Command::Allow => false,
+            Command::Block => true,
+            _ if duration.is_some() =>
+                return sink.reply(Reply::Text("Only allow and block take a duration".to_owned())),
+            _ => false
+        };
+        // expiries are saved as TOML integers, which are signed
+        let expiry = match duration.map(|d| unix_now().checked_add(d).filter(|&t| t <= i64::MAX as u64)) {
+            Some(None) => return sink.reply(Reply::Text("Duration too long".to_owned())),
+            expiry => expiry.and_then(|e| e)
+        };
+        let by = src.user_name.clone().or_else(|| src.user_id.map(|id| id.to_string())).unwrap_or_default();
+        let resp = match self.whitelist.write() {
+            Ok(mut wl) => {
+                for &id in &ids {
+                    match *cmd {
+                        Command::Allow => wl.allow(id),
+                        Command::Unallow => wl.unallow(id),
+                        Command::Block => wl.block(id),
+                        _ => wl.unblock(id)
+                    }
+                    if let Some(expiry) = expiry {
+                        wl.set_expiry(id, blocking, Some(expiry));
+                    }
+                    wl.set_note(id, by.clone(), note.clone());
+                }
+                // groups that may no longer use the bot are left
+                let gone = ids.iter().cloned()
+                    .filter(|&id| self.frontend.is_group_id(id) && !wl.group_ok(id))
+                    .collect::<Vec<_>>();
+                for &id in &gone {
+                    self.cancel_chat(id);
+                    tokio::spawn(sink.leave_chat(id));
+                }
+                tokio::spawn(wl.save(self.whitelist_path.clone()));
+                let done = match *cmd {
+                    Command::Allow => "Allowed",
+                    Command::Unallow => "Unallowed",
+                    Command::Block => "Blocked",
+                    _ => "Unblocked"
+                };
+                let done = match duration {
+                    Some(duration) => format!("{} {} for {}", done, join_ids(&ids), format_duration(duration)),
+                    None => format!("{} {}", done, join_ids(&ids))
+                };
+                match gone.len() {
+                    0 => done,
+                    _ => format!("{}; leaving {}", done, join_ids(&gone))
+                }
+            }
+            Err(err) => {
+                error!("error while acquiring RwLock: {}", err);
+                "error acquiring RwLock".to_owned()
+            }
+        };
+        sink.reply(Reply::Text(resp))
+    }
+
+    fn show_whitelist<S: ReplySink>(&self, src: &MessageSource, sink: S) -> ReplyFuture {
+        if !self.is_owner(src) {
+            return Box::new(Ok(()).into_future());
+        }
+
+        let wl = match self.whitelist.read() {
+            Ok(wl) => wl.clone(),
+            Err(err) => {
+                error!("error while acquiring RwLock: {}", err);
+                return sink.reply(Reply::Text("error acquiring RwLock".to_owned()));
+            }
+        };
+        let mut allowed = wl.allowed.iter().cloned().collect::<Vec<_>>();
+        let mut blocked = wl.blocked.iter().cloned().collect::<Vec<_>>();
+        allowed.sort();
+        blocked.sort();
+        let names = allowed.iter().chain(&blocked).map(|&id| sink.chat_name(id)).collect::<Vec<_>>();
+        Box::new(future::join_all(names).and_then(move |names| {
+            let mut names = allowed.iter().chain(&blocked).cloned().zip(names).collect::<HashMap<_, _>>();
+            let mode = |enabled| if enabled { "whitelisted only" } else { "anyone not blocked" };
+            let now = unix_now();
+            let mut text = format!("Private chats: {}\nGroups: {}", mode(wl.priv_enabled), mode(wl.group_enabled));
+            for &(title, ids, blocked) in &[("Allowed", &allowed, false), ("Blocked", &blocked, true)] {
+                if ids.is_empty() {
+                    text.push_str(&format!("\n{}: none", title));
+                    continue;
+                }
+                text.push_str(&format!("\n{} ({}):", title, ids.len()));
+                for &id in ids.iter() {
+                    text.push_str(&format!("\n{}", id));
+                    if let Some(name) = names.remove(&id).and_then(|name| name) {
+                        text.push_str(&format!(" {}", name));
+                    }
+                    if let Some(at) = wl.expiry(id, blocked) {
+                        text.push_str(&format!(" (for {})", format_duration(at.saturating_sub(now))));
+                    }
+                    match wl.note(id) {
+                        Some(note) if note.text.is_empty() => text.push_str(&format!(" — by {}", note.by)),
+                        Some(note) => text.push_str(&format!(" — by {}: {}", note.by, note.text)),
+                        None => {}
+                    }
+                }
+            }
+            sink.reply(Reply::Text(text))
+        }))
+    }
+
+    fn leave<S: ReplySink>(&self, src: &MessageSource, args: &str, sink: &S) -> ReplyFuture {
+        if !self.is_owner(src) {
+            return Box::new(Ok(()).into_future());
+        }
+
+        let resp = match parse_id(args) {
+            Some(id) => {
+                tokio::spawn(sink.leave_chat(id));
+                "OK"
+            }
+            None => "Invalid ID"
+        };
+        sink.reply(Reply::Text(resp.to_owned()))
+    }
+
+    fn stop<S: ReplySink>(&self, src: &MessageSource, sink: &S) -> ReplyFuture {
+        if let Some(refusal) = self.refusal(src) {
+            return sink.reply(refusal);
+        }
+        sink.reply(Reply::Text(match self.cancel_chat(src.chat_id) {
+            0 => "Nothing to stop".to_owned(),
+            1 => "Stopped 1 evaluation".to_owned(),
+            n => format!("Stopped {} evaluations", n)
+        }))
+    }
+
+    /// `reset <lang>` discards the chat's state in a persistent language. In groups only owners and
+    /// group admins may do that, since everybody shares the state.
+    fn reset<S: ReplySink>(me: &Arc<Self>, src: MessageSource, args: &str, sink: S) -> ReplyFuture {
+        if let Some(refusal) = me.refusal(&src) {
+            return sink.reply(refusal);
+        }
+        let lang = match me.service.get(args.trim()) {
+            Some(lang) => lang.clone(),
+            None if args.trim().is_empty() => return sink.reply(Reply::Text("Usage: reset <language>".to_owned())),
+            None => return sink.reply(Reply::Text(format!("Unknown language {}", args.trim())))
+        };
+        if !lang.backend().is_persistent() {
+            return sink.reply(Reply::Text(format!("{}: this language has no persistent state", lang.name())));
+        }
+
+        let allowed: Box<dyn Future<Item = bool, Error = ()> + Send> = match src.user_id {
+            _ if !src.group || me.is_owner(&src) => Box::new(Ok(true).into_future()),
+            Some(user_id) => sink.is_admin(src.chat_id, user_id),
+            None => Box::new(Ok(false).into_future())
+        };
+        let context = me.context(src.chat_id);
+        Box::new(allowed.or_else(|_| Ok(false)).and_then(move |allowed| {
+            if !allowed {
+                return Either::A(sink.reply(Reply::Text("Only group admins can reset a language".to_owned())));
+            }
+            Either::B(lang.reset_context(&context).then(move |r| sink.reply(Reply::Text(match r {
+                Ok(()) => format!("{}: cleared this chat's state", lang.name()),
+                Err(e) => format!("{}: failed to reset: {}", lang.name(), e)
+            }))))
+        }))
+    }
+
+    /// `quota <id>` shows what a user or chat used and may use, `quota reset <id>` forgets what it
+    /// used, and `quota set <id> [evals=N] [wall=S] [cpu=S]` and `quota unset <id>` give it its own
+    /// limits or take them away again.
+    fn quota<S: ReplySink>(&self, src: &MessageSource, args: &str, sink: &S) -> ReplyFuture {
+        if !self.is_owner(src) {
+            return Box::new(Ok(()).into_future());
+        }
+        let quotas = match self.quotas {
+            Some(ref quotas) => quotas,
+            None => return sink.reply(Reply::Text("Quotas are not enabled".to_owned()))
+        };
+
+        let mut words = args.split_whitespace();
+        let (sub, id) = match words.next() {
+            Some(sub @ "reset") | Some(sub @ "set") | Some(sub @ "unset") => (sub, words.next()),
+            id => ("show", id)
+        };
+        let id = match id.and_then(|id| id.parse::<i64>().ok()) {
+            Some(id) => id,
+            None => return sink.reply(Reply::Text("Invalid ID".to_owned()))
+        };
+        // user and chat IDs never collide, except for private chats, which are the same person
+        let accounts = [Account::User(id), Account::Chat(id)];
+        let resp = match sub {
+            "reset" => {
+                for &account in &accounts {
+                    quotas.reset(account);
+                }
+                format!("Reset usage of {}", id)
+            }
+            "set" | "unset" => {
+                let limits = if sub == "set" {
+                    match Limits::parse(&words.collect::<Vec<_>>().join(" ")) {
+                        Ok(limits) => Some(limits),
+                        Err(e) => return sink.reply(Reply::Text(e))
+                    }
+                } else {
+                    None
+                };
+                match self.whitelist.write() {
+                    Ok(mut wl) => {
+                        wl.set_quota(id, limits);
+                        tokio::spawn(wl.save(self.whitelist_path.clone()));
+                        match limits {
+                            Some(limits) => format!("Quota of {}: {}", id, limits),
+                            None => format!("Quota of {} back to default", id)
+                        }
+                    }
+                    Err(err) => {
+                        error!("error while acquiring RwLock: {}", err);
+                        "error acquiring RwLock".to_owned()
+                    }
+                }
+            }
+            _ => {
+                let overrides = self.whitelist.read().map(|wl| wl.quotas.clone()).unwrap_or_default();
+                accounts.iter()
+                    .map(|&a| format!("{}: used {} of {}", a, quotas.used(a), quotas.limits(a, &overrides)))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        };
+        sink.reply(Reply::Text(resp))
+    }
+
+    /// Every language, with the built-in commands first if `commands`. Owner commands are only shown
+    /// to owners.
+    fn help<S: ReplySink>(&self, src: &MessageSource, commands: bool, sink: &S) -> ReplyFuture {
+        if let Some(refusal) = self.refusal(src) {
+            return sink.reply(refusal);
+        }
+        let owner = self.is_owner(src);
+        let prefix = self.frontend.command_prefix();
+        let mut lines = Vec::new();
+        if commands {
+            lines.push(format!("Evaluate code with {}<language> <code>, or {}<language># for no time limit \
+                (owners only).", prefix, prefix));
+            lines.extend(BUILTIN_COMMANDS.iter()
+                .filter(|(_, cmd)| owner || !cmd.owner_only())
+                .map(|(name, cmd)| format!("{}{} — {}", prefix, name, cmd.description())));
+            lines.push(String::new());
+        }
+
+        let mut langs = self.service.langs().map(|(_, lang)| lang).collect::<Vec<_>>();
+        langs.sort_by(|a, b| a.name().cmp(b.name()));
+        if langs.is_empty() {
+            lines.push("No languages configured".to_owned());
+        }
+        for lang in langs {
+            let names = Some(lang.name()).into_iter()
+                .chain(lang.aliases().iter().map(String::as_str))
+                .map(|n| format!("{}{}", prefix, n))
+                .collect::<Vec<_>>();
+            let mut notes = vec![match lang.timeout() {
+                Some(t) => format!("{}s limit", t),
+                None => "no time limit".to_owned()
+            }];
+            if lang.backend().is_persistent() {
+                notes.push("keeps state per chat".to_owned());
+            }
+            if owner && lang.timeout().is_some() {
+                notes.push(format!("{}{}# for no limit", prefix, lang.name()));
+            }
+            lines.push(format!("{} — {} ({})", names.join(", "), lang.describe(), notes.join(", ")));
+        }
+        sink.reply(Reply::Text(lines.join("\n")))
+    }
+
+    fn status<S: ReplySink>(&self, src: &MessageSource, sink: &S) -> ReplyFuture {
+        if let Some(refusal) = self.refusal(src) {
+            return sink.reply(refusal);
+        }
+        let lines = self.service.status().iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        sink.reply(Reply::Text(if lines.is_empty() {
+            "No languages configured".to_owned()
+        } else {
+            lines.join("\n")
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, fs, process};
+    use crate::EvalBackend;
+    use crate::eval;
+    use crate::quota::{QuotaCfg, Usage, Window};
+    use crate::test_support::{FakeDaemon, ScriptedBackend, TestChat, TestFrontend, block_on,
+        echo_service, service, source};
+
+    fn owners(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|&n| n.to_owned()).collect()
+    }
+
+    // a fresh path in the temporary directory
+    fn temp_file(name: &str) -> String {
+        let path = env::temp_dir().join(format!("evalbot-test-{}-{}.toml", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn dispatcher() -> Dispatcher<TestFrontend> {
+        let service = EvalService::from_toml(r#"
+timeout = 20
+
+[languages.rs]
+cmdline = ["rustc"]
+"#).unwrap();
+        Dispatcher::new(TestFrontend, service, vec!["owner".to_owned(), "42".to_owned()].into_iter().collect(),
+            Whitelist::default(), "whitelist.toml".to_owned())
+    }
+
+    #[test]
+    fn test_parse() {
+        let d = dispatcher();
+        match d.parse("rs# 1 + 1") {
+            Some((Command::Eval(lang, true), " 1 + 1")) => assert_eq!(lang.name(), "rs"),
+            r => panic!("unexpected {:?}", r)
+        }
+        match d.parse("allow 5") {
+            Some((Command::Allow, " 5")) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        match d.parse("status") {
+            Some((Command::Status, "")) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        match d.parse("stop") {
+            Some((Command::Stop, "")) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        match d.parse("reset py") {
+            Some((Command::Reset, " py")) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        match d.parse("help") {
+            Some((Command::Help, "")) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        match d.parse("quota reset 5") {
+            Some((Command::Quota, " reset 5")) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        assert!(d.parse("py 1").is_none());
+    }
+
+    #[test]
+    fn test_owner() {
+        let d = dispatcher();
+        let mut src = MessageSource { chat_id: 1, user_id: Some(7), user_name: Some("owner".to_owned()), group: false };
+        assert!(d.is_owner(&src));
+        src.user_name = None;
+        assert!(!d.is_owner(&src));
+        src.user_id = Some(42);
+        assert!(d.is_owner(&src));
+
+        // Discord names may be all digits
+        let d = Dispatcher::new(TestFrontend, service(Vec::new()), owners(&["9"]), Whitelist::default(),
+            String::new());
+        let src = MessageSource { user_name: Some("9".to_owned()), ..source(1, 1) };
+        assert!(!d.is_owner(&src));
+        assert!(d.is_owner(&source(1, 9)));
+    }
+
+    #[test]
+    fn test_priority() {
+        let d = dispatcher();
+        let mut src = MessageSource { chat_id: -5, user_id: Some(42), user_name: None, group: true };
+        assert_eq!(d.priority(&src), Priority::Owner);
+        src.user_id = Some(7);
+        assert_eq!(d.priority(&src), Priority::Normal);
+        d.whitelist().write().unwrap().allow(-5);
+        assert_eq!(d.priority(&src), Priority::Trusted);
+        src.user_id = None;
+        assert_eq!(d.priority(&src), Priority::Anonymous);
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let mut wl = Whitelist::default();
+        let src = MessageSource { chat_id: -5, user_id: None, user_name: None, group: true };
+        assert!(wl.source_ok(&src));
+        wl.group_enabled = true;
+        assert!(!wl.source_ok(&src));
+        wl.allow(-5);
+        assert!(wl.source_ok(&src));
+        wl.block(-5);
+        assert!(!wl.source_ok(&src));
+
+        wl.set_quota(7, Some(Limits { evals: Some(100), ..Limits::default() }));
+        wl.set_quota(8, Some(Limits::default()));
+        wl.set_note(-5, "boss".to_owned(), "spam".to_owned());
+        wl.set_note(9, "boss".to_owned(), String::new());
+        assert_eq!(wl.notes.len(), 1);
+        wl.allow(10);
+        wl.set_expiry(10, false, Some(100));
+        wl.set_expiry(-5, true, Some(200));
+        wl.set_note(10, "boss".to_owned(), "workshop".to_owned());
+        let saved = toml::to_string(&wl).unwrap();
+        assert_eq!(toml::from_str::<Whitelist>(&saved).unwrap(), wl);
+        wl.set_quota(7, None);
+        assert_eq!(wl.quotas, vec![QuotaOverride { id: 8, limits: Limits::default() }]);
+
+        assert_eq!(wl.expire(99), vec![]);
+        assert_eq!(wl.expire(150), vec![WhitelistExpiry { id: 10, blocked: false, at: 100 }]);
+        assert!(!wl.allowed.contains(&10) && wl.note(10).is_none());
+        assert_eq!(wl.expiry(-5, true), Some(200));
+        wl.block(-5);
+        assert_eq!(wl.expiry(-5, true), None);
+    }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(parse_ids("-5 7 7d -- workshop"), Ok((vec![-5, 7], Some(7 * 24 * 60 * 60), "workshop".to_owned())));
+        // a note that starts with a number is not taken for more IDs
+        assert_eq!(parse_ids("-100123 -- 2024 contest"), Ok((vec![-100123], None, "2024 contest".to_owned())));
+        assert!(parse_ids("-100123 2024 contest").is_err());
+        assert!(parse_ids("-5 d").is_err());
+        assert!(parse_ids("-5 7d 8").is_err());
+        assert_eq!(parse_duration("90m"), Some(90 * 60));
+        assert_eq!(parse_duration("0h"), None);
+        assert_eq!(format_duration(7 * 24 * 60 * 60 - 1), "6d 23h");
+        assert_eq!(format_duration(90 * 60), "1h 30m");
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let daemon = FakeDaemon::echo().unwrap();
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend, service(vec![("p", daemon.backend())]),
+            HashSet::new(), Whitelist::default(), String::new()));
+        assert_eq!(chat.run(&source(7, 1), "p  print(1)"), Reply::Output("print(1)\n".to_owned()));
+        assert_eq!(daemon.requests()[0].context, "test7");
+    }
+
+    #[test]
+    fn test_help() {
+        let daemon = FakeDaemon::echo().unwrap();
+        let mut svc = service(vec![
+            ("p", Arc::new(ScriptedBackend::default()) as Arc<dyn EvalBackend>),
+            ("d", daemon.backend())
+        ]);
+        Arc::make_mut(svc.languages.get_mut("p").unwrap()).timeout = Some(10);
+        svc.alias("q", "p").unwrap();
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend, svc, owners(&["9"]), Whitelist::default(),
+            String::new()));
+        let (user, owner) = (source(7, 1), source(7, 9));
+        let mut run = |src: &MessageSource, text: &str| match chat.run(src, text) {
+            Reply::Text(t) => t,
+            r => panic!("unexpected {:?}", r)
+        };
+
+        assert_eq!(run(&user, "langs"), "d — d (no time limit, keeps state per chat)\np, q — p (10s limit)");
+        assert_eq!(run(&owner, "langs"),
+            "d — d (no time limit, keeps state per chat)\np, q — p (10s limit, p# for no limit)");
+        let help = run(&user, "help");
+        assert!(help.contains("\nstop — Stop everything running in this chat\n"), "{}", help);
+        assert!(!help.contains("allow"), "{}", help);
+        assert!(help.ends_with("\n\nd — d (no time limit, keeps state per chat)\np, q — p (10s limit)"), "{}", help);
+        assert!(run(&owner, "help").contains("\nallow — Whitelist users or chats: allow <id>... [duration] [-- note]\n"));
+    }
+
+    #[test]
+    fn test_reset() {
+        let daemon = FakeDaemon::echo().unwrap();
+        let svc = service(vec![
+            ("d", daemon.backend()),
+            ("p", Arc::new(ScriptedBackend::default()) as Arc<dyn EvalBackend>)
+        ]);
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend, svc, HashSet::new(), Whitelist::default(),
+            String::new()));
+        let (private, group) = (source(7, 1), source(-5, 1));
+
+        chat.run(&private, "reset d");
+        let req = daemon.requests().pop().unwrap();
+        assert_eq!((req.timeout_ms, req.context.as_str(), req.code.as_str()), (eval::RESET, "test7", ""));
+        chat.run(&private, "reset p");
+        chat.run(&private, "reset");
+        chat.run(&group, "reset d");
+        chat.sink.add_admin(-5, 1);
+        chat.run(&group, "reset d");
+        assert_eq!(daemon.requests().pop().unwrap().context, "test-5");
+        assert_eq!(daemon.requests().len(), 2);
+        assert_eq!(chat.sink.replies(), vec![
+            Reply::Text("d: cleared this chat's state".to_owned()),
+            Reply::Text("p: this language has no persistent state".to_owned()),
+            Reply::Text("Usage: reset <language>".to_owned()),
+            Reply::Text("Only group admins can reset a language".to_owned()),
+            Reply::Text("d: cleared this chat's state".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn test_reload_whitelist() {
+        let path = temp_file("reload");
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend, service(Vec::new()), HashSet::new(),
+            Whitelist::default(), path.clone()));
+        fs::write(&path, "priv_enabled = true\ngroup_enabled = false\nallowed = [7]\nblocked = []\n").unwrap();
+        chat.block_on(Dispatcher::reload_whitelist(&chat.dispatcher)).unwrap();
+        assert!(chat.dispatcher.whitelist().read().unwrap().priv_ok(7));
+        assert!(!chat.dispatcher.whitelist().read().unwrap().priv_ok(8));
+
+        fs::write(&path, "priv_enabled = ").unwrap();
+        assert!(chat.block_on(Dispatcher::reload_whitelist(&chat.dispatcher)).unwrap_err().contains("could not parse"));
+        assert!(chat.dispatcher.whitelist().read().unwrap().priv_ok(7));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_access_request() {
+        let path = temp_file("access");
+        let wl = Whitelist { priv_enabled: true, blocked: vec![8].into_iter().collect(), ..Whitelist::default() };
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend, echo_service(), HashSet::new(), wl, path.clone()));
+        assert_eq!(chat.run(&source(7, 7), "p 1"),
+            Reply::NotAllowed("You or this group is not on the whitelist. Seek help. ID: 7".to_owned()));
+        assert_eq!(chat.run(&source(8, 8), "p 1"),
+            Reply::Text("You or this group is not on the whitelist. Seek help. ID: 8".to_owned()));
+
+        let request = |chat_id| AccessRequest {
+            chat_id,
+            group: false,
+            requester: "someone".to_owned(),
+            at: Instant::now()
+        };
+        let d = chat.dispatcher.clone();
+        assert_eq!(d.request_access(request(8)), Err("This chat may not use the bot".to_owned()));
+        assert_eq!(d.request_access(request(7)), Ok(()));
+        assert_eq!(d.request_access(request(7)), Err("Access has already been requested".to_owned()));
+        assert_eq!(d.request_access(request(9)), Ok(()));
+        assert_eq!(d.decide_access(9, false).unwrap().chat_id, 9);
+        assert!(!d.whitelist().read().unwrap().priv_ok(9));
+        assert_eq!(chat.with(|d| d.decide_access(7, true)).unwrap().requester, "someone");
+        assert_eq!(d.decide_access(7, true), Err("No pending access request from 7".to_owned()));
+        assert_eq!(d.request_access(request(7)), Err("This chat can already use the bot".to_owned()));
+        assert_eq!(chat.run(&source(7, 7), "p 1"), Reply::Output("1\n".to_owned()));
+        assert!(Path::new(&path).exists());
+        let _ = fs::remove_file(&path);
+
+        // groups that nobody asks for, or whose request isn't decided, are left after a day
+        d.whitelist().write().unwrap().group_enabled = true;
+        d.offer_access(-5);
+        d.offer_access(-6);
+        d.offer_access(-7);
+        assert_eq!(d.request_access(AccessRequest { group: true, ..request(-6) }), Ok(()));
+        d.whitelist().write().unwrap().allow(-7);
+        assert_eq!(d.expire_access(Instant::now()), Vec::<i64>::new());
+        let mut gone = d.expire_access(Instant::now() + Duration::from_secs(25 * 60 * 60));
+        gone.sort();
+        assert_eq!(gone, vec![-6, -5]);
+        assert_eq!(d.decide_access(-6, true), Err("No pending access request from -6".to_owned()));
+    }
+
+    #[test]
+    fn test_whitelist_commands() {
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend, echo_service(), owners(&["boss"]),
+            Whitelist { group_enabled: true, ..Whitelist::default() }, String::new()));
+        let owner = MessageSource { user_name: Some("boss".to_owned()), ..source(1, 1) };
+        let mut run = |text: &str| chat.run(&owner, text);
+
+        assert_eq!(run("allow -5 -6 7 -- contest until friday"), Reply::Text("Allowed -5, -6, 7".to_owned()));
+        assert_eq!(run("allow -5 2024 contest"),
+            Reply::Text("Expected an ID or a duration, got \"contest\"; a note goes after --".to_owned()));
+        assert_eq!(run("block 8"), Reply::Text("Blocked 8".to_owned()));
+        assert_eq!(run("allow x"), Reply::Text("Invalid ID".to_owned()));
+        assert_eq!(run("unallow -6 7"), Reply::Text("Unallowed -6, 7; leaving -6".to_owned()));
+        assert_eq!(run("unallow -5 1d"), Reply::Text("Only allow and block take a duration".to_owned()));
+        assert_eq!(run("block 9 2h"), Reply::Text("Blocked 9 for 2h 0m".to_owned()));
+        assert_eq!(run("allow -5 18446744073709551615s"), Reply::Text("Duration too long".to_owned()));
+        assert_eq!(run("allow -5 106751991167300d"), Reply::Text("Duration too long".to_owned()));
+        assert!(chat.dispatcher.whitelist().read().unwrap().expiry(9, true).is_some());
+        assert!(chat.dispatcher.whitelist().read().unwrap().expiry(-5, false).is_none());
+        assert_eq!(chat.run(&owner, "unblock 9"), Reply::Text("Unblocked 9".to_owned()));
+        assert_eq!(chat.run(&owner, "whitelist"), Reply::Text("Private chats: anyone not blocked\n\
+            Groups: whitelisted only\n\
+            Allowed (1):\n\
+            -5 — by boss: contest until friday\n\
+            Blocked (1):\n\
+            8 — by boss".to_owned()));
+        assert_eq!(chat.sink.left(), vec![-6]);
+        assert_eq!(chat.dispatcher.whitelist().read().unwrap().notes.len(), 2);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let path = temp_file("migrate");
+        let cfg = QuotaCfg { window: Window::Daily, user: Limits::default(), chat: Limits::default() };
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend, echo_service(), owners(&["9"]),
+            Whitelist { group_enabled: true, ..Whitelist::default() }, String::new())
+            .with_quotas(Quotas::new(cfg, Usage::default(), path.clone())));
+        let (owner, group, moved) = (source(1, 9), source(-5, 2), source(-1005, 2));
+
+        chat.run(&owner, "allow -5 -- workshop");
+        chat.run(&owner, "quota set -5 evals=10");
+        assert_eq!(chat.run(&group, "p 1"), Reply::Output("1\n".to_owned()));
+        chat.with(|d| d.migrate_chat(-5, -1005));
+        assert_eq!(chat.run(&moved, "p 2"), Reply::Output("2\n".to_owned()));
+        assert!(chat.run(&group, "p 3") != Reply::Output("3\n".to_owned()));
+        assert_eq!(chat.dispatcher.context(-1005), "test-5");
+        assert_eq!(chat.dispatcher.whitelist().read().unwrap().note(-1005).unwrap().text,
+            "workshop; migrated from -5");
+        match chat.run(&owner, "quota -1005") {
+            Reply::Text(ref t) if t.ends_with("chat -1005: used evals=2 wall=0.0s cpu=0.0s of evals=10") => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_stop() {
+        let backend = ScriptedBackend::default().then_delayed(Duration::from_secs(5), Ok("late".to_owned()));
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend,
+            service(vec![("p", Arc::new(backend) as Arc<dyn EvalBackend>)]), HashSet::new(), Whitelist::default(),
+            String::new()));
+        let src = source(7, 1);
+        let eval = chat.dispatch(&src, "p 1");
+        chat.run(&src, "stop");
+        block_on(eval).unwrap();
+        chat.run(&src, "stop");
+        assert_eq!(chat.sink.replies(), vec![
+            Reply::Text("Stopped 1 evaluation".to_owned()),
+            Reply::Text("cancelled".to_owned()),
+            Reply::Text("Nothing to stop".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let backend = ScriptedBackend::default()
+            .then_delayed(Duration::from_millis(100), Ok("quick".to_owned()))
+            .then_delayed(Duration::from_secs(10), Ok("slow".to_owned()));
+        let path = temp_file("shutdown");
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend,
+            service(vec![("p", Arc::new(backend) as Arc<dyn EvalBackend>)]), HashSet::new(), Whitelist::default(),
+            path.clone()));
+        let src = source(7, 1);
+        let quick = chat.dispatch(&src, "p 1");
+        let slow = chat.dispatch(&src, "p 1");
+        let shutdown = Dispatcher::shutdown(&chat.dispatcher, Duration::from_millis(500));
+        chat.run(&src, "p 1");
+        chat.block_on(quick.join(slow).join(shutdown)).unwrap();
+        assert_eq!(chat.sink.replies(), vec![
+            Reply::Text("bot restarting".to_owned()),
+            Reply::Output("quick".to_owned()),
+            Reply::Text("bot restarting".to_owned())
+        ]);
+        assert!(Path::new(&path).exists());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_quota() {
+        let path = temp_file("quota");
+        let cfg = QuotaCfg {
+            window: Window::Daily,
+            user: Limits { evals: Some(1), ..Limits::default() },
+            chat: Limits::default()
+        };
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend, echo_service(), owners(&["9"]),
+            Whitelist::default(), String::new())
+            .with_quotas(Quotas::new(cfg, Usage::default(), path.clone())));
+        let (user, owner) = (source(7, 1), source(7, 9));
+        let mut run = |src: &MessageSource, text: &str| chat.run(src, text);
+
+        assert_eq!(run(&user, "p 1"), Reply::Output("1\n".to_owned()));
+        match run(&user, "p 2") {
+            Reply::Text(ref t) if t.starts_with("Quota exceeded: user 1 has used up its evaluations today") => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        // owners are not limited, and their evaluations are not charged to the chat
+        assert_eq!(run(&owner, "p 3"), Reply::Output("3\n".to_owned()));
+        assert_eq!(run(&owner, "quota 1"), Reply::Text("user 1: used evals=1 wall=0.0s cpu=0.0s of evals=1\n\
+            chat 1: used evals=0 wall=0.0s cpu=0.0s of unlimited".to_owned()));
+        assert_eq!(run(&owner, "quota reset 1"), Reply::Text("Reset usage of 1".to_owned()));
+        assert_eq!(run(&user, "p 4"), Reply::Output("4\n".to_owned()));
+        assert_eq!(run(&owner, "quota set 1 evals=3 cpu=60"), Reply::Text("Quota of 1: evals=3 cpu=60s".to_owned()));
+        assert_eq!(run(&user, "p 5"), Reply::Output("5\n".to_owned()));
+        assert_eq!(run(&owner, "quota unset 1"), Reply::Text("Quota of 1 back to default".to_owned()));
+        assert!(run(&user, "p 6") != Reply::Output("6\n".to_owned()));
+        assert!(Path::new(&path).exists());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_quota_concurrent() {
+        let path = temp_file("quota-concurrent");
+        let cfg = QuotaCfg {
+            window: Window::Daily,
+            user: Limits { evals: Some(1), ..Limits::default() },
+            chat: Limits::default()
+        };
+        let backend = ScriptedBackend::default().then_delayed(Duration::from_millis(200), Ok("slow".to_owned()));
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend,
+            service(vec![("p", Arc::new(backend) as Arc<dyn EvalBackend>)]), HashSet::new(), Whitelist::default(),
+            String::new())
+            .with_quotas(Quotas::new(cfg, Usage::default(), path.clone())));
+        let user = source(7, 1);
+
+        // the second one is refused while the first is still running
+        let first = chat.dispatch(&user, "p 1");
+        match chat.run(&user, "p 2") {
+            Reply::Text(ref t) if t.starts_with("Quota exceeded") => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        chat.block_on(first).unwrap();
+        assert_eq!(chat.sink.replies().last(), Some(&Reply::Output("slow".to_owned())));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_quota_refused() {
+        let path = temp_file("quota-refused");
+        let cfg = QuotaCfg {
+            window: Window::Daily,
+            user: Limits { evals: Some(1), ..Limits::default() },
+            chat: Limits::default()
+        };
+        let backend = Arc::new(ScriptedBackend::new().then_err("exited with status 1"));
+        let mut service = service(vec![("p", backend as Arc<dyn EvalBackend>)]);
+        let deny: crate::Deny = toml::from_str(r#"patterns = ["fork"]"#).unwrap();
+        service.attach(None, 0, Arc::new(deny)).unwrap();
+        let mut chat = TestChat::new(Dispatcher::new(TestFrontend, service, HashSet::new(),
+            Whitelist::default(), String::new())
+            .with_quotas(Quotas::new(cfg, Usage::default(), path.clone())));
+        let user = source(7, 1);
+
+        // refused before reaching the backend, so not charged
+        assert_eq!(chat.run(&user, "p fork()"), Reply::Text("code containing \"fork\" is not allowed".to_owned()));
+        // failed, but it ran
+        assert_eq!(chat.run(&user, "p 1"), Reply::Text("exited with status 1".to_owned()));
+        match chat.run(&user, "p 2") {
+            Reply::Text(ref t) if t.starts_with("Quota exceeded") => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
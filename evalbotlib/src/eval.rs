@@ -1,17 +1,27 @@
-use std::process::{Command, Stdio};
-use std::io::Cursor;
-use std::sync::Arc;
+use std::mem;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use std::time::Duration;
-use std::os::unix::process::ExitStatusExt;
+use std::os::unix::process::{CommandExt as StdCommandExt, ExitStatusExt};
 
 use tokio::prelude::*;
 use tokio::prelude::future::Either;
 use tokio::timer::timeout;
 use tokio_process::CommandExt;
+use futures::sync::oneshot;
 use tokio::{io::{flush, read_exact, write_all}, net::unix::UnixStream};
 use bytes::{BytesMut, Buf, BufMut};
 
-use crate::{ExecBackend, UnixSocketBackend};
+use crate::{Cancel, ExecBackend, UnixSocketBackend};
+
+/// Sent in place of a request's timeout to discard its context instead of evaluating.
+pub(crate) const RESET: u32 = 0xFFFF_FFFE;
+
+/// Seconds a daemon gets to discard a context.
+pub(crate) const RESET_TIMEOUT: usize = 5;
 
 fn strsig(sig: i32) -> &'static str {
     match sig {
@@ -87,84 +97,189 @@ fn strsigabbrev(sig: i32) -> &'static str {
     }
 }
 
-pub fn exec<'a, T>(
-    lang: Arc<ExecBackend>,
+/// Kills a command spawned by `exec`, or the process group it leads, unless it has exited.
+#[derive(Clone)]
+struct Kill {
+    pid: libc::pid_t,
+    group: bool,
+    exited: Arc<Mutex<bool>>
+}
+
+impl Kill {
+    fn kill(&self) {
+        let exited = self.exited.lock().unwrap_or_else(|e| e.into_inner());
+        // until `exited` is set the command isn't reaped, so its pid can't have been reused
+        if !*exited {
+            let pid = if self.group { -self.pid } else { self.pid };
+            debug!("killing {}", pid);
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Kills the command if the evaluation is dropped before it has finished, e.g. when it times out.
+struct Running(Kill);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.kill();
+    }
+}
+
+struct Output {
+    status: ExitStatus,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    cpu: Duration
+}
+
+fn read_up_to<R: Read>(io: R) -> io::Result<Vec<u8>> {
+    // FIXME configurable max
+    let mut v = Vec::with_capacity(1024);
+    io.take(1024).read_to_end(&mut v)?;
+    Ok(v)
+}
+
+/// Feeds `code` to the command and collects its output, then reaps it. Blocks until it has exited.
+fn wait_output<T: AsRef<[u8]> + Send + 'static>(mut child: Child, code: T, exited: &Mutex<bool>)
+    -> Result<Output, String> {
+    let writer = child.stdin.take()
+        .map(|mut stdin| thread::spawn(move || stdin.write_all(code.as_ref())));
+    let stderr = child.stderr.take().map(|stderr| thread::spawn(move || read_up_to(stderr)));
+    let stdout = child.stdout.take().map_or(Ok(Vec::new()), read_up_to);
+    let stderr = stderr.map_or(Ok(Ok(Vec::new())), |t| t.join());
+    // reap it whatever happened to its pipes
+    let (status, cpu) = reap(child.id() as libc::pid_t, exited)
+        .map_err(|e| format!("failed to wait for process: {}", e))?;
+    match writer.map(|t| t.join()) {
+        Some(Ok(Ok(()))) => (),
+        Some(Ok(Err(e))) => return Err(format!("failed to write to stdin: {}", e)),
+        Some(Err(_)) => return Err("failed to write to stdin".to_owned()),
+        None => return Err("stdin missing".to_owned())
+    }
+    let (stdout, stderr) = match (stdout, stderr) {
+        (Ok(stdout), Ok(Ok(stderr))) => (stdout, stderr),
+        (Err(e), _) | (_, Ok(Err(e))) => return Err(format!("failed to read output: {}", e)),
+        (_, Err(_)) => return Err("failed to read output".to_owned())
+    };
+    Ok(Output { status, stdout, stderr, cpu })
+}
+
+/// Waits for `pid` to exit, then reaps it. Also returns the CPU time used by it and everything it
+/// waited for.
+fn reap(pid: libc::pid_t, exited: &Mutex<bool>) -> io::Result<(ExitStatus, Duration)> {
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    // leave it a zombie for now, so that `Kill` knows it's gone before its pid is freed
+    let options = libc::WEXITED | libc::WNOWAIT;
+    while unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, options) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    *exited.lock().unwrap_or_else(|e| e.into_inner()) = true;
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    while unsafe { libc::wait4(pid, &mut status, 0, &mut usage) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    let micros = |t: libc::timeval| t.tv_sec as u64 * 1_000_000 + t.tv_usec as u64;
+    let cpu = Duration::from_micros(micros(usage.ru_utime) + micros(usage.ru_stime));
+    Ok((ExitStatus::from_raw(status), cpu))
+}
+
+pub fn exec<T>(
+    lang: &ExecBackend,
     timeout: Option<usize>,
-    code: T) -> impl Future<Item = String, Error = String> + 'a
-        where T: AsRef<[u8]> + 'a {
+    code: T,
+    cancel: Option<&Cancel>) -> impl Future<Item = String, Error = String>
+        where T: AsRef<[u8]> + Send + 'static {
     let timeout_arg = timeout
-        .map(|t| format!("{}{}", lang.timeout_prefix.as_ref().map(String::as_str).unwrap_or(""), t));
-    let timeout_arg_ref = timeout_arg.as_ref().map(String::as_str);
-    if let Some(path) = lang.cmdline.iter().nth(0) {
-        let mut cmd = Command::new(path);
-        cmd.args(lang.cmdline.iter()
-            .skip(1)
-            .filter_map(|a| if a == "{TIMEOUT}" {
-                timeout_arg_ref
-            } else {
-                Some(a.as_ref())
-            }))
-            .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
-        debug!("spawning {:?}", cmd);
-        Either::A(cmd.spawn_async()
-            .map_err(|e| format!("failed to exec: {}", e))
-            .into_future()
-            .and_then(|mut child| {
-                child.stdin().take().ok_or_else(|| "stdin missing".to_owned()).into_future()
-                    .and_then(|stdin|
-                        write_all(stdin, code)
-                            .map_err(|e| format!("failed to write to stdin: {}", e))
-                    )
-                    .and_then(move |_| {
-                        let stdout = match child.stdout().take() {
-                            // FIXME configurable max
-                            Some(io) => Either::A(helper::read_up_to(io, vec![0u8; 1024])
-                                .map(|(_, mut v, s)| {
-                                    v.truncate(s);
-                                    v
-                                })),
-                            None => Either::B(Ok(Vec::new()).into_future()),
-                        };
-                        let stderr = match child.stderr().take() {
-                            // FIXME configurable max
-                            Some(io) => Either::A(helper::read_up_to(io, vec![0u8; 1024])
-                                .map(|(_, mut v, s)| {
-                                    v.truncate(s);
-                                    v
-                                })),
-                            None => Either::B(Ok(Vec::new()).into_future()),
-                        };
-                        child.join3(stdout, stderr)
-                    }.map_err(|e| format!("failed to wait for process: {}", e)))
-            })
-            .map_err(|e| format!("unknown error in exec: {}", e))
-            .map(|(status, stdout, stderr)| {
-                let mut r = format!("{}{}",
-                    String::from_utf8_lossy(&stderr),
-                    String::from_utf8_lossy(&stdout),
-                );
-                if !status.success() {
-                    if !r.ends_with('\n') {
-                        r.push_str("\n");
-                    }
-                    if let Some(code) = status.code() {
-                        r.push_str(&format!("exited with status {}\n", code));
-                    } else if let Some(code) = status.signal() {
-                        r.push_str(&format!("signalled with {} ({})\n", strsig(code), strsigabbrev(code)));
-                    } else {
-                        r.push_str("exited with unknown failure\n");
-                    }
-                }
-                r
-            }))
-    } else {
-        Either::B(Err("empty cmdline".to_owned()).into_future())
+        .map(|t| format!("{}{}", lang.timeout_prefix.as_deref().unwrap_or(""), t));
+    let timeout_arg_ref = timeout_arg.as_deref();
+    let path = match lang.cmdline.first() {
+        Some(path) => path,
+        None => return Either::B(Err("empty cmdline".to_owned()).into_future())
+    };
+    let mut cmd = Command::new(path);
+    cmd.args(lang.cmdline.iter()
+        .skip(1)
+        .filter_map(|a| if a == "{TIMEOUT}" {
+            timeout_arg_ref
+        } else {
+            Some(a.as_ref())
+        }))
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    if cancel.is_some() {
+        // its own process group, so that cancelling gets whatever it spawned too
+        cmd.process_group(0);
+    }
+    debug!("spawning {:?}", cmd);
+    let child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => return Either::B(Err(format!("failed to exec: {}", e)).into_future())
+    };
+    let kill = Kill {
+        pid: child.id() as libc::pid_t,
+        group: cancel.is_some(),
+        exited: Arc::new(Mutex::new(false))
+    };
+    let cancel = cancel.cloned();
+    if let Some(ref cancel) = cancel {
+        cancel.start();
+        let kill = kill.clone();
+        cancel.on_cancel(move || kill.kill());
     }
+    let (tx, rx) = oneshot::channel();
+    let exited = kill.exited.clone();
+    thread::spawn(move || {
+        let _ = tx.send(wait_output(child, code, &exited));
+    });
+    let running = Running(kill);
+    Either::A(rx
+        .map_err(|_| "waiting thread went away".to_owned())
+        .and_then(|r| r)
+        .map_err(|e| format!("unknown error in exec: {}", e))
+        .map(move |Output { status, stdout, stderr, cpu }| {
+            // evaluations that can be cancelled have a handle to charge CPU time to
+            if let Some(cancel) = cancel {
+                cancel.charge(cpu);
+            }
+            let mut r = format!("{}{}",
+                String::from_utf8_lossy(&stderr),
+                String::from_utf8_lossy(&stdout),
+            );
+            if !status.success() {
+                if !r.ends_with('\n') {
+                    r.push('\n');
+                }
+                if let Some(code) = status.code() {
+                    r.push_str(&format!("exited with status {}\n", code));
+                } else if let Some(code) = status.signal() {
+                    r.push_str(&format!("signalled with {} ({})\n", strsig(code), strsigabbrev(code)));
+                } else {
+                    r.push_str("exited with unknown failure\n");
+                }
+            }
+            r
+        })
+        .then(move |r| {
+            // a no-op once the command has been reaped
+            drop(running);
+            r
+        }))
 }
 
 macro_rules! persistent {
-    ($lang:expr, $connfut:expr, $timeout:expr, $buf:expr) => ({
+    ($lang:expr, $connfut:expr, $timeout:expr, $buf:expr, $on_elapsed:expr) => ({
         let buf = $buf;
+        let on_elapsed = $on_elapsed;
+        let timeout_cmdline = $lang.timeout_cmdline.clone();
         let fut = $connfut
             .map_err(|e| format!("error connecting: {}", e))
             .and_then(move |s| write_all(s, buf)
@@ -177,7 +292,7 @@ macro_rules! persistent {
             Either::A(fut.timeout(Duration::from_secs(timeout as u64)))
         } else {
             Either::B(fut.map_err(|e| timeout::Error::inner(e)))
-        }.then(|r| match r {
+        }.then(move |r| match r {
             Ok((s, lenb)) => Either::A(read_exact(s, {
                 // FIXME configurable max
                 let outlen = Cursor::new(lenb).get_u32_le().min(1024) as usize;
@@ -187,34 +302,94 @@ macro_rules! persistent {
             }).map_err(|e| format!("error reading result: {}", e))
                 .map(|(_, ref outb)| String::from_utf8_lossy(outb).into_owned())),
             Err(e) => Either::B(if e.is_elapsed() {
+                on_elapsed();
                 Err("time limit exceeded".to_owned()).into_future()
             } else {
                 Err(format!("error: {}", e)).into_future()
             })
         }).map_err(move |e| {
-            do_persistent_timeout(&$lang.timeout_cmdline);
+            do_persistent_timeout(&timeout_cmdline);
             e
         })
     });
 }
 
 pub fn unix<'a, T, U>(
-    lang: Arc<UnixSocketBackend>,
+    lang: &UnixSocketBackend,
     timeout: Option<usize>,
     context: Option<U>,
-    code: T) -> impl Future<Item = String, Error = String> + 'a
+    code: T,
+    cancel: &Cancel) -> impl Future<Item = String, Error = String> + 'a
         where
             T: AsRef<[u8]>,
             U: AsRef<[u8]> {
+    // timeout_cmdline overrides restarting a supervised daemon
+    let supervisor = match lang.timeout_cmdline {
+        Some(_) => None,
+        None => lang.supervisor.clone()
+    };
+    // the daemon cannot hear about a cancellation, so clear what the evaluation may have left in its
+    // context; restarting the daemon would take every other chat's state with it
+    let done = Arc::new(AtomicBool::new(false));
+    let finished = done.clone();
+    let backend = lang.clone();
+    let reset = context.as_ref().map(|c| String::from_utf8_lossy(c.as_ref()).into_owned());
+    cancel.on_cancel(move || if let (false, Some(context)) = (done.load(Ordering::SeqCst), reset) {
+        tokio::spawn(unix_reset(&backend, &context)
+            .map_err(move |e| warn!("failed to reset {} after cancelling: {}", context, e)));
+    });
+    let started = cancel.clone();
     persistent!(lang,
-        UnixStream::connect(&lang.socket_addr),
+        UnixStream::connect(&lang.socket_addr).map(move |s| {
+            started.start();
+            s
+        }),
         timeout,
-        make_persistent_input(timeout, context, code))
+        make_persistent_input(timeout, context, code),
+        move || if let Some(s) = supervisor {
+            s.restart();
+        }).then(move |r| {
+            finished.store(true, Ordering::SeqCst);
+            r
+        })
+}
+
+/// What to do when a pooled connection stalls: run `timeout_cmdline`, or else restart a supervised
+/// daemon.
+pub(crate) fn unix_recovery(lang: &UnixSocketBackend) -> impl FnOnce() + Send + 'static {
+    let timeout_cmdline = lang.timeout_cmdline.clone();
+    let supervisor = lang.supervisor.clone();
+    move || match (timeout_cmdline, supervisor) {
+        (Some(cmdline), _) => do_persistent_timeout(&Some(cmdline)),
+        (None, Some(s)) => s.restart(),
+        (None, None) => ()
+    }
+}
+
+/// Asks the daemon to discard `context`, recovering as from a timeout if it doesn't answer in time.
+pub fn unix_reset(lang: &UnixSocketBackend, context: &str) -> impl Future<Item = (), Error = String> {
+    let supervisor = match lang.timeout_cmdline {
+        Some(_) => None,
+        None => lang.supervisor.clone()
+    };
+    persistent!(lang,
+        UnixStream::connect(&lang.socket_addr),
+        Some(RESET_TIMEOUT),
+        make_reset_input(context),
+        move || if let Some(s) = supervisor {
+            s.restart();
+        }).map(|_| ())
+}
+
+pub fn unix_connect(lang: &UnixSocketBackend) -> impl Future<Item = (), Error = String> {
+    UnixStream::connect(&lang.socket_addr)
+        .map(|_| ())
+        .map_err(|e| format!("error connecting: {}", e))
 }
 
 fn do_persistent_timeout(cmdline: &Option<Vec<String>>) {
     if let Some(cmdline) = cmdline.as_ref() {
-        if let Some(path) = cmdline.iter().nth(0) {
+        if let Some(path) = cmdline.first() {
             debug!("timeout kill: launching {:?}", cmdline);
             tokio::spawn(Command::new(path)
                 .args(cmdline.iter().skip(1))
@@ -228,89 +403,33 @@ fn do_persistent_timeout(cmdline: &Option<Vec<String>>) {
     }
 }
 
-fn make_persistent_input<T, U>(timeout: Option<usize>, context: Option<T>, code: U) -> BytesMut
+pub(crate) fn make_persistent_input<T, U>(timeout: Option<usize>, context: Option<T>, code: U) -> BytesMut
+    where
+        T: AsRef<[u8]>,
+        U: AsRef<[u8]> {
+    make_request(timeout.unwrap_or(0usize) as u32 * 1000, context, code)
+}
+
+/// A request to discard `context`, which daemons answer with an empty response. Daemons that
+/// predate it evaluate nothing, with a very long timeout.
+pub(crate) fn make_reset_input(context: &str) -> BytesMut {
+    make_request(RESET, Some(context), "")
+}
+
+fn make_request<T, U>(timeout_ms: u32, context: Option<T>, code: U) -> BytesMut
     where
         T: AsRef<[u8]>,
         U: AsRef<[u8]> {
-    let timeout = timeout.unwrap_or(0usize) as u32;
     let contextb = context.as_ref().map(|x| x.as_ref()).unwrap_or(&super::EMPTY_U8);
     let codeb = code.as_ref();
     let contextblen = contextb.len() as u32;
     let codeblen = codeb.len() as u32;
 
     let mut buf = BytesMut::with_capacity(12usize + contextblen as usize + codeblen as usize);
-    buf.put_u32_le(timeout*1000);
+    buf.put_u32_le(timeout_ms);
     buf.put_u32_le(contextblen);
     buf.put_u32_le(codeblen);
     buf.put(&contextb[..contextblen as usize]);
     buf.put(&codeb[..codeblen as usize]);
     buf
 }
-
-mod helper {
-    // https://docs.rs/tokio-io/0.1.10/src/tokio_io/io/read_exact.rs.html
-
-    use std::io;
-    use std::mem;
-
-    use futures::{Poll, Future};
-
-    use tokio::io::AsyncRead;
-
-    #[derive(Debug)]
-    pub struct ReadUpTo<A, T> {
-        state: State<A, T>,
-    }
-
-    #[derive(Debug)]
-    enum State<A, T> {
-        Reading {
-            a: A,
-            buf: T,
-            pos: usize,
-        },
-        Empty,
-    }
-
-    pub fn read_up_to<A, T>(a: A, buf: T) -> ReadUpTo<A, T>
-        where A: AsyncRead,
-            T: AsMut<[u8]>,
-    {
-        ReadUpTo {
-            state: State::Reading {
-                a,
-                buf,
-                pos: 0,
-            },
-        }
-    }
-
-    impl<A, T> Future for ReadUpTo<A, T>
-        where A: AsyncRead,
-            T: AsMut<[u8]>,
-    {
-        type Item = (A, T, usize);
-        type Error = io::Error;
-
-        fn poll(&mut self) -> Poll<(A, T, usize), io::Error> {
-            match self.state {
-                State::Reading { ref mut a, ref mut buf, ref mut pos } => {
-                    let buf = buf.as_mut();
-                    while *pos < buf.len() {
-                        let n = try_ready!(a.poll_read(&mut buf[*pos..]));
-                        *pos += n;
-                        if n == 0 {
-                            break;
-                        }
-                    }
-                }
-                State::Empty => panic!("poll a ReadUpTo after it's done"),
-            }
-
-            match mem::replace(&mut self.state, State::Empty) {
-                State::Reading { a, buf, pos } => Ok((a, buf, pos).into()),
-                State::Empty => panic!(),
-            }
-        }
-    }
-}
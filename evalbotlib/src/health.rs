@@ -0,0 +1,151 @@
+//! Periodic health probes per language, and the circuit breaker that makes an unhealthy language
+//! fail fast until a probe succeeds again.
+
+use std::fmt;
+use std::sync::Mutex;
+
+use futures::sync::mpsc;
+
+/// The `health` table of a language. Languages without one are not probed and never fail fast.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct HealthCfg {
+    /// Seconds between probes.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Consecutive failed probes before the language is marked unhealthy.
+    #[serde(default = "default_threshold")]
+    pub threshold: u32,
+    /// Code to evaluate as the probe, instead of the backend's own check (which `exec` does not have).
+    pub probe: Option<String>,
+    /// Text the probe's output must contain.
+    pub expect: Option<String>,
+    /// Seconds a probe may take.
+    #[serde(default = "default_timeout")]
+    pub timeout: usize
+}
+
+fn default_interval() -> u64 {
+    30
+}
+
+fn default_threshold() -> u32 {
+    2
+}
+
+fn default_timeout() -> usize {
+    10
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HealthState {
+    /// Not probed yet.
+    Unknown,
+    Healthy,
+    Unhealthy
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct HealthStatus {
+    pub state: HealthState,
+    /// Consecutive failed probes.
+    pub failures: u32,
+    pub last_error: Option<String>
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.state {
+            HealthState::Unknown => "unknown",
+            HealthState::Healthy => "healthy",
+            HealthState::Unhealthy => "unhealthy"
+        })?;
+        if let Some(ref e) = self.last_error {
+            write!(f, " ({} failed probes, last: {})", self.failures, e)?;
+        }
+        Ok(())
+    }
+}
+
+/// A language's name and its new status, sent whenever it becomes unhealthy or healthy again.
+pub type HealthChange = (String, HealthStatus);
+
+#[derive(Debug)]
+pub(crate) struct Health {
+    pub cfg: HealthCfg,
+    status: Mutex<HealthStatus>,
+    watchers: Mutex<Vec<mpsc::UnboundedSender<HealthChange>>>
+}
+
+impl Health {
+    pub fn new(cfg: HealthCfg) -> Self {
+        Health {
+            cfg,
+            status: Mutex::new(HealthStatus {
+                state: HealthState::Unknown,
+                failures: 0,
+                last_error: None
+            }),
+            watchers: Mutex::new(Vec::new())
+        }
+    }
+
+    pub fn watch(&self, watcher: mpsc::UnboundedSender<HealthChange>) {
+        if let Ok(mut watchers) = self.watchers.lock() {
+            watchers.push(watcher);
+        }
+    }
+
+    fn changed(&self, name: &str, status: &HealthStatus) {
+        if let Ok(mut watchers) = self.watchers.lock() {
+            watchers.retain(|w| w.unbounded_send((name.to_owned(), status.clone())).is_ok());
+        }
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        match self.status.lock() {
+            Ok(status) => status.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    pub fn record(&self, name: &str, result: Result<(), String>) {
+        let mut status = match self.status.lock() {
+            Ok(status) => status,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        let mut changed = false;
+        match result {
+            Ok(()) => {
+                if status.state == HealthState::Unhealthy {
+                    info!("{} is healthy again", name);
+                    changed = true;
+                }
+                status.state = HealthState::Healthy;
+                status.failures = 0;
+                status.last_error = None;
+            }
+            Err(e) => {
+                status.failures += 1;
+                if status.failures >= self.cfg.threshold && status.state != HealthState::Unhealthy {
+                    warn!("{} is unhealthy: {}", name, e);
+                    status.state = HealthState::Unhealthy;
+                    changed = true;
+                }
+                status.last_error = Some(e);
+            }
+        }
+        if changed {
+            let status = status.clone();
+            self.changed(name, &status);
+        }
+    }
+
+    /// Why evaluations should fail fast right now, if they should.
+    pub fn open_reason(&self) -> Option<String> {
+        let status = self.status();
+        match status.state {
+            HealthState::Unhealthy => Some(status.last_error.unwrap_or_default()),
+            _ => None
+        }
+    }
+}
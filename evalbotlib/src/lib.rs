@@ -3,63 +3,187 @@ extern crate serde;
 extern crate toml;
 extern crate tokio;
 extern crate tokio_process;
-#[macro_use] extern crate futures;
+extern crate tokio_signal;
+extern crate futures;
 #[macro_use] extern crate log;
 extern crate bytes;
+extern crate libc;
+extern crate regex;
 
 use std::collections::HashMap;
-use futures::Future;
-use futures::future::Either;
+use futures::{Future, IntoFuture, Stream};
+use futures::future::{self, Either, Loop};
 use std::path::Path;
-use std::fmt::Display;
-use std::sync::Arc;
+use std::fmt::{self, Debug, Display};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::prelude::FutureExt;
+use tokio::timer::{Delay, Interval};
 
 pub mod util;
+pub mod chat;
+mod cancel;
 mod eval;
+mod health;
+mod middleware;
+pub mod notify;
+mod pool;
+pub mod quota;
+mod ratelimit;
+mod registry;
+mod scheduler;
+pub mod shutdown;
+pub mod smoke;
+mod stdio;
+mod supervisor;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+
+pub use cancel::Cancel;
+pub use health::{HealthCfg, HealthChange, HealthState, HealthStatus};
+pub use middleware::{Deny, EvalRequest, Flow, Middleware};
+pub use pool::PoolCfg;
+pub use ratelimit::{BucketCfg, RateClass, RateLimit};
+pub use registry::{BackendFactory, MiddlewareFactory, Registry};
+pub use scheduler::{Priority, SchedulerCfg};
+pub use stdio::StdioBackend;
+pub use supervisor::{DaemonState, Supervisor, SupervisorCfg, SupervisorStatus};
+use health::Health;
+use middleware::MiddlewareCfg;
+use pool::Pool;
+use scheduler::Scheduler;
+use smoke::{SmokeTest, SmokeTestCfg};
+
+pub type EvalFuture = Box<dyn Future<Item = String, Error = String> + Send>;
+pub type UnitFuture = Box<dyn Future<Item = (), Error = String> + Send>;
+type MiddlewareChain = Vec<(i32, Arc<dyn Middleware>)>;
+
+/// A way of evaluating code, selected per language with `backend = "name"`.
+///
+/// `timeout` is in seconds, with `Some(0)` meaning no limit; `context` names the persistent state
+/// to evaluate in, for backends that keep any.
+pub trait EvalBackend: Send + Sync + Debug {
+    fn eval(&self, code: String, timeout: Option<usize>, context: Option<String>) -> EvalFuture;
+
+    /// Like `eval`, but stops the evaluation once `cancel` is cancelled. By default the evaluation is
+    /// only dropped, which doesn't stop anything the backend started outside the bot.
+    fn eval_cancellable(&self, code: String, timeout: Option<usize>, context: Option<String>, cancel: &Cancel)
+        -> EvalFuture {
+        cancel.start();
+        self.eval(code, timeout, context)
+    }
+
+    /// Whether state is kept between evaluations in the same context.
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    /// Discards the state kept for `context`, or `None` if the backend cannot do that.
+    fn reset_context(&self, _context: &str) -> Option<UnitFuture> {
+        None
+    }
+
+    /// Resolves if the backend looks able to evaluate right now.
+    fn check_health(&self) -> UnitFuture {
+        Box::new(Ok(()).into_future())
+    }
+
+    /// Starts anything the backend runs in the background, such as a supervised daemon.
+    fn start(&self) {}
+
+    /// Stops what `start` or earlier evaluations left running, before the bot exits.
+    fn stop(&self) {}
+
+    /// What the background parts are up to, for backends that have any.
+    fn status(&self) -> Option<String> {
+        None
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize, Default, PartialEq, Debug)]
 struct EvalServiceCfg {
     timeout: usize,
+    #[serde(default)]
+    middleware: Vec<MiddlewareCfg>,
+    /// Run every language's smoke tests from `EvalService::start`.
+    #[serde(default)]
+    smoke_test_on_start: bool,
+    scheduler: Option<SchedulerCfg>,
     languages: HashMap<String, LanguageCfg>
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 struct LanguageCfg {
+    /// Other names the language answers to.
+    #[serde(default)]
+    aliases: Vec<String>,
+    display_name: Option<String>,
+    description: Option<String>,
+    /// Evaluated by the language once it has started; the first line of its output is its version.
+    version_code: Option<String>,
     code_before: Option<String>,
     code_after: Option<String>,
     timeout: Option<usize>,
+    backend: Option<String>,
+    #[serde(default)]
+    middleware: Vec<MiddlewareCfg>,
+    health: Option<HealthCfg>,
+    #[serde(default)]
+    smoke_tests: Vec<SmokeTestCfg>,
     #[serde(flatten)]
-    backend: BackendCfg
-}
-
-#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
-#[serde(untagged)]
-enum BackendCfg {
-    Exec(ExecBackend),
-    Network(NetworkBackend),
-    UnixSocket(UnixSocketBackend)
+    params: toml::value::Table
 }
 
 #[derive(Clone, Debug)]
 pub struct EvalService {
-    timeout: usize,
-    languages: HashMap<String, Arc<Language>>
+    middleware: MiddlewareChain,
+    smoke_test_on_start: bool,
+    scheduler: Option<Arc<Scheduler>>,
+    languages: HashMap<String, Arc<Language>>,
+    /// Alias to language name.
+    aliases: HashMap<String, String>
 }
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, Debug)]
 pub struct Language {
     name: String,
+    aliases: Vec<String>,
+    display_name: Option<String>,
+    description: Option<String>,
+    version_code: Option<String>,
+    /// What `version_code` printed, once it has.
+    version: Arc<OnceLock<String>>,
     code_before: Option<String>,
     code_after: Option<String>,
     timeout: Option<usize>,
-    backend: Backend
+    backend: Arc<dyn EvalBackend>,
+    middleware: MiddlewareChain,
+    health: Option<Arc<Health>>,
+    smoke_tests: Vec<SmokeTest>
 }
 
+/// What `/status` shows for a language.
 #[derive(Clone, PartialEq, Debug)]
-enum Backend {
-    Exec(Arc<ExecBackend>),
-    Network(Arc<NetworkBackend>),
-    UnixSocket(Arc<UnixSocketBackend>)
+pub struct LanguageStatus {
+    pub name: String,
+    /// `None` if the language is not probed.
+    pub health: Option<HealthStatus>,
+    /// From `EvalBackend::status`.
+    pub backend: Option<String>
+}
+
+impl Display for LanguageStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.name)?;
+        match self.health {
+            Some(ref health) => write!(f, "{}", health)?,
+            None => f.write_str("not monitored")?
+        }
+        if let Some(ref backend) = self.backend {
+            write!(f, "; {}", backend)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
@@ -74,82 +198,501 @@ pub struct NetworkBackend {
     timeout_cmdline: Option<Vec<String>>
 }
 
-#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct UnixSocketBackend {
     socket_addr: String,
-    timeout_cmdline: Option<Vec<String>>
+    timeout_cmdline: Option<Vec<String>>,
+    #[serde(default)]
+    daemon: Option<SupervisorCfg>,
+    /// Keep connections open and multiplex requests over them, which needs protocol version 2.
+    #[serde(default)]
+    pool: Option<PoolCfg>,
+    #[serde(skip)]
+    supervisor: Option<Arc<Supervisor>>,
+    #[serde(skip)]
+    connections: Option<Arc<Pool>>
+}
+
+impl UnixSocketBackend {
+    fn from_config(cfg: toml::Value) -> Result<Arc<dyn EvalBackend>, String> {
+        let mut backend = cfg.try_into::<UnixSocketBackend>()
+            .map_err(|e| format!("invalid backend configuration: {}", e))?;
+        backend.supervisor = backend.daemon.clone()
+            .map(|daemon| Arc::new(Supervisor::new(backend.socket_addr.clone(), daemon)));
+        backend.connections = backend.pool.as_ref()
+            .map(|pool| Arc::new(Pool::new(backend.socket_addr.clone(), pool)));
+        Ok(Arc::new(backend))
+    }
+}
+
+impl EvalBackend for ExecBackend {
+    fn eval(&self, code: String, timeout: Option<usize>, _: Option<String>) -> EvalFuture {
+        Box::new(eval::exec(self, timeout, code, None))
+    }
+
+    fn eval_cancellable(&self, code: String, timeout: Option<usize>, _: Option<String>, cancel: &Cancel)
+        -> EvalFuture {
+        Box::new(eval::exec(self, timeout, code, Some(cancel)))
+    }
+}
+
+impl EvalBackend for NetworkBackend {
+    fn eval(&self, _: String, _: Option<usize>, _: Option<String>) -> EvalFuture {
+        Box::new(Ok("Unimplemented".to_owned()).into_future())
+    }
+}
+
+impl EvalBackend for UnixSocketBackend {
+    fn eval(&self, code: String, timeout: Option<usize>, context: Option<String>) -> EvalFuture {
+        self.eval_cancellable(code, timeout, context, &Cancel::new())
+    }
+
+    /// Pooled connections ask the daemon to cancel the request; otherwise the daemon is treated as if
+    /// the request had timed out.
+    fn eval_cancellable(&self, code: String, timeout: Option<usize>, context: Option<String>, cancel: &Cancel)
+        -> EvalFuture {
+        match self.connections {
+            Some(ref pool) => pool.eval(timeout, context, code, cancel, eval::unix_recovery(self)),
+            None => Box::new(eval::unix(self, timeout, context, code, cancel))
+        }
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn reset_context(&self, context: &str) -> Option<UnitFuture> {
+        Some(match self.connections {
+            Some(ref pool) => pool.reset(context, eval::unix_recovery(self)),
+            None => Box::new(eval::unix_reset(self, context))
+        })
+    }
+
+    fn check_health(&self) -> UnitFuture {
+        Box::new(eval::unix_connect(self))
+    }
+
+    fn start(&self) {
+        if let Some(ref supervisor) = self.supervisor {
+            Supervisor::start(supervisor);
+        }
+    }
+
+    fn stop(&self) {
+        if let Some(ref supervisor) = self.supervisor {
+            supervisor.stop();
+        }
+    }
+
+    fn status(&self) -> Option<String> {
+        self.supervisor.as_ref().map(|s| s.status().to_string())
+    }
 }
 
 impl Language {
-    fn from(name: String, default_timeout: usize, cfg: LanguageCfg) -> Self {
-        Language {
+    fn from(name: String, default_timeout: usize, cfg: LanguageCfg, registry: &Registry) -> Result<Self, String> {
+        let backend = match cfg.backend {
+            Some(backend) => backend,
+            // languages configured before `backend` existed
+            None if cfg.params.contains_key("cmdline") => "exec".to_owned(),
+            None if cfg.params.contains_key("socket_addr") => "unix".to_owned(),
+            None if cfg.params.contains_key("network_addr") => "network".to_owned(),
+            None => return Err(format!("{}: no backend configured", name))
+        };
+        Ok(Language {
+            backend: registry.backend(&backend, toml::Value::Table(cfg.params))
+                .map_err(|e| format!("{}: {}", name, e))?,
+            version_code: cfg.version_code,
+            version: Arc::new(OnceLock::new()),
+            aliases: cfg.aliases,
+            display_name: cfg.display_name,
+            description: cfg.description,
+            middleware: build_middleware(cfg.middleware, registry).map_err(|e| format!("{}: {}", name, e))?,
+            health: cfg.health.map(|h| Arc::new(Health::new(h))),
+            smoke_tests: cfg.smoke_tests.into_iter().map(SmokeTest::from).collect::<Result<_, _>>()
+                .map_err(|e| format!("{}: {}", name, e))?,
             name,
             code_before: cfg.code_before,
             code_after: cfg.code_after,
-            timeout: cfg.timeout.or_else(|| Some(default_timeout)),
-            backend: match cfg.backend {
-                BackendCfg::Exec(x) => Backend::Exec(Arc::new(x)),
-                BackendCfg::Network(x) => Backend::Network(Arc::new(x)),
-                BackendCfg::UnixSocket(x) => Backend::UnixSocket(Arc::new(x)),
-            }
-        }
+            timeout: cfg.timeout.or(Some(default_timeout))
+        })
     }
 }
 
+// how long `version_code` may take, and how often it is tried while the backend comes up
+const VERSION_TIMEOUT: usize = 5;
+const VERSION_ATTEMPTS: usize = 5;
+const VERSION_RETRY: Duration = Duration::from_secs(10);
+
+fn build_middleware(cfgs: Vec<MiddlewareCfg>, registry: &Registry)
+    -> Result<MiddlewareChain, String> {
+    cfgs.into_iter()
+        .map(|cfg| {
+            let priority = cfg.priority;
+            registry.middleware(&cfg.name, toml::Value::Table(cfg.params)).map(|m| (priority, m))
+        })
+        .collect()
+}
+
 impl EvalService {
-    fn fixup(cfg: EvalServiceCfg) -> Self {
+    pub(crate) fn fixup(cfg: EvalServiceCfg, registry: &Registry) -> Result<Self, String> {
         debug!("Loaded config: {:#?}", cfg);
         let mut new = EvalService {
-            timeout: cfg.timeout,
-            languages: HashMap::new()
+            middleware: build_middleware(cfg.middleware, registry)?,
+            smoke_test_on_start: cfg.smoke_test_on_start,
+            scheduler: cfg.scheduler.map(|s| Arc::new(Scheduler::new(s))),
+            languages: HashMap::new(),
+            aliases: HashMap::new()
         };
         let timeout = cfg.timeout;
         for (name, lang) in cfg.languages.into_iter() {
-            new.languages.insert(name.clone(), Arc::new(Language::from(name, timeout,lang)));
+            new.languages.insert(name.clone(), Arc::new(Language::from(name, timeout, lang, registry)?));
+        }
+        for lang in new.languages.values() {
+            for alias in &lang.aliases {
+                if new.languages.contains_key(alias) {
+                    return Err(format!("{}: alias {} is also a language", lang.name, alias));
+                }
+                if let Some(other) = new.aliases.insert(alias.clone(), lang.name.clone()) {
+                    return Err(format!("{}: alias {} is also an alias of {}", lang.name, alias, other));
+                }
+            }
         }
-        new
+        Ok(new)
     }
 
     pub fn from_toml_file<P>(path: P) -> impl Future<Item = Self, Error = String>
-        where P: AsRef<Path> + Send + Display + 'static{
-        util::decode(path).map(EvalService::fixup)
+        where P: AsRef<Path> + Send + Display + 'static {
+        EvalService::from_toml_file_with(path, Arc::new(Registry::new()))
+    }
+
+    pub fn from_toml_file_with<P>(path: P, registry: Arc<Registry>) -> impl Future<Item = Self, Error = String>
+        where P: AsRef<Path> + Send + Display + 'static {
+        util::decode(path).and_then(move |cfg| EvalService::fixup(cfg, &registry))
     }
 
     pub fn from_toml(toml: &str) -> Result<Self, String> {
-        toml::from_str(toml).map(EvalService::fixup).map_err(|x| format!("could not parse TOML: {:?}", x))
+        EvalService::from_toml_with(toml, &Registry::new())
+    }
+
+    pub fn from_toml_with(toml: &str, registry: &Registry) -> Result<Self, String> {
+        toml::from_str(toml).map_err(|x| format!("could not parse TOML: {:?}", x))
+            .and_then(|cfg| EvalService::fixup(cfg, registry))
     }
 
     pub fn langs(&self) -> impl Iterator<Item = (&str, &Arc<Language>)> {
         self.languages.iter().map(|(n, l)| (n.as_str(), l))
     }
 
+    /// Every alias and the language it stands for.
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, &Arc<Language>)> {
+        self.aliases.iter().filter_map(move |(alias, name)| self.languages.get(name).map(|l| (alias.as_str(), l)))
+    }
+
+    /// The language called `lang`, or that has `lang` as an alias.
     pub fn get(&self, lang: &str) -> Option<&Arc<Language>> {
-        self.languages.get(lang)
+        self.languages.get(lang).or_else(|| self.aliases.get(lang).and_then(|name| self.languages.get(name)))
+    }
+
+    /// Starts the background parts of every backend and the health probes. Call once from within
+    /// the runtime.
+    pub fn start(&self) {
+        for lang in self.languages.values() {
+            lang.backend.start();
+            if lang.version_code.is_some() {
+                let lang = lang.clone();
+                tokio::spawn(future::loop_fn(1, move |attempt| {
+                    let name = lang.name.clone();
+                    lang.resolve_version().then(move |r| match r {
+                        Ok(version) => {
+                            info!("{}: version {}", name, version);
+                            Either::A(Ok(Loop::Break(())).into_future())
+                        }
+                        Err(e) if attempt < VERSION_ATTEMPTS => {
+                            debug!("{}: failed to get version, retrying: {}", name, e);
+                            Either::B(Delay::new(Instant::now() + VERSION_RETRY)
+                                .then(move |_| Ok(Loop::Continue(attempt + 1))))
+                        }
+                        Err(e) => {
+                            warn!("{}: failed to get version: {}", name, e);
+                            Either::A(Ok(Loop::Break(())).into_future())
+                        }
+                    })
+                }));
+            }
+            if let Some(ref health) = lang.health {
+                let lang = lang.clone();
+                tokio::spawn(Interval::new_interval(Duration::from_secs(health.cfg.interval.max(1)))
+                    .map_err(|e| error!("health probe timer failed: {}", e))
+                    .for_each(move |_| lang.probe().then(|_| Ok(()))));
+            }
+        }
+        if self.smoke_test_on_start {
+            tokio::spawn(self.smoke_test(&[]).map(|report| if report.ok() {
+                info!("smoke tests: {}", report);
+            } else {
+                warn!("smoke tests failed: {}", report);
+            }));
+        }
+    }
+
+    /// Stops the background parts of every backend.
+    pub fn stop(&self) {
+        for lang in self.languages.values() {
+            lang.backend.stop();
+        }
+    }
+
+    /// Languages becoming unhealthy or healthy again, for as long as the stream is kept.
+    pub fn health_changes(&self) -> impl Stream<Item = HealthChange, Error = ()> {
+        let (tx, rx) = futures::sync::mpsc::unbounded();
+        for health in self.languages.values().filter_map(|lang| lang.health.as_ref()) {
+            health.watch(tx.clone());
+        }
+        rx
+    }
+
+    /// The health of every language, sorted by name.
+    pub fn status(&self) -> Vec<LanguageStatus> {
+        let mut r = self.languages.values()
+            .map(|lang| LanguageStatus {
+                name: lang.name.clone(),
+                health: lang.health.as_ref().map(|h| h.status()),
+                backend: lang.backend.status()
+            })
+            .collect::<Vec<_>>();
+        r.sort_by(|a, b| a.name.cmp(&b.name));
+        r
+    }
+
+    /// Makes `alias` another name for `lang`, e.g. for names a frontend cannot use as commands.
+    pub fn alias(&mut self, alias: &str, lang: &str) -> Result<(), String> {
+        if self.get(alias).is_some() {
+            return Err(format!("{} is already a language or alias", alias));
+        }
+        let name = match self.get(lang) {
+            Some(l) => l.name.clone(),
+            None => return Err(format!("unknown language {}", lang))
+        };
+        if let Some(l) = self.languages.get_mut(&name) {
+            Arc::make_mut(l).aliases.push(alias.to_owned());
+        }
+        self.aliases.insert(alias.to_owned(), name);
+        Ok(())
+    }
+
+    /// Adds middleware for every language, or only for `lang`.
+    pub fn attach(&mut self, lang: Option<&str>, priority: i32, middleware: Arc<dyn Middleware>) -> Result<(), String> {
+        match lang {
+            Some(lang) => match self.languages.get_mut(lang) {
+                Some(l) => Arc::make_mut(l).middleware.push((priority, middleware)),
+                None => return Err(format!("unknown language {}", lang))
+            },
+            None => self.middleware.push((priority, middleware))
+        }
+        Ok(())
+    }
+
+    /// Evaluates `req` through the middleware chain. `Language::eval` bypasses the chain.
+    pub fn eval(&self, req: EvalRequest) -> EvalFuture {
+        self.eval_cancellable(req, &Cancel::new())
+    }
+
+    /// Like `eval`, failing with "cancelled" as soon as `cancel` is cancelled.
+    pub fn eval_cancellable(&self, mut req: EvalRequest, cancel: &Cancel) -> EvalFuture {
+        // middleware only ever sees the real name
+        if let Some(lang) = self.get(&req.lang) {
+            req.lang = lang.name.clone();
+            // refused ahead of the chain, so that no middleware takes this for a failed evaluation
+            if let Some(reason) = lang.health.as_ref().and_then(|h| h.open_reason()) {
+                return Box::new(Err(format!("{} is unavailable right now ({}); try again later", lang.name, reason))
+                    .into_future());
+            }
+        }
+        let mut chain = self.middleware.iter()
+            .chain(self.languages.get(&req.lang).into_iter().flat_map(|l| l.middleware.iter()))
+            .collect::<Vec<_>>();
+        chain.sort_by_key(|m| m.0);
+
+        let mut passed = Vec::with_capacity(chain.len());
+        let mut response = None;
+        for (_, m) in chain {
+            match m.before(&mut req) {
+                Flow::Continue => passed.push(m.clone()),
+                Flow::Respond(r) => {
+                    response = Some(r);
+                    break;
+                }
+                Flow::Ignore => {
+                    response = Some(Err(String::new()));
+                    break;
+                }
+            }
+        }
+
+        let outcome: EvalFuture = match (response, self.languages.get(&req.lang)) {
+            (Some(r), _) => Box::new(r.into_future()),
+            (None, Some(lang)) => match self.scheduler {
+                Some(ref scheduler) => {
+                    let (lang, cancel) = (lang.clone(), cancel.clone());
+                    let (code, timeout, context) = (req.code.clone(), req.timeout, req.context.clone());
+                    let admitted = Scheduler::acquire(scheduler, req.priority)
+                        .select2(cancel.cancelled())
+                        .then(|r| match r {
+                            Ok(Either::A((permit, _))) => Ok(permit),
+                            Err(Either::A((e, _))) => Err(e),
+                            Ok(Either::B(_)) | Err(Either::B(_)) => Err("cancelled".to_owned())
+                        });
+                    Box::new(admitted.and_then(move |permit| lang.eval_cancellable(code, timeout, context, &cancel)
+                        .then(move |r| {
+                            drop(permit);
+                            r
+                        })))
+                }
+                None => lang.eval_cancellable(&req.code, req.timeout, req.context.as_ref(), cancel)
+            },
+            (None, None) => Box::new(Err(format!("unknown language {}", req.lang)).into_future())
+        };
+        Box::new(outcome.then(move |r| passed.iter().rev().fold(r, |r, m| m.after(&req, r))))
     }
 }
 
 static EMPTY_U8: [u8; 0] = [];
 
 impl Language {
-    pub fn eval<T, U>(&self, code: T, timeout: Option<usize>, context: Option<U>) -> impl Future<Item = String, Error = String>
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    /// The configured `display_name`, or else the name.
+    pub fn display_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// What `version_code` printed; `None` until it has been evaluated, after `EvalService::start`.
+    pub fn version(&self) -> Option<&str> {
+        self.version.get().map(String::as_str)
+    }
+
+    /// Evaluates `version_code` and keeps the first non-empty line of its output as the version.
+    fn resolve_version(&self) -> EvalFuture {
+        let code = match self.version_code {
+            Some(ref code) => code,
+            None => return Box::new(Err(format!("{} has no version_code", self.name)).into_future())
+        };
+        let version = self.version.clone();
+        Box::new(self.eval(code, Some(VERSION_TIMEOUT), None::<&str>)
+            .timeout(Duration::from_secs(VERSION_TIMEOUT as u64))
+            .map_err(|e| e.into_inner().unwrap_or_else(|| "timed out".to_owned()))
+            .and_then(move |output| {
+                let line = output.lines().map(str::trim).find(|l| !l.is_empty())
+                    .ok_or_else(|| "no output".to_owned())?;
+                Ok(version.get_or_init(|| line.to_owned()).clone())
+            }))
+    }
+
+    /// Seconds evaluations may take unless asked otherwise, or `None` for no limit.
+    pub fn timeout(&self) -> Option<usize> {
+        self.timeout.filter(|&t| t > 0)
+    }
+
+    /// The display name, version and description, e.g. `Rust, rustc 1.70.0: expression mode`.
+    pub fn describe(&self) -> String {
+        let mut r = self.display_name().to_owned();
+        if let Some(version) = self.version() {
+            r.push_str(&format!(", {}", version));
+        }
+        if let Some(ref description) = self.description {
+            r.push_str(&format!(": {}", description));
+        }
+        r
+    }
+
+    /// One line for help output, e.g. `rs (rust) — Rust, rustc 1.70.0: expression mode`.
+    pub fn summary(&self) -> String {
+        let mut r = self.name.clone();
+        if !self.aliases.is_empty() {
+            r.push_str(&format!(" ({})", self.aliases.join(", ")));
+        }
+        r.push_str(" — ");
+        r.push_str(&self.describe());
+        r
+    }
+
+    pub fn backend(&self) -> &Arc<dyn EvalBackend> {
+        &self.backend
+    }
+
+    pub fn eval<T, U>(&self, code: T, timeout: Option<usize>, context: Option<U>) -> EvalFuture
+        where T: AsRef<str>, U: AsRef<str> {
+        self.eval_cancellable(code, timeout, context, &Cancel::new())
+    }
+
+    pub fn eval_cancellable<T, U>(&self, code: T, timeout: Option<usize>, context: Option<U>, cancel: &Cancel)
+        -> EvalFuture
         where T: AsRef<str>, U: AsRef<str> {
         debug!("evaluating {}: \"{}\"", self.name, code.as_ref());
-        match self.backend {
-            Backend::Exec(ref lang) =>
-                Either::A(Either::A(
-                    eval::exec(
-                        lang.clone(),
-                        timeout.or(self.timeout),
-                        self.wrap_code(code.as_ref())))),
-            Backend::UnixSocket(ref lang) =>
-                Either::A(Either::B(
-                    eval::unix(
-                        lang.clone(),
-                        timeout.or(self.timeout),
-                        context.map(|x| x.as_ref().to_owned()), // FIXME copy :(
-                        self.wrap_code(code.as_ref())))),
-            _ => Either::B(futures::finished("Unimplemented".to_owned()))
+        if cancel.is_cancelled() {
+            return Box::new(Err("cancelled".to_owned()).into_future());
+        }
+        let fut = self.backend.eval_cancellable(
+            self.wrap_code(code.as_ref()),
+            timeout.or(self.timeout),
+            context.map(|x| x.as_ref().to_owned()),
+            cancel);
+        // in case the backend doesn't stop by itself; and whatever it says once killed, it was cancelled
+        let cancel = cancel.clone();
+        Box::new(fut.select2(cancel.cancelled()).then(move |r| match r {
+            _ if cancel.is_cancelled() => Err("cancelled".to_owned()),
+            Ok(Either::A((output, _))) => Ok(output),
+            Err(Either::A((e, _))) => Err(e),
+            Ok(Either::B(_)) | Err(Either::B(_)) => Err("cancelled".to_owned())
+        }))
+    }
+
+    /// Discards the state the language keeps for `context`.
+    pub fn reset_context(&self, context: &str) -> UnitFuture {
+        debug!("resetting {} for {}", self.name, context);
+        if !self.backend.is_persistent() {
+            return Box::new(Err("this language has no persistent state".to_owned()).into_future());
         }
+        self.backend.reset_context(context)
+            .unwrap_or_else(|| Box::new(Err("this language cannot be reset".to_owned()).into_future()))
+    }
+
+    /// Runs one health probe and records its result. Fails if the language has no `health` table.
+    pub fn probe(&self) -> UnitFuture {
+        let health = match self.health {
+            Some(ref health) => health.clone(),
+            None => return Box::new(Err(format!("{} has no health probe", self.name)).into_future())
+        };
+        let check: UnitFuture = match health.cfg.probe {
+            Some(ref probe) => {
+                let expect = health.cfg.expect.clone();
+                Box::new(self.eval(probe, Some(health.cfg.timeout), None::<&str>)
+                    .and_then(move |output| match expect {
+                        Some(ref expect) if !output.contains(expect.as_str()) =>
+                            Err(format!("unexpected probe output: {:?}", output)),
+                        _ => Ok(())
+                    }))
+            }
+            None => Box::new(self.backend.check_health()
+                .timeout(Duration::from_secs(health.cfg.timeout as u64))
+                .map_err(|e| e.into_inner().unwrap_or_else(|| "probe timed out".to_owned())))
+        };
+        let name = self.name.clone();
+        Box::new(check.then(move |r| {
+            health.record(&name, r.clone());
+            r
+        }))
     }
 
     fn wrap_code(&self, raw: &str) -> String {
@@ -173,6 +716,8 @@ impl Language {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
     #[test]
     fn test_decode() {
         let toml = r#"
@@ -187,4 +732,198 @@ cmdline = ["rustc", "-O"]
 "#;
         println!("{:#?}", super::EvalService::from_toml(toml).unwrap());
     }
+
+    #[derive(Deserialize, Debug)]
+    struct UpperBackend {
+        suffix: String
+    }
+
+    impl EvalBackend for UpperBackend {
+        fn eval(&self, code: String, _: Option<usize>, _: Option<String>) -> EvalFuture {
+            Box::new(Ok(code.to_uppercase() + &self.suffix).into_future())
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let toml = r#"
+timeout = 20
+
+[languages.up]
+backend = "upper"
+suffix = "!"
+code_before = "<"
+"#;
+        assert!(EvalService::from_toml(toml).is_err());
+        let mut registry = Registry::new();
+        registry.register_config::<UpperBackend>("upper");
+        let service = EvalService::from_toml_with(toml, &registry).unwrap();
+        let r = service.get("up").unwrap().eval("abc", None, None::<&str>).wait();
+        assert_eq!(r, Ok("<ABC!".to_owned()));
+    }
+
+    #[test]
+    fn test_backend_inferred() {
+        let toml = r#"
+timeout = 20
+
+[languages.py]
+socket_addr = "/run/eval/pyeval.sock"
+
+[languages.bad]
+code_before = ""
+"#;
+        let err = EvalService::from_toml(toml).unwrap_err();
+        assert_eq!(err, "bad: no backend configured");
+        let service = EvalService::from_toml(&toml.replace("[languages.bad]", "[languages.bad]\nbackend = \"exec\"\ncmdline = []")).unwrap();
+        assert!(service.get("py").unwrap().backend().is_persistent());
+        assert!(!service.get("bad").unwrap().backend().is_persistent());
+    }
+
+    #[test]
+    fn test_aliases() {
+        let toml = r#"
+timeout = 20
+
+[languages.up]
+backend = "upper"
+suffix = ""
+aliases = ["upper", "u"]
+display_name = "Upper"
+description = "shouting"
+version_code = "\n upper 1.0\nmore"
+
+[languages.plain]
+backend = "upper"
+suffix = ""
+version_code = " "
+"#;
+        let mut registry = Registry::new();
+        registry.register_config::<UpperBackend>("upper");
+        let service = EvalService::from_toml_with(toml, &registry).unwrap();
+        let up = service.get("u").unwrap();
+        assert_eq!(up.name(), "up");
+        // asked of the backend itself, once it has started
+        assert_eq!(up.version(), None);
+        assert_eq!(test_support::block_on(up.resolve_version()), Ok("UPPER 1.0".to_owned()));
+        assert_eq!(up.version(), Some("UPPER 1.0"));
+        assert_eq!(up.summary(), "up (upper, u) — Upper, UPPER 1.0: shouting");
+        let plain = service.get("plain").unwrap();
+        assert_eq!(test_support::block_on(plain.resolve_version()), Err("no output".to_owned()));
+        assert_eq!(plain.summary(), "plain — plain");
+        let mut aliases = service.aliases().map(|(a, l)| (a, l.name())).collect::<Vec<_>>();
+        aliases.sort();
+        assert_eq!(aliases, vec![("u", "up"), ("upper", "up")]);
+        assert_eq!(service.eval(EvalRequest::new("upper", "x")).wait(), Ok("X".to_owned()));
+
+        let err = EvalService::from_toml_with(&toml.replace(r#"aliases = ["upper", "u"]"#, r#"aliases = ["plain"]"#),
+            &registry).unwrap_err();
+        assert_eq!(err, "up: alias plain is also a language");
+
+        let mut service = service;
+        assert_eq!(service.alias("shout", "u"), Ok(()));
+        assert_eq!(service.get("shout").unwrap().aliases(), ["upper", "u", "shout"]);
+        assert!(service.alias("plain", "up").is_err());
+        assert!(service.alias("p", "none").is_err());
+    }
+
+    #[derive(Debug)]
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn before(&self, req: &mut EvalRequest) -> Flow {
+            req.code.push_str(self.0);
+            Flow::Continue
+        }
+
+        fn after(&self, _req: &EvalRequest, outcome: Result<String, String>) -> Result<String, String> {
+            outcome.map(|r| format!("{}{}", r, self.0))
+        }
+    }
+
+    #[test]
+    fn test_middleware() {
+        let toml = r#"
+timeout = 20
+
+[[middleware]]
+name = "deny"
+patterns = ["fork"]
+
+[languages.up]
+backend = "upper"
+suffix = ""
+
+[[languages.up.middleware]]
+name = "deny"
+priority = -1
+patterns = ["exec"]
+message = "no exec"
+"#;
+        let mut registry = Registry::new();
+        registry.register_config::<UpperBackend>("upper");
+        let mut service = EvalService::from_toml_with(toml, &registry).unwrap();
+        service.attach(None, 0, Arc::new(Tag("a"))).unwrap();
+        service.attach(Some("up"), -2, Arc::new(Tag("b"))).unwrap();
+        assert!(service.attach(Some("none"), 0, Arc::new(Tag("c"))).is_err());
+
+        let r = service.eval(EvalRequest::new("up", "x")).wait();
+        assert_eq!(r, Ok("XBAab".to_owned()));
+        let r = service.eval(EvalRequest::new("up", "fork")).wait();
+        assert_eq!(r, Err("code containing \"fork\" is not allowed".to_owned()));
+        let r = service.eval(EvalRequest::new("up", "exec")).wait();
+        assert_eq!(r, Err("no exec".to_owned()));
+        let r = service.eval(EvalRequest::new("none", "x")).wait();
+        assert_eq!(r, Err("unknown language none".to_owned()));
+        // Language::eval bypasses the chain
+        let r = service.get("up").unwrap().eval("fork", None, None::<&str>).wait();
+        assert_eq!(r, Ok("FORK".to_owned()));
+    }
+
+    #[test]
+    fn test_health() {
+        use test_support::ScriptedBackend;
+
+        let toml = r#"
+timeout = 20
+
+[languages.s]
+backend = "scripted"
+
+[languages.s.health]
+probe = "ping"
+expect = "pong"
+threshold = 2
+
+[languages.plain]
+backend = "scripted"
+"#;
+        let backend = Arc::new(ScriptedBackend::new()
+            .then_ok("pong")
+            .then_err("error connecting")
+            .then_ok("garbage")
+            .then_ok("pong"));
+        let mut registry = Registry::empty();
+        let b = backend.clone();
+        registry.register("scripted", move |_| Ok(b.clone() as Arc<dyn EvalBackend>));
+        let service = EvalService::from_toml_with(toml, &registry).unwrap();
+        let lang = service.get("s").unwrap();
+        let status = |service: &EvalService| service.status().iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(status(&service), vec!["plain: not monitored", "s: unknown"]);
+
+        assert_eq!(lang.probe().wait(), Ok(()));
+        assert_eq!(lang.probe().wait(), Err("error connecting".to_owned()));
+        assert_eq!(status(&service)[1], "s: healthy (1 failed probes, last: error connecting)");
+        assert!(lang.probe().wait().is_err());
+        assert_eq!(status(&service)[1], "s: unhealthy (2 failed probes, last: unexpected probe output: \"garbage\")");
+        let calls = backend.calls().len();
+        assert_eq!(service.eval(EvalRequest::new("s", "1")).wait(),
+            Err("s is unavailable right now (unexpected probe output: \"garbage\"); try again later".to_owned()));
+        assert_eq!(backend.calls().len(), calls);
+        assert!(service.get("plain").unwrap().probe().wait().is_err());
+
+        assert_eq!(lang.probe().wait(), Ok(()));
+        assert_eq!(status(&service)[1], "s: healthy");
+        assert_eq!(service.eval(EvalRequest::new("s", "1")).wait(), Ok("1".to_owned()));
+    }
 }
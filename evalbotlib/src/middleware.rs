@@ -0,0 +1,83 @@
+use std::fmt::Debug;
+
+use crate::Priority;
+
+/// One evaluation as it passes through the middleware chain.
+#[derive(Clone, PartialEq, Debug)]
+pub struct EvalRequest {
+    pub lang: String,
+    pub code: String,
+    /// In seconds; `None` for the language's default and `Some(0)` for no limit.
+    pub timeout: Option<usize>,
+    pub context: Option<String>,
+    /// Decides the order evaluations start in when the service has a `scheduler`.
+    pub priority: Priority,
+    /// The sender and the chat, for frontends that have them.
+    pub user: Option<i64>,
+    pub chat: Option<i64>
+}
+
+impl EvalRequest {
+    pub fn new<T, U>(lang: T, code: U) -> Self
+        where T: Into<String>, U: Into<String> {
+        EvalRequest {
+            lang: lang.into(),
+            code: code.into(),
+            timeout: None,
+            context: None,
+            priority: Priority::default(),
+            user: None,
+            chat: None
+        }
+    }
+}
+
+pub enum Flow {
+    Continue,
+    /// Skip evaluation (and the rest of the chain) and answer with this instead.
+    Respond(Result<String, String>),
+    /// Skip evaluation and answer nothing, e.g. to stop repeating an error the sender has already
+    /// seen. `EvalService::eval` fails with an empty message, which frontends do not send.
+    Ignore
+}
+
+/// Runs around every evaluation made through `EvalService::eval`.
+///
+/// Middleware is ordered by priority, lowest first, with global middleware before a language's own
+/// at equal priority. `after` runs in reverse order, and only for middleware whose `before` ran
+/// and let the request through.
+pub trait Middleware: Send + Sync + Debug {
+    fn before(&self, _req: &mut EvalRequest) -> Flow {
+        Flow::Continue
+    }
+
+    fn after(&self, _req: &EvalRequest, outcome: Result<String, String>) -> Result<String, String> {
+        outcome
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub(crate) struct MiddlewareCfg {
+    pub name: String,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(flatten)]
+    pub params: toml::value::Table
+}
+
+/// Rejects code containing any of `patterns`, e.g. to keep `fork` out of an unsandboxed language.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Deny {
+    patterns: Vec<String>,
+    message: Option<String>
+}
+
+impl Middleware for Deny {
+    fn before(&self, req: &mut EvalRequest) -> Flow {
+        match self.patterns.iter().find(|p| req.code.contains(p.as_str())) {
+            Some(p) => Flow::Respond(Err(self.message.clone()
+                .unwrap_or_else(|| format!("code containing {:?} is not allowed", p)))),
+            None => Flow::Continue
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Messages to the owners about events they should know of, such as a language going down. Each
+//! kind of event can be turned off, and is sent at most once per interval about the same thing.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Future, IntoFuture, Stream};
+
+use crate::{EvalRequest, EvalService, HealthState, Middleware};
+use crate::chat::ReplyFuture;
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Startup,
+    Shutdown,
+    /// The bot was added to a group that is not on the whitelist.
+    GroupAdded,
+    /// A language became unhealthy, or healthy again.
+    Unhealthy,
+    /// A language failed `error_threshold` evaluations in a row.
+    EvalErrors,
+    /// Reloading configuration failed, and the old one is kept.
+    ReloadFailed,
+    /// An allowance or block given a duration lapsed.
+    WhitelistExpired
+}
+
+impl Event {
+    /// As in the configuration.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::Startup => "startup",
+            Event::Shutdown => "shutdown",
+            Event::GroupAdded => "group_added",
+            Event::Unhealthy => "unhealthy",
+            Event::EvalErrors => "eval_errors",
+            Event::ReloadFailed => "reload_failed",
+            Event::WhitelistExpired => "whitelist_expired"
+        }
+    }
+}
+
+static ALL_EVENTS: [Event; 7] = [
+    Event::Startup,
+    Event::Shutdown,
+    Event::GroupAdded,
+    Event::Unhealthy,
+    Event::EvalErrors,
+    Event::ReloadFailed,
+    Event::WhitelistExpired
+];
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct NotifyCfg {
+    /// Events to send; all of them by default.
+    #[serde(default = "all_events")]
+    pub events: HashSet<Event>,
+    /// Seconds between messages about the same event and subject.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// `interval` for particular events, by name.
+    #[serde(default)]
+    pub intervals: HashMap<String, u64>,
+    /// Failed evaluations of a language in a row before `eval_errors` is sent.
+    #[serde(default = "default_error_threshold")]
+    pub error_threshold: u32
+}
+
+fn all_events() -> HashSet<Event> {
+    ALL_EVENTS.iter().cloned().collect()
+}
+
+fn default_interval() -> u64 {
+    600
+}
+
+fn default_error_threshold() -> u32 {
+    5
+}
+
+impl Default for NotifyCfg {
+    fn default() -> Self {
+        NotifyCfg {
+            events: all_events(),
+            interval: default_interval(),
+            intervals: HashMap::new(),
+            error_threshold: default_error_threshold()
+        }
+    }
+}
+
+struct Sent {
+    at: Instant,
+    /// Messages held back since.
+    suppressed: u32
+}
+
+type SendFn = dyn Fn(String) -> ReplyFuture + Send + Sync;
+
+/// Sends notifications through the frontend's `send`, usually to a chat with the owners.
+///
+/// Attached to an `EvalService` as middleware, it also counts failed evaluations per language.
+/// Timeouts and cancellations are the users' doing and don't count; give it the highest priority so
+/// that it sees what the backend said rather than what other middleware made of it.
+pub struct Notifier {
+    cfg: NotifyCfg,
+    send: Box<SendFn>,
+    sent: Mutex<HashMap<(Event, String), Sent>>,
+    errors: Mutex<HashMap<String, u32>>
+}
+
+impl Notifier {
+    pub fn new<F>(cfg: NotifyCfg, send: F) -> Self
+        where F: Fn(String) -> ReplyFuture + Send + Sync + 'static {
+        Notifier {
+            cfg,
+            send: Box::new(send),
+            sent: Mutex::new(HashMap::new()),
+            errors: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Sends `text` about `subject` (e.g. a language, or nothing in particular) unless `event` is
+    /// turned off or was sent about `subject` within its interval. How many messages were held back
+    /// is mentioned in the next one that isn't.
+    pub fn notify(&self, event: Event, subject: &str, text: String) -> ReplyFuture {
+        if !self.cfg.events.contains(&event) {
+            return Box::new(Ok(()).into_future());
+        }
+        let interval = self.cfg.intervals.get(event.name()).cloned().unwrap_or(self.cfg.interval);
+        let now = Instant::now();
+        let suppressed = {
+            let mut sent = match self.sent.lock() {
+                Ok(sent) => sent,
+                Err(poisoned) => poisoned.into_inner()
+            };
+            let key = (event, subject.to_owned());
+            let suppressed = match sent.get_mut(&key) {
+                Some(prev) if now.duration_since(prev.at) < Duration::from_secs(interval) => {
+                    prev.suppressed += 1;
+                    return Box::new(Ok(()).into_future());
+                }
+                Some(prev) => prev.suppressed,
+                None => 0
+            };
+            sent.insert(key, Sent { at: now, suppressed: 0 });
+            suppressed
+        };
+        match suppressed {
+            0 => (self.send)(text),
+            n => (self.send)(format!("{} ({} similar held back)", text, n))
+        }
+    }
+
+    /// Tells of languages becoming unhealthy or healthy again, until the service's languages are gone.
+    pub fn watch_health(me: &Arc<Self>, service: &EvalService) -> impl Future<Item = (), Error = ()> {
+        let me = me.clone();
+        service.health_changes().for_each(move |(name, status)| {
+            let text = match status.state {
+                HealthState::Unhealthy => format!("{} is unhealthy: {}", name,
+                    status.last_error.as_deref().unwrap_or("unknown error")),
+                _ => format!("{} is healthy again", name)
+            };
+            me.notify(Event::Unhealthy, &name, text)
+        })
+    }
+}
+
+impl Middleware for Notifier {
+    fn after(&self, req: &EvalRequest, outcome: Result<String, String>) -> Result<String, String> {
+        let failures = {
+            let mut errors = match self.errors.lock() {
+                Ok(errors) => errors,
+                Err(poisoned) => poisoned.into_inner()
+            };
+            match outcome {
+                Ok(_) => {
+                    errors.remove(&req.lang);
+                    return outcome;
+                }
+                Err(ref e) if e.is_empty() || e == "cancelled" || e == "time limit exceeded" => return outcome,
+                Err(_) => {
+                    let n = errors.entry(req.lang.clone()).or_insert(0);
+                    *n += 1;
+                    *n
+                }
+            }
+        };
+        if failures >= self.cfg.error_threshold.max(1) {
+            let e = outcome.as_ref().err().map(String::as_str).unwrap_or_default();
+            tokio::spawn(self.notify(Event::EvalErrors, &req.lang,
+                format!("{} failed {} evaluations in a row, last with: {}", req.lang, failures, e)));
+        }
+        outcome
+    }
+}
+
+impl fmt::Debug for Notifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Notifier").field("cfg", &self.cfg).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use crate::{EvalBackend, HealthCfg, RateLimit};
+    use crate::health::Health;
+    use crate::test_support::{ScriptedBackend, block_on, service};
+
+    fn notifier(cfg: NotifyCfg) -> (Arc<Notifier>, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        (Arc::new(Notifier::new(cfg, move |text| {
+            tx.lock().unwrap().send(text).unwrap();
+            Box::new(Ok(()).into_future())
+        })), rx)
+    }
+
+    #[test]
+    fn test_cfg() {
+        let cfg: NotifyCfg = toml::from_str(r#"
+events = ["unhealthy", "eval_errors"]
+
+[intervals]
+eval_errors = 60
+"#).unwrap();
+        assert_eq!(cfg.events, vec![Event::Unhealthy, Event::EvalErrors].into_iter().collect());
+        assert_eq!(cfg.intervals.get("eval_errors"), Some(&60));
+        assert_eq!((cfg.interval, cfg.error_threshold), (600, 5));
+        assert_eq!(toml::from_str::<NotifyCfg>("").unwrap(), NotifyCfg::default());
+    }
+
+    #[test]
+    fn test_notify() {
+        let (n, rx) = notifier(NotifyCfg {
+            events: vec![Event::Unhealthy].into_iter().collect(),
+            intervals: vec![("unhealthy".to_owned(), 1)].into_iter().collect(),
+            ..NotifyCfg::default()
+        });
+        block_on(n.notify(Event::Startup, "", "up".to_owned())).unwrap();
+        block_on(n.notify(Event::Unhealthy, "a", "a down".to_owned())).unwrap();
+        block_on(n.notify(Event::Unhealthy, "a", "a down".to_owned())).unwrap();
+        block_on(n.notify(Event::Unhealthy, "b", "b down".to_owned())).unwrap();
+        ::std::thread::sleep(Duration::from_millis(1100));
+        block_on(n.notify(Event::Unhealthy, "a", "a up".to_owned())).unwrap();
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec!["a down", "b down", "a up (1 similar held back)"]);
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let backend = ScriptedBackend::default()
+            .then_err("error connecting: refused")
+            .then_err("time limit exceeded")
+            .then_err("error connecting: refused")
+            .then_ok("1")
+            .then_err("error connecting: refused")
+            .then_err("error connecting: refused");
+        let mut svc = service(vec![("p", Arc::new(backend) as Arc<dyn EvalBackend>)]);
+        let (n, rx) = notifier(NotifyCfg { error_threshold: 2, ..NotifyCfg::default() });
+        svc.attach(None, i32::MAX, n).unwrap();
+        for _ in 0..6 {
+            let _ = block_on(svc.eval(EvalRequest::new("p", "x")));
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(),
+            vec!["p failed 2 evaluations in a row, last with: error connecting: refused"]);
+    }
+
+    #[test]
+    fn test_refusals() {
+        let mut svc = service(vec![("p", Arc::new(ScriptedBackend::default()) as Arc<dyn EvalBackend>)]);
+        let limit: RateLimit = toml::from_str(r#"
+[classes.default]
+user = { burst = 1, refill = 60 }
+"#).unwrap();
+        let (n, rx) = notifier(NotifyCfg { error_threshold: 2, ..NotifyCfg::default() });
+        svc.attach(None, 0, Arc::new(limit)).unwrap();
+        svc.attach(None, i32::MAX, n).unwrap();
+        let req = || EvalRequest { user: Some(1), ..EvalRequest::new("p", "x") };
+        assert_eq!(block_on(svc.eval(req())), Ok("x".to_owned()));
+        for _ in 0..3 {
+            assert!(block_on(svc.eval(req())).is_err());
+        }
+
+        let health = Arc::new(Health::new(toml::from_str::<HealthCfg>("threshold = 1").unwrap()));
+        health.record("p", Err("down".to_owned()));
+        Arc::make_mut(svc.languages.get_mut("p").unwrap()).health = Some(health);
+        for _ in 0..3 {
+            assert_eq!(block_on(svc.eval(EvalRequest::new("p", "x"))),
+                Err("p is unavailable right now (down); try again later".to_owned()));
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), Vec::<String>::new());
+    }
+}
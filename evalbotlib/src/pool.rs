@@ -0,0 +1,345 @@
+//! Long-lived connections to a `unix` daemon speaking version 2 of the persistent protocol, which
+//! tags every request with an ID so several can be in flight on one connection and answered in any
+//! order.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Cursor};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::future::Shared;
+use futures::sync::{mpsc, oneshot};
+use tokio::codec::{Decoder, Encoder};
+use tokio::io::{read_exact, write_all};
+use tokio::net::unix::UnixStream;
+use tokio::prelude::*;
+use tokio::prelude::future::Either;
+
+use crate::{Cancel, EvalFuture, UnitFuture, eval};
+
+/// Sent instead of a timeout to open a version 2 session, followed by the version.
+pub const HELLO: u32 = 0xFFFF_FFFF;
+pub const VERSION: u32 = 2;
+pub const OP_EVAL: u32 = 0;
+pub const OP_CANCEL: u32 = 1;
+
+// how much longer than the request's own timeout to wait for a reply before giving up on the
+// connection; daemons are expected to enforce timeouts themselves
+const STALL_GRACE: Duration = Duration::from_secs(2);
+
+// responses longer than this are treated as a broken daemon
+const MAX_RESPONSE_LEN: usize = 1 << 24;
+
+/// The `pool` table of a `unix` language. Its daemon must speak protocol version 2.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct PoolCfg {
+    #[serde(default = "default_connections")]
+    pub connections: usize
+}
+
+fn default_connections() -> usize {
+    2
+}
+
+struct Codec;
+
+impl Encoder for Codec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: BytesMut, dst: &mut BytesMut) -> io::Result<()> {
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+impl Decoder for Codec {
+    type Item = (u32, String);
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<(u32, String)>> {
+        if src.len() < 8 {
+            return Ok(None);
+        }
+        let mut header = Cursor::new(&src[..8]);
+        let id = header.get_u32_le();
+        let len = header.get_u32_le() as usize;
+        if len > MAX_RESPONSE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "response too long"));
+        }
+        if src.len() < 8 + len {
+            src.reserve(8 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(8);
+        let mut body = src.split_to(len);
+        // FIXME configurable max
+        body.truncate(1024);
+        Ok(Some((id, String::from_utf8_lossy(&body).into_owned())))
+    }
+}
+
+struct ConnInner {
+    requests: mpsc::UnboundedSender<BytesMut>,
+    pending: Mutex<HashMap<u32, oneshot::Sender<Result<String, String>>>>,
+    dead: AtomicBool,
+    close: Mutex<Option<oneshot::Sender<()>>>
+}
+
+#[derive(Clone)]
+struct Conn(Arc<ConnInner>);
+
+impl Conn {
+    fn is_dead(&self) -> bool {
+        self.0.dead.load(Ordering::SeqCst)
+    }
+
+    /// Closes the connection, failing every request still waiting on it.
+    fn kill(&self) {
+        self.0.dead.store(true, Ordering::SeqCst);
+        if let Ok(mut pending) = self.0.pending.lock() {
+            pending.clear();
+        }
+        if let Some(close) = self.0.close.lock().ok().and_then(|mut c| c.take()) {
+            let _ = close.send(());
+        }
+    }
+
+    fn send(&self, id: u32, frame: BytesMut) -> Result<oneshot::Receiver<Result<String, String>>, String> {
+        let (tx, rx) = oneshot::channel();
+        self.0.pending.lock().map_err(|_| "internal error".to_owned())?.insert(id, tx);
+        if self.0.requests.unbounded_send(frame).is_err() {
+            self.kill();
+            return Err("connection lost".to_owned());
+        }
+        Ok(rx)
+    }
+
+    /// Fails request `id` and asks the daemon to stop evaluating it, if it is still waiting.
+    fn cancel(&self, id: u32) {
+        let tx = match self.0.pending.lock() {
+            Ok(mut pending) => pending.remove(&id),
+            Err(_) => None
+        };
+        if let Some(tx) = tx {
+            let _ = tx.send(Err("cancelled".to_owned()));
+            let mut frame = BytesMut::with_capacity(8);
+            frame.put_u32_le(id);
+            frame.put_u32_le(OP_CANCEL);
+            let _ = self.0.requests.unbounded_send(frame);
+        }
+    }
+}
+
+fn connect(socket_addr: &str) -> impl Future<Item = Conn, Error = String> {
+    let mut hello = BytesMut::with_capacity(8);
+    hello.put_u32_le(HELLO);
+    hello.put_u32_le(VERSION);
+    UnixStream::connect(socket_addr)
+        .and_then(move |s| write_all(s, hello))
+        .and_then(|(s, _)| read_exact(s, [0u8; 8]))
+        .map_err(|e| format!("error connecting: {}", e))
+        .and_then(|(s, reply)| {
+            let mut reply = Cursor::new(reply);
+            match (reply.get_u32_le(), reply.get_u32_le()) {
+                (HELLO, VERSION) => Ok(s),
+                _ => Err("daemon does not speak protocol version 2".to_owned())
+            }
+        })
+        .map(|s| {
+            let (sink, stream) = Codec.framed(s).split();
+            let (requests, rx) = mpsc::unbounded();
+            let (close, closed) = oneshot::channel();
+            let conn = Conn(Arc::new(ConnInner {
+                requests,
+                pending: Mutex::new(HashMap::new()),
+                dead: AtomicBool::new(false),
+                close: Mutex::new(Some(close))
+            }));
+
+            tokio::spawn(sink.sink_map_err(|e| debug!("error writing: {}", e))
+                .send_all(rx)
+                .map(|_| ()));
+            let (reader, closed_guard) = (conn.clone(), Closed(conn.clone()));
+            tokio::spawn(stream
+                .for_each(move |(id, output)| {
+                    match reader.0.pending.lock().ok().and_then(|mut p| p.remove(&id)) {
+                        Some(tx) => {
+                            let _ = tx.send(Ok(output));
+                        }
+                        // most likely cancelled
+                        None => debug!("reply to unknown request {}", id)
+                    }
+                    Ok(())
+                })
+                .map_err(|e| debug!("error reading: {}", e))
+                .select2(closed)
+                .then(move |_| {
+                    drop(closed_guard);
+                    Ok(())
+                }));
+            conn
+        })
+}
+
+/// Marks its connection dead once the reader is done with it, including when the reader is dropped
+/// unfinished along with the runtime it was spawned on.
+struct Closed(Conn);
+
+impl Drop for Closed {
+    fn drop(&mut self) {
+        (self.0).0.dead.store(true, Ordering::SeqCst);
+        if let Ok(mut pending) = (self.0).0.pending.lock() {
+            pending.clear();
+        }
+    }
+}
+
+type ConnectFuture = Box<dyn Future<Item = Conn, Error = String> + Send>;
+
+/// Requests get IDs from a counter shared by all of the pool's connections.
+pub(crate) struct Pool {
+    socket_addr: String,
+    slots: Vec<Mutex<Option<Shared<ConnectFuture>>>>,
+    next_slot: AtomicUsize,
+    next_id: AtomicUsize
+}
+
+impl Pool {
+    pub fn new(socket_addr: String, cfg: &PoolCfg) -> Self {
+        Pool {
+            socket_addr,
+            slots: (0..cfg.connections.max(1)).map(|_| Mutex::new(None)).collect(),
+            next_slot: AtomicUsize::new(0),
+            next_id: AtomicUsize::new(0)
+        }
+    }
+
+    /// The next connection round-robin, replacing it if it died or never came up.
+    fn conn(&self) -> Shared<ConnectFuture> {
+        let i = self.next_slot.fetch_add(1, Ordering::SeqCst) % self.slots.len();
+        let mut slot = match self.slots[i].lock() {
+            Ok(slot) => slot,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        let usable = match slot.as_ref().map(Shared::peek) {
+            Some(None) => true,
+            Some(Some(Ok(conn))) => !conn.is_dead(),
+            Some(Some(Err(_))) | None => false
+        };
+        if !usable {
+            let fut: ConnectFuture = Box::new(connect(&self.socket_addr));
+            *slot = Some(fut.shared());
+        }
+        slot.as_ref().map(Clone::clone).expect("slot was just filled")
+    }
+
+    pub fn eval<F>(&self, timeout: Option<usize>, context: Option<String>, code: String, cancel: &Cancel, on_stall: F)
+        -> EvalFuture
+        where F: FnOnce() + Send + 'static {
+        self.request(eval::make_persistent_input(timeout, context, code), timeout, cancel, on_stall)
+    }
+
+    /// Asks the daemon to discard `context`.
+    pub fn reset<F>(&self, context: &str, on_stall: F) -> UnitFuture
+        where F: FnOnce() + Send + 'static {
+        Box::new(self.request(eval::make_reset_input(context), Some(eval::RESET_TIMEOUT), &Cancel::new(), on_stall)
+            .map(|_| ()))
+    }
+
+    fn request<F>(&self, body: BytesMut, timeout: Option<usize>, cancel: &Cancel, on_stall: F) -> EvalFuture
+        where F: FnOnce() + Send + 'static {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) as u32;
+        let mut frame = BytesMut::with_capacity(8 + body.len());
+        frame.put_u32_le(id);
+        frame.put_u32_le(OP_EVAL);
+        frame.extend_from_slice(&body);
+        let wait = timeout.filter(|&t| t > 0).map(|t| Duration::from_secs(t as u64) + STALL_GRACE);
+
+        let cancel = cancel.clone();
+        Box::new(self.conn()
+            .map_err(|e| (*e).clone())
+            .and_then(move |conn| {
+                let conn = (*conn).clone();
+                cancel.start();
+                let reply = conn.send(id, frame).into_future()
+                    .and_then(|rx| rx.map_err(|_| "connection lost".to_owned()).and_then(|r| r));
+                let canceller = conn.clone();
+                cancel.on_cancel(move || canceller.cancel(id));
+                match wait {
+                    Some(wait) => Either::A(reply.timeout(wait).map_err(move |e| {
+                        if e.is_elapsed() {
+                            warn!("connection stalled; replacing it");
+                            conn.kill();
+                            on_stall();
+                            "time limit exceeded".to_owned()
+                        } else {
+                            e.into_inner().unwrap_or_else(|| "timer error".to_owned())
+                        }
+                    })),
+                    None => Either::B(reply)
+                }
+            }))
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("socket_addr", &self.socket_addr)
+            .field("connections", &self.slots.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use futures::Future;
+
+    use crate::{Cancel, EvalRequest, eval};
+    use crate::test_support::{FakeDaemon, block_on, service};
+
+    #[test]
+    fn test_pool() {
+        let daemon = FakeDaemon::start(|req| {
+            match req.code.as_str() {
+                "slow" => thread::sleep(::std::time::Duration::from_millis(500)),
+                "hang" => thread::sleep(::std::time::Duration::from_secs(5)),
+                _ => ()
+            }
+            format!("{}:{}", req.context, req.code)
+        }).unwrap();
+        let svc = service(vec![("p", daemon.pooled_backend(1))]);
+        let req = |code: &str, timeout| EvalRequest {
+            timeout: Some(timeout),
+            context: Some("c".to_owned()),
+            ..EvalRequest::new("p", code)
+        };
+
+        // both go over the one connection, and the fast one is answered first
+        let r = block_on(Future::join(svc.eval(req("slow", 5)), svc.eval(req("fast", 5))));
+        assert_eq!(r, Ok(("c:slow".to_owned(), "c:fast".to_owned())));
+        let codes = daemon.requests().into_iter().map(|r| r.code).collect::<Vec<_>>();
+        assert_eq!(codes, vec!["fast", "slow"]);
+
+        // a daemon that doesn't honour the timeout gets its connection replaced
+        assert_eq!(block_on(svc.eval(req("hang", 1))), Err("time limit exceeded".to_owned()));
+        assert_eq!(block_on(svc.eval(req("x", 1))), Ok("c:x".to_owned()));
+
+        // cancelling fails the request right away and leaves the connection usable
+        let cancel = Cancel::new();
+        let r = svc.eval_cancellable(req("hang", 0), &cancel);
+        cancel.cancel();
+        let r = block_on(r.then(|r| svc.eval(req("y", 1)).map(|y| (r, y))));
+        assert_eq!(r, Ok((Err("cancelled".to_owned()), "c:y".to_owned())));
+
+        assert_eq!(block_on(svc.get("p").unwrap().reset_context("c")), Ok(()));
+        let req = daemon.requests().pop().unwrap();
+        assert_eq!((req.timeout_ms, req.context, req.code), (eval::RESET, "c".to_owned(), String::new()));
+    }
+}
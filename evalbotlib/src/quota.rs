@@ -0,0 +1,453 @@
+//! Quotas on evaluations, wall time and CPU time per user and per chat, reset every day or week.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::Future;
+
+use crate::util;
+
+// usage is saved at most this often, and on shutdown
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Window {
+    /// Midnight to midnight, UTC.
+    #[default]
+    Daily,
+    /// Monday to Monday, UTC.
+    Weekly
+}
+
+impl Window {
+    /// The window `t` falls in, counted from the epoch.
+    fn period(self, t: SystemTime) -> u64 {
+        let days = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) / 86400;
+        match self {
+            Window::Daily => days,
+            // the epoch was a Thursday
+            Window::Weekly => (days + 3) / 7
+        }
+    }
+
+    fn period_secs(self) -> u64 {
+        match self {
+            Window::Daily => 86400,
+            Window::Weekly => 7 * 86400
+        }
+    }
+
+    /// When the window containing `t` ends.
+    fn end(self, t: SystemTime) -> SystemTime {
+        let offset = match self {
+            Window::Daily => 0,
+            Window::Weekly => 3 * 86400
+        };
+        UNIX_EPOCH + Duration::from_secs((self.period(t) + 1) * self.period_secs() - offset)
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Window::Daily => "today",
+            Window::Weekly => "this week"
+        })
+    }
+}
+
+/// How much a user or chat may use per window; missing limits are unlimited.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default, Debug)]
+pub struct Limits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub evals: Option<u64>,
+    /// Seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wall: Option<u64>,
+    /// Seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u64>
+}
+
+impl Limits {
+    /// Parses `evals=N wall=S cpu=S`, in any order and each optional.
+    pub fn parse(args: &str) -> Result<Limits, String> {
+        let mut r = Limits::default();
+        for arg in args.split_whitespace() {
+            let (key, value) = match arg.find('=') {
+                Some(i) => (&arg[..i], &arg[i + 1..]),
+                None => return Err(format!("expected key=value, got {:?}", arg))
+            };
+            let value = value.parse().map_err(|_| format!("invalid number {:?}", value))?;
+            match key {
+                "evals" => r.evals = Some(value),
+                "wall" => r.wall = Some(value),
+                "cpu" => r.cpu = Some(value),
+                _ => return Err(format!("unknown limit {:?}", key))
+            }
+        }
+        Ok(r)
+    }
+}
+
+impl Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let limits = [("evals", self.evals, ""), ("wall", self.wall, "s"), ("cpu", self.cpu, "s")];
+        let set = limits.iter()
+            .filter_map(|&(name, limit, unit)| limit.map(|l| format!("{}={}{}", name, l, unit)))
+            .collect::<Vec<_>>();
+        if set.is_empty() {
+            f.write_str("unlimited")
+        } else {
+            f.write_str(&set.join(" "))
+        }
+    }
+}
+
+/// A whitelist entry's own limits, which replace the defaults for the user or chat with that ID.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct QuotaOverride {
+    pub id: i64,
+    #[serde(flatten)]
+    pub limits: Limits
+}
+
+/// The `quota` table of a frontend's configuration.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct QuotaCfg {
+    #[serde(default)]
+    pub window: Window,
+    /// Defaults for every user.
+    #[serde(default)]
+    pub user: Limits,
+    /// Defaults for every chat.
+    #[serde(default)]
+    pub chat: Limits
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Account {
+    User(i64),
+    Chat(i64)
+}
+
+impl Account {
+    pub fn id(self) -> i64 {
+        match self {
+            Account::User(id) | Account::Chat(id) => id
+        }
+    }
+}
+
+impl Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Account::User(id) => write!(f, "user {}", id),
+            Account::Chat(id) => write!(f, "chat {}", id)
+        }
+    }
+}
+
+/// What an account used in the current window.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default, Debug)]
+pub struct Used {
+    pub evals: u64,
+    /// Milliseconds.
+    pub wall: u64,
+    /// Milliseconds.
+    pub cpu: u64
+}
+
+impl Used {
+    /// The first limit this reaches, if any.
+    fn exceeds(&self, limits: &Limits) -> Option<&'static str> {
+        if limits.evals.map(|l| self.evals >= l).unwrap_or(false) {
+            Some("evaluations")
+        } else if limits.wall.map(|l| self.wall >= l * 1000).unwrap_or(false) {
+            Some("wall time")
+        } else if limits.cpu.map(|l| self.cpu >= l * 1000).unwrap_or(false) {
+            Some("CPU time")
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for Used {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "evals={} wall={:.1}s cpu={:.1}s", self.evals, self.wall as f64 / 1000.0,
+            self.cpu as f64 / 1000.0)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+struct Entry {
+    id: i64,
+    #[serde(flatten)]
+    used: Used
+}
+
+/// Usage as saved to disk.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Default, Debug)]
+pub struct Usage {
+    period: u64,
+    #[serde(default)]
+    users: Vec<Entry>,
+    #[serde(default)]
+    chats: Vec<Entry>
+}
+
+impl Usage {
+    pub fn load<P>(path: P) -> impl Future<Item = Self, Error = ()>
+        where P: AsRef<Path> + Send + Display + 'static {
+        util::decode(path).or_else(|e| {
+            warn!("failed to read quota usage: {}; starting from zero", e);
+            Ok(Usage::default())
+        })
+    }
+}
+
+struct State {
+    period: u64,
+    used: HashMap<Account, Used>,
+    saved: Option<Instant>
+}
+
+/// Usage of every account, kept in memory and saved to `path` now and then.
+pub struct Quotas {
+    cfg: QuotaCfg,
+    path: String,
+    state: Mutex<State>
+}
+
+impl Quotas {
+    pub fn new(cfg: QuotaCfg, usage: Usage, path: String) -> Self {
+        let used = usage.users.into_iter().map(|e| (Account::User(e.id), e.used))
+            .chain(usage.chats.into_iter().map(|e| (Account::Chat(e.id), e.used)))
+            .collect();
+        Quotas {
+            cfg,
+            path,
+            state: Mutex::new(State { period: usage.period, used, saved: None })
+        }
+    }
+
+    // rolls over to the current window first
+    fn lock(&self) -> MutexGuard<'_, State> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        let period = self.cfg.window.period(SystemTime::now());
+        if state.period != period {
+            state.period = period;
+            state.used.clear();
+        }
+        state
+    }
+
+    /// The limits of `account`: its whitelist override if it has one, otherwise the defaults.
+    pub fn limits(&self, account: Account, overrides: &[QuotaOverride]) -> Limits {
+        match overrides.iter().find(|o| o.id == account.id()) {
+            Some(o) => o.limits,
+            None => match account {
+                Account::User(_) => self.cfg.user,
+                Account::Chat(_) => self.cfg.chat
+            }
+        }
+    }
+
+    pub fn used(&self, account: Account) -> Used {
+        self.lock().used.get(&account).cloned().unwrap_or_default()
+    }
+
+    /// Why `account` may not evaluate any more in this window, if it may not.
+    pub fn check(&self, account: Account, overrides: &[QuotaOverride]) -> Option<String> {
+        self.exceeded(account, self.used(account), overrides)
+    }
+
+    fn exceeded(&self, account: Account, used: Used, overrides: &[QuotaOverride]) -> Option<String> {
+        let limits = self.limits(account, overrides);
+        used.exceeds(&limits).map(|what| {
+            let left = self.cfg.window.end(SystemTime::now()).duration_since(SystemTime::now())
+                .unwrap_or_default();
+            format!("Quota exceeded: {} has used up its {} {}; resets in {}", account, what, self.cfg.window,
+                format_duration(left))
+        })
+    }
+
+    /// Charges one evaluation to each of `accounts` up front, unless one of them may not evaluate any
+    /// more. Evaluations running at the same time thus can't all get past the last one left. Once the
+    /// evaluation is over, `settle` its time, or `release` it if it never ran.
+    pub fn reserve(&self, accounts: &[Account], overrides: &[QuotaOverride]) -> Result<(), String> {
+        let mut state = self.lock();
+        for &account in accounts {
+            let used = state.used.get(&account).cloned().unwrap_or_default();
+            if let Some(exceeded) = self.exceeded(account, used, overrides) {
+                return Err(exceeded);
+            }
+        }
+        for &account in accounts {
+            state.used.entry(account).or_default().evals += 1;
+        }
+        Ok(())
+    }
+
+    /// Charges the time a reserved evaluation took to each of `accounts`, saving now and then.
+    pub fn settle(&self, accounts: &[Account], wall: Duration, cpu: Duration) {
+        let mut state = self.lock();
+        for &account in accounts {
+            let used = state.used.entry(account).or_default();
+            used.wall += millis(wall);
+            used.cpu += millis(cpu);
+        }
+        self.save_soon(state);
+    }
+
+    /// Gives back an evaluation reserved for `accounts` that never ran.
+    pub fn release(&self, accounts: &[Account]) {
+        let mut state = self.lock();
+        for &account in accounts {
+            if let Some(used) = state.used.get_mut(&account) {
+                // already zero if a new window started in the meantime
+                used.evals = used.evals.saturating_sub(1);
+            }
+        }
+        self.save_soon(state);
+    }
+
+    fn save_soon(&self, mut state: MutexGuard<'_, State>) {
+        match state.saved {
+            Some(t) if t.elapsed() < SAVE_INTERVAL => return,
+            _ => state.saved = Some(Instant::now())
+        }
+        drop(state);
+        tokio::spawn(self.save());
+    }
+
+    /// Forgets what `account` used in this window.
+    pub fn reset(&self, account: Account) -> Used {
+        let used = self.lock().used.remove(&account).unwrap_or_default();
+        tokio::spawn(self.save());
+        used
+    }
+
+    /// Adds what `from` used to `to`, e.g. when a chat's ID changes.
+    pub fn migrate(&self, from: Account, to: Account) {
+        {
+            let mut state = self.lock();
+            let used = match state.used.remove(&from) {
+                Some(used) => used,
+                None => return
+            };
+            let into = state.used.entry(to).or_default();
+            into.evals += used.evals;
+            into.wall += used.wall;
+            into.cpu += used.cpu;
+        }
+        tokio::spawn(self.save());
+    }
+
+    pub fn save(&self) -> impl Future<Item = (), Error = ()> {
+        let usage = {
+            let state = self.lock();
+            let mut usage = Usage { period: state.period, users: Vec::new(), chats: Vec::new() };
+            for (&account, &used) in &state.used {
+                match account {
+                    Account::User(id) => usage.users.push(Entry { id, used }),
+                    Account::Chat(id) => usage.chats.push(Entry { id, used })
+                }
+            }
+            usage
+        };
+        util::encode(&usage, self.path.clone()).map_err(|e| error!("error saving quota usage: {}", e))
+    }
+}
+
+impl fmt::Debug for Quotas {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Quotas").field("cfg", &self.cfg).field("path", &self.path).finish()
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+fn format_duration(d: Duration) -> String {
+    let mins = d.as_secs().div_ceil(60);
+    match (mins / 60, mins % 60) {
+        (0, m) => format!("{}m", m),
+        (h, 0) => format!("{}h", h),
+        (h, m) => format!("{}h{}m", h, m)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        // Wednesday 2021-06-02 12:00 UTC
+        let t = UNIX_EPOCH + Duration::from_secs(1_622_635_200);
+        assert_eq!(Window::Daily.end(t), UNIX_EPOCH + Duration::from_secs(1_622_678_400));
+        // Monday 2021-06-07 00:00 UTC
+        assert_eq!(Window::Weekly.end(t), UNIX_EPOCH + Duration::from_secs(1_623_024_000));
+        assert_eq!(Window::Weekly.period(Window::Weekly.end(t)), Window::Weekly.period(t) + 1);
+    }
+
+    #[test]
+    fn test_quota() {
+        let cfg = QuotaCfg {
+            window: Window::Daily,
+            user: Limits { evals: Some(2), ..Limits::default() },
+            chat: Limits { cpu: Some(1), ..Limits::default() }
+        };
+        let quotas = Quotas::new(cfg, Usage::default(), "quota.toml".to_owned());
+        let (user, chat) = (Account::User(7), Account::Chat(-5));
+        let overrides = vec![QuotaOverride { id: 8, limits: Limits::default() }];
+        // keep `debit` from saving
+        quotas.lock().saved = Some(Instant::now());
+
+        assert_eq!(quotas.reserve(&[user, chat], &overrides), Ok(()));
+        quotas.settle(&[user, chat], Duration::from_millis(300), Duration::from_millis(600));
+        assert_eq!(quotas.check(user, &overrides), None);
+        assert_eq!(quotas.check(chat, &overrides), None);
+        // the last evaluation left is taken as soon as it's reserved
+        assert_eq!(quotas.reserve(&[user, chat], &overrides), Ok(()));
+        assert!(quotas.reserve(&[user], &overrides).unwrap_err()
+            .starts_with("Quota exceeded: user 7 has used up its evaluations today"));
+        quotas.settle(&[user, chat], Duration::from_millis(300), Duration::from_millis(600));
+        assert!(quotas.check(chat, &overrides).unwrap().contains("CPU time"));
+        assert_eq!(quotas.used(user), Used { evals: 2, wall: 600, cpu: 1200 });
+        // one that never ran is given back
+        assert!(quotas.reserve(&[user, chat], &overrides).is_err());
+        quotas.release(&[user]);
+        assert_eq!(quotas.used(user), Used { evals: 1, wall: 600, cpu: 1200 });
+        assert_eq!(quotas.used(chat).evals, 2);
+
+        // user 8 has no limits at all
+        for _ in 0..3 {
+            assert_eq!(quotas.reserve(&[Account::User(8)], &overrides), Ok(()));
+        }
+        assert_eq!(quotas.check(Account::User(8), &overrides), None);
+
+        // a new day starts from zero
+        quotas.lock().period -= 1;
+        assert_eq!(quotas.check(user, &overrides), None);
+    }
+
+    #[test]
+    fn test_limits() {
+        assert_eq!(Limits::parse("cpu=30 evals=5"), Ok(Limits { evals: Some(5), wall: None, cpu: Some(30) }));
+        assert!(Limits::parse("cpu").is_err());
+        assert!(Limits::parse("mem=5").is_err());
+        assert_eq!(Limits::parse("").map(|l| l.to_string()), Ok("unlimited".to_owned()));
+        assert_eq!(Limits { evals: Some(5), wall: Some(60), cpu: None }.to_string(), "evals=5 wall=60s");
+    }
+}
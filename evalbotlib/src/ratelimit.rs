@@ -0,0 +1,192 @@
+//! The `ratelimit` middleware: token buckets per user and per chat, with separate limits for each
+//! class of languages.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::{EvalRequest, Flow, Middleware, Priority};
+
+// once there are this many buckets, those that have filled up again are dropped
+const PRUNE_AT: usize = 4096;
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub struct BucketCfg {
+    /// Requests allowed in a row.
+    pub burst: f64,
+    /// Seconds to earn back one request.
+    pub refill: f64
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct RateClass {
+    /// Languages in this class. The class named `default` also covers every language in no other.
+    #[serde(default)]
+    pub languages: Vec<String>,
+    pub user: Option<BucketCfg>,
+    pub chat: Option<BucketCfg>
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Key {
+    User(i64),
+    Chat(i64)
+}
+
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+    /// Whether the sender was told to slow down since they last got through.
+    warned: bool
+}
+
+impl Bucket {
+    fn refill(&mut self, cfg: BucketCfg, now: Instant) {
+        let d = now.duration_since(self.at);
+        let earned = (d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9) / cfg.refill.max(0.001);
+        self.tokens = (self.tokens + earned).min(cfg.burst);
+        self.at = now;
+    }
+
+    /// Seconds until a token is available.
+    fn wait(&self, cfg: BucketCfg) -> f64 {
+        (1.0 - self.tokens).max(0.0) * cfg.refill
+    }
+}
+
+/// Throttles requests from users and chats that exceed their class's limits, except the owners'.
+/// The first throttled request gets "slow down, try again in Ns"; more before the wait is over get
+/// no answer at all.
+#[derive(Deserialize)]
+pub struct RateLimit {
+    classes: HashMap<String, RateClass>,
+    #[serde(skip)]
+    buckets: Mutex<HashMap<(String, Key), Bucket>>
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RateLimit").field("classes", &self.classes).finish()
+    }
+}
+
+impl RateClass {
+    fn limit(&self, key: Key) -> Option<BucketCfg> {
+        match key {
+            Key::User(_) => self.user,
+            Key::Chat(_) => self.chat
+        }
+    }
+}
+
+impl RateLimit {
+    fn class(&self, lang: &str) -> Option<(&str, &RateClass)> {
+        self.classes.iter()
+            .find(|(_, c)| c.languages.iter().any(|l| l == lang))
+            .or_else(|| self.classes.iter().find(|(name, _)| *name == "default"))
+            .map(|(name, c)| (name.as_str(), c))
+    }
+}
+
+impl Middleware for RateLimit {
+    fn before(&self, req: &mut EvalRequest) -> Flow {
+        if req.priority == Priority::Owner {
+            return Flow::Continue;
+        }
+        let (class_name, class) = match self.class(&req.lang) {
+            Some(class) => class,
+            None => return Flow::Continue
+        };
+        let limits = req.user.map(Key::User).into_iter()
+            .chain(req.chat.map(Key::Chat))
+            .filter_map(|key| class.limit(key).map(|cfg| ((class_name.to_owned(), key), cfg)))
+            .collect::<Vec<_>>();
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        let now = Instant::now();
+        if buckets.len() >= PRUNE_AT {
+            let classes = &self.classes;
+            buckets.retain(|(class, key), b| match classes.get(class).and_then(|c| c.limit(*key)) {
+                Some(cfg) => {
+                    b.refill(cfg, now);
+                    b.tokens < cfg.burst
+                }
+                None => false
+            });
+        }
+        for &(ref key, cfg) in &limits {
+            buckets.entry(key.clone())
+                .or_insert(Bucket { tokens: cfg.burst, at: now, warned: false })
+                .refill(cfg, now);
+        }
+        let wait = limits.iter()
+            .map(|&(ref key, cfg)| buckets[key].wait(cfg))
+            .fold(0.0, f64::max);
+        if wait <= 0.0 {
+            for (key, _) in &limits {
+                let b = buckets.get_mut(key).expect("bucket was just added");
+                b.tokens -= 1.0;
+                b.warned = false;
+            }
+            return Flow::Continue;
+        }
+
+        let mut warned = false;
+        for (key, _) in &limits {
+            warned |= ::std::mem::replace(&mut buckets.get_mut(key).expect("bucket was just added").warned, true);
+        }
+        if warned {
+            Flow::Ignore
+        } else {
+            Flow::Respond(Err(format!("Slow down, try again in {}s", wait.ceil() as u64)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit: RateLimit = toml::from_str(r#"
+[classes.compiled]
+languages = ["rs"]
+user = { burst = 2, refill = 60 }
+
+[classes.default]
+chat = { burst = 3, refill = 60 }
+"#).unwrap();
+        let req = |lang: &str, user, priority| EvalRequest {
+            user: Some(user),
+            chat: Some(-5),
+            priority,
+            ..EvalRequest::new(lang, "")
+        };
+        let run = |lang, user, priority| match limit.before(&mut req(lang, user, priority)) {
+            Flow::Continue => Ok(()),
+            Flow::Respond(r) => r.map(|_| ()),
+            Flow::Ignore => Err(String::new())
+        };
+
+        assert_eq!(run("rs", 1, Priority::Normal), Ok(()));
+        assert_eq!(run("rs", 1, Priority::Normal), Ok(()));
+        // told once, then ignored
+        assert_eq!(run("rs", 1, Priority::Normal), Err("Slow down, try again in 60s".to_owned()));
+        assert_eq!(run("rs", 1, Priority::Normal), Err(String::new()));
+        // other users have their own bucket, and owners none at all
+        assert_eq!(run("rs", 2, Priority::Normal), Ok(()));
+        assert_eq!(run("rs", 1, Priority::Owner), Ok(()));
+
+        // everything else shares the chat's bucket
+        for _ in 0..3 {
+            assert_eq!(run("py", 3, Priority::Normal), Ok(()));
+        }
+        assert!(run("sh", 4, Priority::Trusted).is_err());
+        assert_eq!(run("rs", 3, Priority::Normal), Ok(()));
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+
+use crate::{Deny, EvalBackend, ExecBackend, Middleware, NetworkBackend, RateLimit, StdioBackend, UnixSocketBackend};
+
+pub type BackendFactory = dyn Fn(toml::Value) -> Result<Arc<dyn EvalBackend>, String> + Send + Sync;
+pub type MiddlewareFactory = dyn Fn(toml::Value) -> Result<Arc<dyn Middleware>, String> + Send + Sync;
+
+/// Maps the `backend` key of a language, and the `name` of each middleware entry, to the factory
+/// that builds it from the rest of its table. `Registry::new` comes with the `exec`, `unix`, `stdio`
+/// and `network` backends and the `deny` and `ratelimit` middleware.
+pub struct Registry {
+    backends: HashMap<String, Box<BackendFactory>>,
+    middleware: HashMap<String, Box<MiddlewareFactory>>
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        let mut r = Registry::empty();
+        r.register_config::<ExecBackend>("exec");
+        r.register("unix", UnixSocketBackend::from_config);
+        r.register_config::<NetworkBackend>("network");
+        r.register_config::<StdioBackend>("stdio");
+        r.register_middleware_config::<Deny>("deny");
+        r.register_middleware_config::<RateLimit>("ratelimit");
+        r
+    }
+
+    pub fn empty() -> Self {
+        Registry {
+            backends: HashMap::new(),
+            middleware: HashMap::new()
+        }
+    }
+
+    pub fn register<F>(&mut self, name: &str, factory: F) -> &mut Self
+        where F: Fn(toml::Value) -> Result<Arc<dyn EvalBackend>, String> + Send + Sync + 'static {
+        if self.backends.insert(name.to_owned(), Box::new(factory)).is_some() {
+            warn!("backend {} registered twice", name);
+        }
+        self
+    }
+
+    /// Registers a backend that is simply deserialised from its configuration.
+    pub fn register_config<T>(&mut self, name: &str) -> &mut Self
+        where T: EvalBackend + DeserializeOwned + 'static {
+        self.register(name, |cfg| cfg.try_into::<T>()
+            .map(|b| Arc::new(b) as Arc<dyn EvalBackend>)
+            .map_err(|e| format!("invalid backend configuration: {}", e)))
+    }
+
+    pub fn backend(&self, name: &str, cfg: toml::Value) -> Result<Arc<dyn EvalBackend>, String> {
+        match self.backends.get(name) {
+            Some(factory) => factory(cfg),
+            None => Err(format!("unknown backend {}", name))
+        }
+    }
+
+    pub fn register_middleware<F>(&mut self, name: &str, factory: F) -> &mut Self
+        where F: Fn(toml::Value) -> Result<Arc<dyn Middleware>, String> + Send + Sync + 'static {
+        if self.middleware.insert(name.to_owned(), Box::new(factory)).is_some() {
+            warn!("middleware {} registered twice", name);
+        }
+        self
+    }
+
+    pub fn register_middleware_config<T>(&mut self, name: &str) -> &mut Self
+        where T: Middleware + DeserializeOwned + 'static {
+        self.register_middleware(name, |cfg| cfg.try_into::<T>()
+            .map(|m| Arc::new(m) as Arc<dyn Middleware>)
+            .map_err(|e| format!("invalid middleware configuration: {}", e)))
+    }
+
+    pub fn middleware(&self, name: &str, cfg: toml::Value) -> Result<Arc<dyn Middleware>, String> {
+        match self.middleware.get(name) {
+            Some(factory) => factory(cfg),
+            None => Err(format!("unknown middleware {}", name))
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
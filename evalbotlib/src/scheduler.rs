@@ -0,0 +1,206 @@
+//! A limit on concurrent evaluations, with waiting requests admitted by priority class. Waiting
+//! raises a request's priority over time, so low priority work is never starved.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::{Future, IntoFuture};
+use futures::future::Either;
+use futures::sync::oneshot;
+
+/// Who an evaluation is for, lowest first.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Priority {
+    /// No user is known, e.g. messages posted as a channel.
+    Anonymous,
+    #[default]
+    Normal,
+    /// Explicitly whitelisted.
+    Trusted,
+    Owner
+}
+
+impl Priority {
+    fn rank(self) -> f64 {
+        match self {
+            Priority::Anonymous => 0.0,
+            Priority::Normal => 1.0,
+            Priority::Trusted => 2.0,
+            Priority::Owner => 3.0
+        }
+    }
+}
+
+/// The `scheduler` table of `evalbot.toml`. Without one, evaluations are never queued.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct SchedulerCfg {
+    /// Evaluations allowed to run at once; the rest wait.
+    pub max_concurrent: usize,
+    /// Seconds of waiting that count as much as one priority class, or 0 for strict priority.
+    #[serde(default = "default_aging")]
+    pub aging: f64
+}
+
+fn default_aging() -> f64 {
+    10.0
+}
+
+struct Waiter {
+    priority: Priority,
+    since: Instant,
+    tx: oneshot::Sender<Permit>
+}
+
+struct State {
+    running: usize,
+    // in arrival order
+    queue: Vec<Waiter>
+}
+
+pub(crate) struct Scheduler {
+    cfg: SchedulerCfg,
+    state: Mutex<State>
+}
+
+/// Held for as long as an evaluation runs; dropping it admits the next one.
+pub(crate) struct Permit(Option<Arc<Scheduler>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.0.take() {
+            Scheduler::release(&scheduler);
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(cfg: SchedulerCfg) -> Self {
+        Scheduler {
+            cfg,
+            state: Mutex::new(State { running: 0, queue: Vec::new() })
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Resolves once an evaluation with `priority` may run.
+    pub fn acquire(me: &Arc<Self>, priority: Priority) -> impl Future<Item = Permit, Error = String> {
+        let mut state = me.lock();
+        if state.running < me.cfg.max_concurrent.max(1) {
+            state.running += 1;
+            return Either::A(Ok(Permit(Some(me.clone()))).into_future());
+        }
+        let (tx, rx) = oneshot::channel();
+        state.queue.push(Waiter { priority, since: Instant::now(), tx });
+        Either::B(rx.map_err(|_| "scheduler stopped".to_owned()))
+    }
+
+    #[cfg(test)]
+    fn queued(&self) -> usize {
+        self.lock().queue.len()
+    }
+
+    fn release(me: &Arc<Self>) {
+        loop {
+            let next = {
+                let mut state = me.lock();
+                match me.pick(&state.queue, Instant::now()) {
+                    Some(i) => state.queue.remove(i),
+                    None => {
+                        state.running -= 1;
+                        return;
+                    }
+                }
+            };
+            // the slot passes straight to the waiter; if it gave up, on to the next
+            match next.tx.send(Permit(Some(me.clone()))) {
+                Ok(()) => return,
+                Err(mut permit) => {
+                    permit.0.take();
+                }
+            }
+        }
+    }
+
+    // the waiter with the highest priority after aging, the earliest among equals
+    fn pick(&self, queue: &[Waiter], now: Instant) -> Option<usize> {
+        let aging = if self.cfg.aging > 0.0 { self.cfg.aging } else { f64::INFINITY };
+        let score = |w: &Waiter| w.priority.rank() + secs(now.duration_since(w.since)) / aging;
+        let mut best: Option<(usize, f64)> = None;
+        for (i, w) in queue.iter().enumerate() {
+            let s = score(w);
+            if best.map(|(_, b)| s > b).unwrap_or(true) {
+                best = Some((i, s));
+            }
+        }
+        best.map(|(i, _)| i)
+    }
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("Scheduler")
+            .field("cfg", &self.cfg)
+            .field("running", &state.running)
+            .field("queued", &state.queue.len())
+            .finish()
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_priority() {
+        let sched = Arc::new(Scheduler::new(SchedulerCfg { max_concurrent: 1, aging: 0.0 }));
+        let first = Scheduler::acquire(&sched, Priority::Normal).wait().unwrap();
+        let waiters = [Priority::Normal, Priority::Anonymous, Priority::Owner, Priority::Trusted, Priority::Owner]
+            .iter()
+            .map(|&p| Scheduler::acquire(&sched, p))
+            .collect::<Vec<_>>();
+        assert_eq!(sched.queued(), 5);
+
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        for (i, w) in waiters.into_iter().enumerate() {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let permit = w.wait().unwrap();
+                tx.send(i).unwrap();
+                thread::sleep(Duration::from_millis(20));
+                drop(permit);
+            });
+        }
+        drop(first);
+        let order = rx.iter().take(5).collect::<Vec<_>>();
+        assert_eq!(order, vec![2, 4, 3, 0, 1]);
+    }
+
+    #[test]
+    fn test_aging() {
+        let sched = Arc::new(Scheduler::new(SchedulerCfg { max_concurrent: 1, aging: 0.05 }));
+        let first = Scheduler::acquire(&sched, Priority::Normal).wait().unwrap();
+        let old = Scheduler::acquire(&sched, Priority::Anonymous);
+        thread::sleep(Duration::from_millis(200));
+        let new = Scheduler::acquire(&sched, Priority::Owner);
+        drop(first);
+        // four classes' worth of waiting beats arriving as an owner
+        let old = old.wait().unwrap();
+        assert_eq!(sched.queued(), 1);
+        drop(old);
+        drop(new.wait().unwrap());
+        assert_eq!(sched.queued(), 0);
+    }
+}
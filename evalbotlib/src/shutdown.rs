@@ -0,0 +1,32 @@
+//! Graceful shutdown: frontends wait for `signal`, then drain their `Dispatcher` with
+//! `Dispatcher::shutdown` before exiting. SIGHUP is left for reloading, through `hangups`.
+
+use futures::{Future, Stream, future};
+use futures::future::Either;
+use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
+
+/// Resolves on the first SIGTERM or SIGINT. A signal that cannot be listened for is logged and then
+/// never arrives, rather than shutting down right away.
+pub fn signal() -> impl Future<Item = (), Error = ()> {
+    let wait = |sig| Signal::new(sig)
+        .flatten_stream()
+        .into_future()
+        .then(move |r| match r {
+            Ok(_) => Either::A(future::ok::<_, ()>(sig)),
+            Err((e, _)) => {
+                error!("failed to listen for signal {}: {}", sig, e);
+                Either::B(future::empty())
+            }
+        });
+    wait(SIGTERM).select(wait(SIGINT))
+        .map(|(sig, _)| info!("shutting down on signal {}", sig))
+        .map_err(|_| ())
+}
+
+/// Yields on every SIGHUP.
+pub fn hangups() -> impl Stream<Item = (), Error = ()> {
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .map(|_| info!("reloading on SIGHUP"))
+        .map_err(|e| error!("failed to listen for SIGHUP: {}", e))
+}
@@ -0,0 +1,221 @@
+//! Smoke tests declared per language in `evalbot.toml`, to check a toolchain still works after an
+//! upgrade.
+
+use std::fmt;
+
+use futures::{Future, future};
+use regex::Regex;
+use tokio::runtime::Runtime;
+
+use crate::{EvalService, util};
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub(crate) struct SmokeTestCfg {
+    pub code: String,
+    /// A regex the whole output is searched for, so anchor it with `^` and `$` as needed.
+    pub expect: String
+}
+
+#[derive(Clone, Debug)]
+pub struct SmokeTest {
+    pub code: String,
+    pub expect: Regex
+}
+
+impl SmokeTest {
+    pub(crate) fn from(cfg: SmokeTestCfg) -> Result<Self, String> {
+        Ok(SmokeTest {
+            expect: Regex::new(&cfg.expect).map_err(|e| format!("invalid smoke test regex: {}", e))?,
+            code: cfg.code
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SmokeResult {
+    pub lang: String,
+    /// Which of the language's smoke tests, counting from 1.
+    pub index: usize,
+    pub expect: String,
+    pub outcome: Result<String, String>,
+    pub passed: bool
+}
+
+impl fmt::Display for SmokeResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.passed {
+            return write!(f, "PASS {} #{}", self.lang, self.index);
+        }
+        writeln!(f, "FAIL {} #{}", self.lang, self.index)?;
+        match self.outcome {
+            Ok(ref output) => {
+                writeln!(f, "--- expected /{}/", self.expect)?;
+                writeln!(f, "+++ output")?;
+                f.write_str(diff(&self.expect, output).trim_end_matches('\n'))
+            }
+            Err(ref e) => write!(f, "error: {}", e)
+        }
+    }
+}
+
+/// Lines of `expect` that don't match the corresponding line of `output` (as a regex, or literally if
+/// the line is not one by itself), in unified diff style.
+fn diff(expect: &str, output: &str) -> String {
+    let expect = expect.trim_start_matches('^').trim_end_matches('$');
+    let (elines, olines) = (expect.lines().collect::<Vec<_>>(), output.lines().collect::<Vec<_>>());
+    let mut r = String::new();
+    for i in 0..elines.len().max(olines.len()) {
+        match (elines.get(i), olines.get(i)) {
+            (Some(e), Some(o)) if Regex::new(e).map(|re| re.is_match(o)).unwrap_or(e == o) => {
+                r.push_str(&format!(" {}\n", o));
+            }
+            (e, o) => {
+                if let Some(e) = e {
+                    r.push_str(&format!("-{}\n", e));
+                }
+                if let Some(o) = o {
+                    r.push_str(&format!("+{}\n", o));
+                }
+            }
+        }
+    }
+    r
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SmokeReport {
+    pub results: Vec<SmokeResult>
+}
+
+impl SmokeReport {
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|r| !r.passed).count()
+    }
+
+    pub fn ok(&self) -> bool {
+        self.failed() == 0
+    }
+}
+
+impl fmt::Display for SmokeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for r in &self.results {
+            writeln!(f, "{}", r)?;
+        }
+        write!(f, "{} passed, {} failed", self.results.len() - self.failed(), self.failed())
+    }
+}
+
+impl EvalService {
+    /// Runs the smoke tests of `langs`, or of every language if empty, all at once.
+    pub fn smoke_test(&self, langs: &[String]) -> impl Future<Item = SmokeReport, Error = ()> {
+        let mut names = self.languages.keys()
+            .filter(|name| langs.is_empty() || langs.contains(name))
+            .collect::<Vec<_>>();
+        names.sort();
+        let tests = names.into_iter()
+            .map(|name| &self.languages[name])
+            .flat_map(|lang| lang.smoke_tests.iter().enumerate().map(move |(i, test)| (lang, i, test)))
+            .map(|(lang, i, test)| {
+                let (name, expect) = (lang.name.clone(), test.expect.clone());
+                lang.eval(&test.code, None, None::<&str>).then(move |outcome| Ok(SmokeResult {
+                    passed: outcome.as_ref().map(|o| expect.is_match(o)).unwrap_or(false),
+                    lang: name,
+                    index: i + 1,
+                    expect: expect.as_str().to_owned(),
+                    outcome
+                }))
+            })
+            .collect::<Vec<_>>();
+        future::join_all(tests).map(|results| SmokeReport { results })
+    }
+}
+
+/// For a `smoke` subcommand: loads `config`, runs the smoke tests of `langs` (or all), prints the report
+/// and returns the exit code.
+pub fn run_cli(config: &str, langs: &[String]) -> i32 {
+    let mut rt = match Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("failed to start runtime: {}", e);
+            return 2;
+        }
+    };
+    let langs = langs.to_vec();
+    let report = rt.block_on(util::decode(config.to_owned())
+        .and_then(|cfg| EvalService::fixup(cfg, &crate::Registry::new()))
+        .map_err(|e| eprintln!("failed to load configuration: {}", e))
+        .and_then(move |mut service| {
+            // supervised daemons have to be up, and the tests below are the ones to report
+            service.smoke_test_on_start = false;
+            service.start();
+            service.smoke_test(&langs).then(move |report| {
+                service.stop();
+                report
+            })
+        }));
+    match report {
+        Ok(report) => {
+            println!("{}", report);
+            if report.ok() { 0 } else { 1 }
+        }
+        Err(()) => 2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::block_on;
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff("^a\nb+\nc$", "a\nbbb\nd\ne"), " a\n bbb\n-c\n+d\n+e\n");
+    }
+
+    #[test]
+    fn test_smoke() {
+        let service = EvalService::from_toml(r#"
+timeout = 20
+
+[languages.cat]
+cmdline = ["cat"]
+
+[[languages.cat.smoke_tests]]
+code = "hello"
+expect = "^hel+o$"
+
+[[languages.cat.smoke_tests]]
+code = "1\n2"
+expect = "^1\n3$"
+
+[languages.none]
+cmdline = ["/nonexistent"]
+
+[[languages.none.smoke_tests]]
+code = ""
+expect = ""
+"#).unwrap();
+        let report = block_on(service.smoke_test(&[])).unwrap();
+        assert_eq!(report.results.len(), 3);
+        assert_eq!(report.failed(), 2);
+        let text = report.to_string();
+        assert!(text.starts_with("PASS cat #1\nFAIL cat #2\n--- expected /^1\n3$/\n+++ output\n 1\n-3\n+2\nFAIL none #1\nerror: "),
+            "{}", text);
+        assert!(text.ends_with("1 passed, 2 failed"));
+
+        let report = block_on(service.smoke_test(&["none".to_owned()])).unwrap();
+        assert_eq!(report.results.len(), 1);
+
+        assert!(EvalService::from_toml(r#"
+timeout = 20
+
+[languages.cat]
+cmdline = ["cat"]
+
+[[languages.cat.smoke_tests]]
+code = ""
+expect = "("
+"#).is_err());
+    }
+}
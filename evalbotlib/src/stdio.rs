@@ -0,0 +1,235 @@
+//! A persistent evaluator spawned and supervised by the bot itself, speaking the persistent protocol
+//! over its stdin and stdout instead of a socket.
+
+use std::fmt;
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::sync::{mpsc, oneshot};
+use tokio::prelude::*;
+use tokio::prelude::future::Either;
+use tokio::io::{flush, read_exact, write_all};
+use tokio::timer::timeout;
+use tokio_process::{Child, ChildStdin, ChildStdout, CommandExt};
+use bytes::BytesMut;
+
+use crate::{Cancel, EvalBackend, EvalFuture, UnitFuture, eval};
+
+// responses longer than this are treated as a broken evaluator
+const MAX_RESPONSE_LEN: usize = 1 << 24;
+
+/// Requests are queued and sent one at a time. The daemon is started on the first request and
+/// restarted on the next one after it crashes, misbehaves or times out (and is killed).
+#[derive(Deserialize)]
+pub struct StdioBackend {
+    cmdline: Vec<String>,
+    #[serde(skip)]
+    jobs: Mutex<Option<mpsc::UnboundedSender<Job>>>
+}
+
+struct Job {
+    input: BytesMut,
+    timeout: Option<usize>,
+    cancel: Cancel,
+    reply: oneshot::Sender<Result<String, String>>
+}
+
+struct Daemon {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout
+}
+
+impl fmt::Debug for StdioBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StdioBackend").field("cmdline", &self.cmdline).finish()
+    }
+}
+
+impl EvalBackend for StdioBackend {
+    fn eval(&self, code: String, timeout: Option<usize>, context: Option<String>) -> EvalFuture {
+        self.eval_cancellable(code, timeout, context, &Cancel::new())
+    }
+
+    /// A cancelled request is skipped if it is still queued, and kills the daemon if it is running.
+    fn eval_cancellable(&self, code: String, timeout: Option<usize>, context: Option<String>, cancel: &Cancel)
+        -> EvalFuture {
+        self.submit(eval::make_persistent_input(timeout, context, code), timeout, cancel)
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    /// Queued like any request.
+    fn reset_context(&self, context: &str) -> Option<UnitFuture> {
+        Some(Box::new(self.submit(eval::make_reset_input(context), Some(eval::RESET_TIMEOUT), &Cancel::new())
+            .map(|_| ())))
+    }
+
+    fn stop(&self) {
+        // the worker finishes once it has no sender, killing the daemon
+        if let Ok(mut jobs) = self.jobs.lock() {
+            *jobs = None;
+        }
+    }
+}
+
+impl StdioBackend {
+    fn submit(&self, input: BytesMut, timeout: Option<usize>, cancel: &Cancel) -> EvalFuture {
+        let (tx, rx) = oneshot::channel();
+        let mut job = Job {
+            input,
+            timeout,
+            cancel: cancel.clone(),
+            reply: tx
+        };
+        let mut jobs = match self.jobs.lock() {
+            Ok(jobs) => jobs,
+            Err(_) => return Box::new(Err("internal error".to_owned()).into_future())
+        };
+        if let Some(ref sender) = *jobs {
+            match sender.unbounded_send(job) {
+                Ok(()) => return Box::new(StdioBackend::receive(rx)),
+                // the worker is gone with the runtime it was spawned on; start another
+                Err(e) => job = e.into_inner()
+            }
+        }
+        let (sender, receiver) = mpsc::unbounded();
+        tokio::spawn(work(self.cmdline.clone(), receiver));
+        let _ = sender.unbounded_send(job);
+        *jobs = Some(sender);
+        Box::new(StdioBackend::receive(rx))
+    }
+
+    fn receive(rx: oneshot::Receiver<Result<String, String>>) -> impl Future<Item = String, Error = String> {
+        rx.map_err(|_| "evaluator worker stopped".to_owned()).and_then(|r| r)
+    }
+}
+
+fn spawn(cmdline: &[String]) -> Result<Daemon, String> {
+    let path = cmdline.first().ok_or_else(|| "empty cmdline".to_owned())?;
+    let mut cmd = Command::new(path);
+    cmd.args(&cmdline[1..]).stdin(Stdio::piped()).stdout(Stdio::piped());
+    debug!("spawning persistent evaluator {:?}", cmd);
+    let mut child = cmd.spawn_async().map_err(|e| format!("failed to exec: {}", e))?;
+    match (child.stdin().take(), child.stdout().take()) {
+        (Some(stdin), Some(stdout)) => Ok(Daemon { child, stdin, stdout }),
+        _ => Err("stdio missing".to_owned())
+    }
+}
+
+fn work(cmdline: Vec<String>, jobs: mpsc::UnboundedReceiver<Job>) -> impl Future<Item = (), Error = ()> {
+    jobs.fold(None, move |daemon: Option<Daemon>, Job { input, timeout, cancel, reply }| {
+        if cancel.is_cancelled() {
+            let _ = reply.send(Err("cancelled".to_owned()));
+            return Either::B(Ok(daemon).into_future());
+        }
+        match daemon.map_or_else(|| spawn(&cmdline), Ok) {
+            Ok(daemon) => {
+                cancel.start();
+                Either::A(request(daemon, input, timeout).select2(cancel.cancelled()).then(move |r| {
+                    let r = match r {
+                        Ok(Either::A((r, _))) => Ok(r),
+                        Err(Either::A((e, _))) => Err(e),
+                        Ok(Either::B(_)) | Err(Either::B(_)) => Err("cancelled".to_owned())
+                    };
+                    let (daemon, result) = match r {
+                        Ok((daemon, output)) => (Some(daemon), Ok(output)),
+                        // dropping the child kills it; the next request starts a new one
+                        Err(e) => (None, Err(e))
+                    };
+                    let _ = reply.send(result);
+                    Ok(daemon)
+                }))
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e));
+                Either::B(Ok(None).into_future())
+            }
+        }
+    }).map(|_| ())
+}
+
+fn request(daemon: Daemon, input: BytesMut, timeout: Option<usize>)
+    -> impl Future<Item = (Daemon, String), Error = String> {
+    let Daemon { child, stdin, stdout } = daemon;
+    let fut = write_all(stdin, input)
+        .and_then(|(stdin, _)| flush(stdin))
+        .and_then(|stdin| read_exact(stdout, [0u8; 4]).map(|(stdout, lenb)| (stdin, stdout, lenb)))
+        .and_then(|(stdin, stdout, lenb)| {
+            let len = u32::from_le_bytes(lenb) as usize;
+            if len > MAX_RESPONSE_LEN {
+                Either::A(Err(io::Error::new(io::ErrorKind::InvalidData, "response too long")).into_future())
+            } else {
+                Either::B(read_exact(stdout, vec![0u8; len]).map(|(stdout, outb)| (stdin, stdout, outb)))
+            }
+        });
+    match timeout {
+        Some(t) if t > 0 => Either::A(fut.timeout(Duration::from_secs(t as u64))),
+        _ => Either::B(fut.map_err(timeout::Error::inner))
+    }.map_err(|e| if e.is_elapsed() {
+        "time limit exceeded".to_owned()
+    } else {
+        format!("error: {}", e)
+    }).map(move |(stdin, stdout, mut outb)| {
+        // FIXME configurable max
+        outb.truncate(1024);
+        (Daemon { child, stdin, stdout }, String::from_utf8_lossy(&outb).into_owned())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+    use crate::test_support::block_on;
+
+    // counts requests per context; exits on "exit", hangs on "sleep" and forgets the count on a reset
+    static COUNTER: &str = r#"
+$| = 1;
+my %n;
+while (read(STDIN, my $h, 12) == 12) {
+    my ($t, $cl, $l) = unpack("VVV", $h);
+    my ($c, $s) = ("", "");
+    read(STDIN, $c, $cl) if $cl;
+    read(STDIN, $s, $l) if $l;
+    if ($t == 0xFFFFFFFE) {
+        delete $n{$c};
+        print pack("V", 0);
+        next;
+    }
+    exit 1 if $s eq "exit";
+    sleep 10 if $s eq "sleep";
+    my $out = ++$n{$c} . ":" . $s;
+    print pack("V", length $out) . $out;
+}
+"#;
+
+    #[test]
+    fn test_stdio() {
+        let backend = StdioBackend {
+            cmdline: vec!["perl".to_owned(), "-e".to_owned(), COUNTER.to_owned()],
+            jobs: Mutex::new(None)
+        };
+        let reqs = vec![
+            ("a", None, "x"), ("b", None, "x"), ("c", None, "y"),
+            ("exit", None, "x"), ("d", None, "x"),
+            ("sleep", Some(1), "x"), ("e", None, "x")
+        ];
+        let results = block_on(stream::iter_ok::<_, ()>(reqs)
+            .and_then(|(code, timeout, ctx)| backend.eval(code.to_owned(), timeout, Some(ctx.to_owned())).then(Ok))
+            .collect()).unwrap();
+        assert_eq!(&results[..3], &[Ok("1:a".to_owned()), Ok("2:b".to_owned()), Ok("1:c".to_owned())]);
+        assert!(results[3].is_err());
+        assert_eq!(results[4], Ok("1:d".to_owned()));
+        assert_eq!(results[5], Err("time limit exceeded".to_owned()));
+        assert_eq!(results[6], Ok("1:e".to_owned()));
+
+        let r = block_on(future::lazy(|| backend.reset_context("x").unwrap())
+            .and_then(|_| backend.eval("f".to_owned(), None, Some("x".to_owned()))));
+        assert_eq!(r, Ok("1:f".to_owned()));
+    }
+}
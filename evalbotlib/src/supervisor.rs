@@ -0,0 +1,389 @@
+//! Owns the lifecycle of a persistent evaluator daemon behind a `unix` backend, in place of a
+//! systemd `.socket` and `.service` pair.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often the supervisor checks on its daemon
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The `daemon` table of a `unix` language.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct SupervisorCfg {
+    pub cmdline: Vec<String>,
+    /// Bind the socket ourselves and pass it as fd 3, like systemd socket activation. Otherwise the
+    /// daemon creates the socket.
+    #[serde(default)]
+    pub listen_fd: bool,
+    /// Seconds to wait for the socket to appear.
+    #[serde(default = "default_startup_timeout")]
+    pub startup_timeout: f64,
+    /// Seconds to wait before restarting a crashed daemon, doubling with each consecutive crash.
+    #[serde(default = "default_backoff")]
+    pub backoff: f64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: f64
+}
+
+fn default_startup_timeout() -> f64 {
+    10.0
+}
+
+fn default_backoff() -> f64 {
+    1.0
+}
+
+fn default_max_backoff() -> f64 {
+    60.0
+}
+
+fn secs(s: f64) -> Duration {
+    Duration::from_millis((s.max(0.0) * 1000.0) as u64)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DaemonState {
+    Stopped,
+    Starting,
+    Running,
+    BackingOff
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SupervisorStatus {
+    pub state: DaemonState,
+    pub pid: Option<u32>,
+    /// How many times the daemon was started.
+    pub starts: u32,
+    /// How many times it exited or failed to start without being asked to.
+    pub crashes: u32,
+    pub last_exit: Option<String>
+}
+
+impl fmt::Display for SupervisorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.state)?;
+        if let Some(pid) = self.pid {
+            write!(f, " (pid {})", pid)?;
+        }
+        write!(f, ", {} starts, {} crashes", self.starts, self.crashes)?;
+        if let Some(ref last_exit) = self.last_exit {
+            write!(f, ", last: {}", last_exit)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    status: SupervisorStatus,
+    child: Option<Child>,
+    listener: Option<UnixListener>,
+    started: bool,
+    stopping: bool,
+    restarting: bool
+}
+
+#[derive(Debug)]
+pub struct Supervisor {
+    socket_addr: String,
+    cfg: SupervisorCfg,
+    state: Mutex<State>
+}
+
+impl Supervisor {
+    pub fn new(socket_addr: String, cfg: SupervisorCfg) -> Self {
+        Supervisor {
+            socket_addr,
+            cfg,
+            state: Mutex::new(State {
+                status: SupervisorStatus {
+                    state: DaemonState::Stopped,
+                    pid: None,
+                    starts: 0,
+                    crashes: 0,
+                    last_exit: None
+                },
+                child: None,
+                listener: None,
+                started: false,
+                stopping: false,
+                restarting: false
+            })
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Starts supervising on a background thread. Does nothing if already started.
+    pub fn start(me: &Arc<Self>) {
+        {
+            let mut state = me.lock();
+            if state.started {
+                return;
+            }
+            state.started = true;
+            state.stopping = false;
+        }
+        let me = me.clone();
+        thread::spawn(move || me.run());
+    }
+
+    /// Stops the daemon for good.
+    pub fn stop(&self) {
+        let mut state = self.lock();
+        state.stopping = true;
+        if let Some(ref mut child) = state.child {
+            let _ = child.kill();
+        }
+    }
+
+    /// Kills the daemon so it is restarted right away, e.g. after it stopped responding.
+    pub fn restart(&self) {
+        let state = &mut *self.lock();
+        if let Some(ref mut child) = state.child {
+            info!("restarting daemon for {}", self.socket_addr);
+            state.restarting = true;
+            let _ = child.kill();
+        }
+    }
+
+    pub fn status(&self) -> SupervisorStatus {
+        self.lock().status.clone()
+    }
+
+    fn set_state(&self, s: DaemonState) {
+        self.lock().status.state = s;
+    }
+
+    fn run(&self) {
+        let mut backoff = secs(self.cfg.backoff);
+        loop {
+            self.set_state(DaemonState::Starting);
+            let crashed = match self.spawn() {
+                Ok(()) => {
+                    let started_at = Instant::now();
+                    let crashed = self.watch();
+                    // a daemon that ran for a while before crashing starts over with a short backoff
+                    if started_at.elapsed() > secs(self.cfg.max_backoff) {
+                        backoff = secs(self.cfg.backoff);
+                    }
+                    crashed
+                }
+                Err(e) => {
+                    error!("failed to start daemon for {}: {}", self.socket_addr, e);
+                    self.lock().status.last_exit = Some(format!("failed to start: {}", e));
+                    true
+                }
+            };
+
+            let mut state = self.lock();
+            state.child = None;
+            state.status.pid = None;
+            if state.stopping {
+                state.status.state = DaemonState::Stopped;
+                state.started = false;
+                return;
+            }
+            if !crashed {
+                continue;
+            }
+            state.status.crashes += 1;
+            state.status.state = DaemonState::BackingOff;
+            drop(state);
+
+            let until = Instant::now() + backoff;
+            while Instant::now() < until && !self.lock().stopping {
+                thread::sleep(POLL_INTERVAL);
+            }
+            backoff = (backoff * 2).min(secs(self.cfg.max_backoff).max(secs(self.cfg.backoff)));
+        }
+    }
+
+    fn spawn(&self) -> io::Result<()> {
+        let path = self.cfg.cmdline.first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty cmdline"))?;
+        let mut cmd = Command::new(path);
+        cmd.args(&self.cfg.cmdline[1..]);
+
+        let mut state = self.lock();
+        if self.cfg.listen_fd {
+            if state.listener.is_none() {
+                if Path::new(&self.socket_addr).exists() {
+                    fs::remove_file(&self.socket_addr)?;
+                }
+                state.listener = Some(UnixListener::bind(&self.socket_addr)?);
+            }
+            let fd = state.listener.as_ref().map(|l| l.as_raw_fd()).unwrap_or(-1);
+            unsafe {
+                cmd.pre_exec(move || pass_fd(fd));
+            }
+        } else if Path::new(&self.socket_addr).exists() {
+            // stale from a previous daemon; we wait for the new one to create it
+            fs::remove_file(&self.socket_addr)?;
+        }
+
+        debug!("spawning daemon {:?}", cmd);
+        let child = cmd.spawn()?;
+        state.status.pid = Some(child.id());
+        state.status.starts += 1;
+        state.child = Some(child);
+        Ok(())
+    }
+
+    /// Waits for the daemon to exit, returning whether that was unexpected.
+    fn watch(&self) -> bool {
+        let startup_deadline = Instant::now() + secs(self.cfg.startup_timeout);
+        loop {
+            let mut state = self.lock();
+            let exited = match state.child.as_mut().map(Child::try_wait) {
+                Some(Ok(None)) => None,
+                Some(Ok(Some(status))) => Some(describe_exit(status)),
+                Some(Err(e)) => Some(format!("failed to wait: {}", e)),
+                None => Some("gone".to_owned())
+            };
+            match exited {
+                Some(exit) => {
+                    info!("daemon for {} {}", self.socket_addr, exit);
+                    let expected = state.stopping || state.restarting;
+                    state.restarting = false;
+                    state.status.last_exit = Some(exit);
+                    return !expected;
+                }
+                None if state.status.state == DaemonState::Starting => {
+                    if Path::new(&self.socket_addr).exists() {
+                        state.status.state = DaemonState::Running;
+                    } else if Instant::now() > startup_deadline {
+                        warn!("daemon for {} did not create its socket in time", self.socket_addr);
+                        if let Some(ref mut child) = state.child {
+                            let _ = child.kill();
+                        }
+                    }
+                }
+                None => ()
+            }
+            drop(state);
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+fn describe_exit(status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exited with status {}", code),
+        (None, Some(sig)) => format!("killed by signal {}", sig),
+        _ => "exited".to_owned()
+    }
+}
+
+/// Runs in the child between fork and exec: move the listening socket to fd 3.
+fn pass_fd(fd: i32) -> io::Result<()> {
+    const LISTEN_FD: i32 = 3;
+    unsafe {
+        if fd == LISTEN_FD {
+            // dup2 would be a no-op and leave close-on-exec set
+            if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        } else if libc::dup2(fd, LISTEN_FD) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+    use crate::EvalRequest;
+    use crate::test_support::{block_on, service};
+    use crate::UnixSocketBackend;
+
+    // answers every request with "ok:<code>" on the socket passed as fd 3, exiting on "exit"
+    static DAEMON: &str = r#"
+use IO::Socket::UNIX;
+my $l = IO::Socket::UNIX->new_from_fd(3, "r") or die;
+while (my $c = $l->accept) {
+    read($c, my $h, 12);
+    my ($t, $cl, $l2) = unpack("VVV", $h);
+    my ($ctx, $s) = ("", "");
+    read($c, $ctx, $cl) if $cl;
+    read($c, $s, $l2) if $l2;
+    exit 3 if $s eq "exit";
+    my $out = "ok:" . $s;
+    print $c pack("V", length $out) . $out;
+    close $c;
+}
+"#;
+
+    fn wait_for(sup: &Supervisor, f: impl Fn(&SupervisorStatus) -> bool) -> SupervisorStatus {
+        for _ in 0..100 {
+            let status = sup.status();
+            if f(&status) {
+                return status;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        panic!("gave up waiting: {:?}", sup.status());
+    }
+
+    #[test]
+    fn test_supervise() {
+        let path = env::temp_dir().join(format!("evalbot-supervisor-{}.sock", process::id()));
+        let socket_addr = path.to_string_lossy().into_owned();
+        let sup = Arc::new(Supervisor::new(socket_addr.clone(), SupervisorCfg {
+            cmdline: vec!["perl".to_owned(), "-e".to_owned(), DAEMON.to_owned()],
+            listen_fd: true,
+            startup_timeout: 5.0,
+            backoff: 0.1,
+            max_backoff: 1.0
+        }));
+        Supervisor::start(&sup);
+        wait_for(&sup, |s| s.state == DaemonState::Running);
+
+        let backend = UnixSocketBackend {
+            socket_addr,
+            timeout_cmdline: None,
+            daemon: None,
+            pool: None,
+            supervisor: Some(sup.clone()),
+            connections: None
+        };
+        let svc = service(vec![("pl", Arc::new(backend) as Arc<dyn crate::EvalBackend>)]);
+        let r = block_on(svc.eval(EvalRequest { timeout: Some(5), ..EvalRequest::new("pl", "1") }));
+        assert_eq!(r, Ok("ok:1".to_owned()));
+
+        // a crash is counted and the daemon comes back after the backoff
+        assert!(block_on(svc.eval(EvalRequest { timeout: Some(5), ..EvalRequest::new("pl", "exit") })).is_err());
+        let status = wait_for(&sup, |s| s.starts == 2 && s.state == DaemonState::Running);
+        assert_eq!(status.crashes, 1);
+        assert_eq!(status.last_exit, Some("exited with status 3".to_owned()));
+        let r = block_on(svc.eval(EvalRequest { timeout: Some(5), ..EvalRequest::new("pl", "2") }));
+        assert_eq!(r, Ok("ok:2".to_owned()));
+
+        // requested restarts are not crashes
+        sup.restart();
+        let status = wait_for(&sup, |s| s.starts == 3 && s.state == DaemonState::Running);
+        assert_eq!(status.crashes, 1);
+
+        sup.stop();
+        wait_for(&sup, |s| s.state == DaemonState::Stopped);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,470 @@
+//! Fakes for testing frontends end to end without real evaluators. Enabled with the `test-support`
+//! feature.
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, IntoFuture};
+use futures::future;
+use futures::sync::oneshot;
+use tokio::runtime::current_thread::Runtime;
+
+use crate::{EvalBackend, EvalFuture, EvalService, Language, PoolCfg, UnixSocketBackend};
+use crate::pool::{self, Pool};
+use crate::chat::{Dispatcher, Frontend, MessageSource, Reply, ReplyFuture, ReplySink};
+
+/// One scripted response of a `ScriptedBackend`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Outcome {
+    pub result: Result<String, String>,
+    pub delay: Option<Duration>
+}
+
+/// What a fake backend was asked to evaluate.
+#[derive(Clone, PartialEq, Debug)]
+pub struct BackendCall {
+    pub code: String,
+    pub timeout: Option<usize>,
+    pub context: Option<String>
+}
+
+/// Answers with queued outcomes in order, then echoes the code once the script runs out.
+#[derive(Default, Debug)]
+pub struct ScriptedBackend {
+    script: Mutex<VecDeque<Outcome>>,
+    calls: Mutex<Vec<BackendCall>>,
+    persistent: bool
+}
+
+impl ScriptedBackend {
+    pub fn new() -> Self {
+        ScriptedBackend::default()
+    }
+
+    /// Reports itself as persistent, like the `unix` backend.
+    pub fn persistent(mut self) -> Self {
+        self.persistent = true;
+        self
+    }
+
+    pub fn then_ok<T: Into<String>>(self, output: T) -> Self {
+        self.then(Outcome { result: Ok(output.into()), delay: None })
+    }
+
+    pub fn then_err<T: Into<String>>(self, error: T) -> Self {
+        self.then(Outcome { result: Err(error.into()), delay: None })
+    }
+
+    pub fn then_delayed(self, delay: Duration, result: Result<String, String>) -> Self {
+        self.then(Outcome { result, delay: Some(delay) })
+    }
+
+    pub fn then(self, outcome: Outcome) -> Self {
+        self.script.lock().unwrap().push_back(outcome);
+        self
+    }
+
+    pub fn calls(&self) -> Vec<BackendCall> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl EvalBackend for ScriptedBackend {
+    fn eval(&self, code: String, timeout: Option<usize>, context: Option<String>) -> EvalFuture {
+        let outcome = self.script.lock().unwrap().pop_front()
+            .unwrap_or_else(|| Outcome { result: Ok(code.clone()), delay: None });
+        self.calls.lock().unwrap().push(BackendCall { code, timeout, context });
+        match outcome.delay {
+            Some(delay) => {
+                // a thread rather than a tokio timer, so tests can also just `wait()`
+                let (tx, rx) = oneshot::channel();
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    let _ = tx.send(outcome.result);
+                });
+                Box::new(rx.map_err(|_| "scripted outcome dropped".to_owned()).and_then(|r| r))
+            }
+            None => Box::new(outcome.result.into_future())
+        }
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+}
+
+/// A request as received by a `FakeDaemon`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DaemonRequest {
+    pub timeout_ms: u32,
+    pub context: String,
+    pub code: String
+}
+
+type DaemonHandler = dyn Fn(&DaemonRequest) -> String + Send + Sync;
+
+/// A persistent evaluator speaking the socket protocol (either version) on a temporary unix socket,
+/// removed on drop.
+pub struct FakeDaemon {
+    path: PathBuf,
+    requests: Arc<Mutex<Vec<DaemonRequest>>>,
+    stop: Arc<AtomicBool>
+}
+
+static NEXT_SOCKET: AtomicUsize = AtomicUsize::new(0);
+
+impl FakeDaemon {
+    pub fn start<H>(handler: H) -> io::Result<Self>
+        where H: Fn(&DaemonRequest) -> String + Send + Sync + 'static {
+        let path = env::temp_dir().join(format!("evalbot-test-{}-{}.sock",
+            process::id(), NEXT_SOCKET.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let daemon = FakeDaemon {
+            path,
+            requests: Arc::new(Mutex::new(Vec::new())),
+            stop: Arc::new(AtomicBool::new(false))
+        };
+
+        let handler: Arc<DaemonHandler> = Arc::new(handler);
+        let requests = daemon.requests.clone();
+        let stop = daemon.stop.clone();
+        thread::spawn(move || for stream in listener.incoming() {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Ok(stream) = stream {
+                let handler = handler.clone();
+                let requests = requests.clone();
+                thread::spawn(move || serve(stream, &handler, &requests));
+            }
+        });
+        Ok(daemon)
+    }
+
+    /// Answers every request with its code.
+    pub fn echo() -> io::Result<Self> {
+        FakeDaemon::start(|req| req.code.clone())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A `unix` backend pointed at this daemon.
+    pub fn backend(&self) -> Arc<dyn EvalBackend> {
+        Arc::new(UnixSocketBackend {
+            socket_addr: self.path.to_string_lossy().into_owned(),
+            timeout_cmdline: None,
+            daemon: None,
+            pool: None,
+            supervisor: None,
+            connections: None
+        })
+    }
+
+    /// A `unix` backend pointed at this daemon, keeping `connections` connections open.
+    pub fn pooled_backend(&self, connections: usize) -> Arc<dyn EvalBackend> {
+        let socket_addr = self.path.to_string_lossy().into_owned();
+        let cfg = PoolCfg { connections };
+        Arc::new(UnixSocketBackend {
+            connections: Some(Arc::new(Pool::new(socket_addr.clone(), &cfg))),
+            socket_addr,
+            timeout_cmdline: None,
+            daemon: None,
+            pool: Some(cfg),
+            supervisor: None
+        })
+    }
+
+    pub fn requests(&self) -> Vec<DaemonRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl fmt::Debug for FakeDaemon {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FakeDaemon").field("path", &self.path).finish()
+    }
+}
+
+impl Drop for FakeDaemon {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wake the accept loop so it sees the flag
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn read_u32(stream: &mut UnixStream) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    stream.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_string(stream: &mut UnixStream, len: u32) -> io::Result<String> {
+    let mut b = vec![0u8; len as usize];
+    stream.read_exact(&mut b)?;
+    Ok(String::from_utf8_lossy(&b).into_owned())
+}
+
+fn read_request(stream: &mut UnixStream, timeout_ms: u32) -> io::Result<DaemonRequest> {
+    let ctxlen = read_u32(stream)?;
+    let codelen = read_u32(stream)?;
+    Ok(DaemonRequest {
+        timeout_ms,
+        context: read_string(stream, ctxlen)?,
+        code: read_string(stream, codelen)?
+    })
+}
+
+fn serve(mut stream: UnixStream, handler: &Arc<DaemonHandler>, requests: &Arc<Mutex<Vec<DaemonRequest>>>) {
+    let _ = match read_u32(&mut stream) {
+        Ok(pool::HELLO) => serve_v2(stream, handler, requests),
+        Ok(timeout_ms) => serve_v1(stream, timeout_ms, &**handler, requests),
+        Err(_) => Ok(())
+    };
+}
+
+fn serve_v1(mut stream: UnixStream, mut timeout_ms: u32, handler: &DaemonHandler, requests: &Mutex<Vec<DaemonRequest>>)
+    -> io::Result<()> {
+    loop {
+        let req = read_request(&mut stream, timeout_ms)?;
+        let resp = handler(&req);
+        requests.lock().unwrap().push(req);
+        stream.write_all(&(resp.len() as u32).to_le_bytes())?;
+        stream.write_all(resp.as_bytes())?;
+        stream.flush()?;
+        timeout_ms = read_u32(&mut stream)?;
+    }
+}
+
+// answers each request from its own thread, so slow requests don't hold up the rest
+fn serve_v2(mut stream: UnixStream, handler: &Arc<DaemonHandler>, requests: &Arc<Mutex<Vec<DaemonRequest>>>)
+    -> io::Result<()> {
+    read_u32(&mut stream)?;
+    stream.write_all(&pool::HELLO.to_le_bytes())?;
+    stream.write_all(&pool::VERSION.to_le_bytes())?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    loop {
+        let id = read_u32(&mut stream)?;
+        // cancellations are ignored; the bot has stopped waiting anyway
+        if read_u32(&mut stream)? == pool::OP_CANCEL {
+            continue;
+        }
+        let timeout_ms = read_u32(&mut stream)?;
+        let req = read_request(&mut stream, timeout_ms)?;
+        let (handler, requests, writer) = (handler.clone(), requests.clone(), writer.clone());
+        thread::spawn(move || {
+            let resp = handler(&req);
+            requests.lock().unwrap().push(req);
+            let mut frame = Vec::with_capacity(8 + resp.len());
+            frame.extend_from_slice(&id.to_le_bytes());
+            frame.extend_from_slice(&(resp.len() as u32).to_le_bytes());
+            frame.extend_from_slice(resp.as_bytes());
+            let _ = writer.lock().unwrap().write_all(&frame);
+        });
+    }
+}
+
+/// Builds a service with one language per backend and no code wrapping or default timeout.
+pub fn service<'a, I>(langs: I) -> EvalService
+    where I: IntoIterator<Item = (&'a str, Arc<dyn EvalBackend>)> {
+    EvalService {
+        middleware: Vec::new(),
+        smoke_test_on_start: false,
+        scheduler: None,
+        languages: langs.into_iter()
+            .map(|(name, backend)| (name.to_owned(), Arc::new(Language {
+                name: name.to_owned(),
+                aliases: Vec::new(),
+                display_name: None,
+                description: None,
+                version_code: None,
+                version: Arc::new(OnceLock::new()),
+                code_before: None,
+                code_after: None,
+                timeout: None,
+                backend,
+                middleware: Vec::new(),
+                health: None,
+                smoke_tests: Vec::new()
+            })))
+            .collect(),
+        aliases: HashMap::new()
+    }
+}
+
+/// A service with one language, `p`, whose backend echoes the code back.
+pub fn echo_service() -> EvalService {
+    service(vec![("p", Arc::new(ScriptedBackend::default()) as Arc<dyn EvalBackend>)])
+}
+
+/// A message from `user_id` in `chat_id`, which is a group's if negative, as on Telegram.
+pub fn source(chat_id: i64, user_id: i64) -> MessageSource {
+    MessageSource { chat_id, user_id: Some(user_id), user_name: None, group: chat_id < 0 }
+}
+
+/// Runs a future to completion on a fresh runtime, for backends that need a reactor or timer.
+pub fn block_on<F: Future>(f: F) -> Result<F::Item, F::Error> {
+    Runtime::new().expect("failed to start runtime").block_on(f)
+}
+
+/// A frontend with context prefix `test` and the default code extraction.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TestFrontend;
+
+impl Frontend for TestFrontend {
+    fn context_prefix(&self) -> &str {
+        "test"
+    }
+
+    // as on Telegram
+    fn is_group_id(&self, id: i64) -> bool {
+        id < 0
+    }
+}
+
+/// Collects replies and left chats instead of sending them anywhere.
+#[derive(Clone, Default, Debug)]
+pub struct RecordingSink {
+    replies: Arc<Mutex<Vec<Reply>>>,
+    left: Arc<Mutex<Vec<i64>>>,
+    admins: Arc<Mutex<Vec<(i64, i64)>>>
+}
+
+impl RecordingSink {
+    /// Makes `user_id` an admin of the group `chat_id`.
+    pub fn add_admin(&self, chat_id: i64, user_id: i64) {
+        self.admins.lock().unwrap().push((chat_id, user_id));
+    }
+
+    pub fn replies(&self) -> Vec<Reply> {
+        self.replies.lock().unwrap().clone()
+    }
+
+    pub fn left(&self) -> Vec<i64> {
+        self.left.lock().unwrap().clone()
+    }
+}
+
+impl ReplySink for RecordingSink {
+    fn reply(&self, reply: Reply) -> ReplyFuture {
+        self.replies.lock().unwrap().push(reply);
+        Box::new(Ok(()).into_future())
+    }
+
+    fn leave_chat(&self, chat_id: i64) -> ReplyFuture {
+        self.left.lock().unwrap().push(chat_id);
+        Box::new(Ok(()).into_future())
+    }
+
+    fn is_admin(&self, chat_id: i64, user_id: i64) -> Box<dyn Future<Item = bool, Error = ()> + Send> {
+        Box::new(Ok(self.admins.lock().unwrap().contains(&(chat_id, user_id))).into_future())
+    }
+}
+
+/// Drives a `Dispatcher` the way a frontend does, recording its replies. Commands run on a threaded
+/// runtime, since saving the whitelist or quota usage needs its blocking pool.
+pub struct TestChat {
+    pub dispatcher: Arc<Dispatcher<TestFrontend>>,
+    pub sink: RecordingSink,
+    rt: tokio::runtime::Runtime
+}
+
+impl TestChat {
+    pub fn new(dispatcher: Dispatcher<TestFrontend>) -> Self {
+        TestChat {
+            dispatcher: Arc::new(dispatcher),
+            sink: RecordingSink::default(),
+            rt: tokio::runtime::Runtime::new().expect("failed to start runtime")
+        }
+    }
+
+    /// The command `text` from `src`, to be run later, e.g. alongside others.
+    pub fn dispatch(&self, src: &MessageSource, text: &str) -> ReplyFuture {
+        let (cmd, args) = self.dispatcher.parse(text).expect("not a command");
+        Dispatcher::dispatch(&self.dispatcher, cmd, src.clone(), args, self.sink.clone())
+    }
+
+    /// Runs the command `text` from `src` to completion and returns the last reply so far.
+    pub fn run(&mut self, src: &MessageSource, text: &str) -> Reply {
+        let (me, src, text, sink) = (self.dispatcher.clone(), src.clone(), text.to_owned(), self.sink.clone());
+        self.rt.block_on(future::lazy(move || {
+            let (cmd, args) = me.parse(&text).expect("not a command");
+            Dispatcher::dispatch(&me, cmd, src, args, sink)
+        })).unwrap();
+        self.sink.replies().pop().expect("no reply")
+    }
+
+    /// Calls `f` on the runtime, for dispatcher methods that spawn.
+    pub fn with<T, G>(&mut self, f: G) -> T
+        where T: Send + 'static, G: FnOnce(&Dispatcher<TestFrontend>) -> T + Send + 'static {
+        let me = self.dispatcher.clone();
+        self.rt.block_on(future::lazy(move || Ok::<_, ()>(f(&me)))).unwrap()
+    }
+
+    pub fn block_on<F>(&mut self, f: F) -> Result<F::Item, F::Error>
+        where F: Future + Send + 'static, F::Item: Send + 'static, F::Error: Send + 'static {
+        self.rt.block_on(f)
+    }
+}
+
+impl fmt::Debug for TestChat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TestChat").field("sink", &self.sink).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EvalRequest;
+
+    #[test]
+    fn test_scripted() {
+        let backend = Arc::new(ScriptedBackend::new()
+            .then_ok("one")
+            .then_err("two")
+            .then_delayed(Duration::from_millis(10), Ok("three".to_owned())));
+        let service = service(vec![("s", backend.clone() as Arc<dyn EvalBackend>)]);
+        let eval = |code: &str| service.eval(EvalRequest::new("s", code)).wait();
+        assert_eq!(eval("a"), Ok("one".to_owned()));
+        assert_eq!(eval("b"), Err("two".to_owned()));
+        assert_eq!(eval("c"), Ok("three".to_owned()));
+        assert_eq!(eval("d"), Ok("d".to_owned()));
+        assert_eq!(backend.calls().len(), 4);
+        assert_eq!(backend.calls()[3].code, "d");
+    }
+
+    #[test]
+    fn test_daemon() {
+        let daemon = FakeDaemon::start(|req| format!("{}:{}", req.context, req.code)).unwrap();
+        let service = service(vec![("p", daemon.backend())]);
+        let r = block_on(service.eval(EvalRequest {
+            timeout: Some(3),
+            context: Some("ctx".to_owned()),
+            ..EvalRequest::new("p", "1 + 1")
+        }));
+        assert_eq!(r, Ok("ctx:1 + 1".to_owned()));
+        assert_eq!(daemon.requests(), vec![DaemonRequest {
+            timeout_ms: 3000,
+            context: "ctx".to_owned(),
+            code: "1 + 1".to_owned()
+        }]);
+        let path = daemon.path().to_owned();
+        drop(daemon);
+        assert!(!path.exists());
+    }
+}
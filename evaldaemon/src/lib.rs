@@ -0,0 +1,469 @@
+//! Server side of the persistent evaluator protocol, for writing evaluator daemons in Rust.
+//!
+//! ````no_run
+//! extern crate evaldaemon;
+//!
+//! use std::time::Instant;
+//! use evaldaemon::{Evaluator, Server};
+//!
+//! struct Counter;
+//!
+//! impl Evaluator for Counter {
+//!     type Context = usize;
+//!
+//!     fn new_context(&self, _: &str) -> usize {
+//!         0
+//!     }
+//!
+//!     fn eval(&self, n: &mut usize, code: &str, _: Option<Instant>) -> String {
+//!         *n += 1;
+//!         format!("{}: {}", n, code)
+//!     }
+//! }
+//!
+//! fn main() {
+//!     Server::new(Counter).run().unwrap();
+//! }
+//! ````
+
+#[macro_use] extern crate log;
+
+pub mod protocol;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+pub use protocol::{Frame, Request};
+
+/// The socket systemd (or `launcher.c`, or `run_playpen_fd --no-cloexec=3`) passes to evaluators.
+pub const LISTEN_FD: RawFd = 3;
+
+/// How many abandoned evaluations may still be running before `Server` refuses new ones.
+pub const DEFAULT_MAX_ABANDONED: usize = 8;
+
+// what became of an evaluation's thread
+const RUNNING: u8 = 0;
+const FINISHED: u8 = 1;
+const ABANDONED: u8 = 2;
+
+/// A language evaluator with some state per context, e.g. one interpreter per chat.
+///
+/// Requests for the same context are evaluated one at a time; different contexts run concurrently.
+pub trait Evaluator: Send + Sync + 'static {
+    type Context: Send + 'static;
+
+    fn new_context(&self, key: &str) -> Self::Context;
+
+    /// Evaluates `code` and returns its output. `deadline` is advisory: once it passes, the bot has
+    /// already been told the time limit was exceeded and the context will be discarded, so long-running
+    /// evaluators should check it and give up.
+    ///
+    /// Threads cannot be killed, so an evaluation that ignores its deadline (or a cancellation) keeps
+    /// running on its own thread after it has been answered. `Server` counts these, and refuses to
+    /// evaluate anything else while `max_abandoned` of them are still running; an evaluator that can
+    /// get stuck for good must then be restarted.
+    fn eval(&self, ctx: &mut Self::Context, code: &str, deadline: Option<Instant>) -> String;
+}
+
+type Slot<C> = Arc<Mutex<Option<C>>>;
+
+enum Outcome<C> {
+    Done(C, String),
+    Panicked,
+    Cancelled
+}
+
+pub struct Server<E: Evaluator> {
+    evaluator: Arc<E>,
+    contexts: Mutex<HashMap<String, Slot<E::Context>>>,
+    abandoned: Arc<AtomicUsize>,
+    max_abandoned: usize
+}
+
+impl<E: Evaluator> Server<E> {
+    pub fn new(evaluator: E) -> Self {
+        Server {
+            evaluator: Arc::new(evaluator),
+            contexts: Mutex::new(HashMap::new()),
+            abandoned: Arc::new(AtomicUsize::new(0)),
+            max_abandoned: DEFAULT_MAX_ABANDONED
+        }
+    }
+
+    /// Sets how many evaluations that timed out or were cancelled may still be running before new
+    /// ones are refused.
+    pub fn max_abandoned(mut self, max: usize) -> Self {
+        self.max_abandoned = max;
+        self
+    }
+
+    /// Evaluations that timed out or were cancelled but are still running.
+    pub fn abandoned(&self) -> usize {
+        self.abandoned.load(Ordering::SeqCst)
+    }
+
+    /// Serves on stdin and stdout if the first argument is `--stdio`, on the path given as the first
+    /// argument otherwise, or on `LISTEN_FD` if there is none.
+    pub fn run(self) -> io::Result<()> {
+        match env::args_os().nth(1) {
+            Some(ref arg) if arg == "--stdio" => {
+                self.serve_stdio();
+                Ok(())
+            }
+            Some(path) => self.serve_path(path),
+            None => self.serve_fd(LISTEN_FD)
+        }
+    }
+
+    /// Serves the `stdio` backend, which spawns the evaluator itself. Nothing else may write to stdout.
+    pub fn serve_stdio(self) {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        self.handle(Stdio(stdin.lock(), stdout.lock()));
+    }
+
+    /// Serves on an inherited listening socket.
+    pub fn serve_fd(self, fd: RawFd) -> io::Result<()> {
+        self.serve(unsafe { UnixListener::from_raw_fd(fd) })
+    }
+
+    /// Binds `path`, replacing any stale socket there, and serves on it.
+    pub fn serve_path<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        self.serve(UnixListener::bind(path)?)
+    }
+
+    pub fn serve(self, listener: UnixListener) -> io::Result<()> {
+        let me = Arc::new(self);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let me = me.clone();
+                    thread::spawn(move || Server::handle_socket(&me, stream));
+                }
+                Err(e) => error!("failed to accept: {}", e)
+            }
+        }
+        Ok(())
+    }
+
+    fn slot(&self, key: &str) -> Slot<E::Context> {
+        let mut contexts = self.contexts.lock().unwrap();
+        contexts.entry(key.to_owned()).or_insert_with(|| Arc::new(Mutex::new(None))).clone()
+    }
+
+    /// Serves requests on one connection until the bot closes it, in whichever protocol version the
+    /// bot opens it with.
+    pub fn handle_socket(me: &Arc<Self>, mut stream: UnixStream) {
+        let first = match protocol::read_u32(&mut stream) {
+            Ok(first) => first,
+            Err(_) => return
+        };
+        if first != protocol::HELLO {
+            me.handle_after(stream, Some(first));
+            return;
+        }
+        let version = protocol::read_hello(&mut stream)
+            .and_then(|version| {
+                let version = version.min(protocol::VERSION);
+                protocol::write_hello(&mut stream, version).map(|_| version)
+            });
+        match version {
+            Ok(2) => Server::handle_v2(me, stream),
+            Ok(_) => me.handle(stream),
+            Err(e) => error!("failed to open session: {}", e)
+        }
+    }
+
+    // each request is evaluated on its own thread and answered as soon as it's done
+    fn handle_v2(me: &Arc<Self>, mut stream: UnixStream) {
+        let running = Arc::new(Mutex::new(HashMap::new()));
+        let writer = match stream.try_clone() {
+            Ok(writer) => Arc::new(Mutex::new(writer)),
+            Err(e) => {
+                error!("failed to clone stream: {}", e);
+                return;
+            }
+        };
+        loop {
+            let (id, frame) = match protocol::read_frame(&mut stream) {
+                Ok(frame) => frame,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+                Err(e) => {
+                    error!("failed to read request: {}", e);
+                    return;
+                }
+            };
+            match frame {
+                Frame::Eval(req) => {
+                    let (tx, rx) = mpsc::channel();
+                    running.lock().unwrap().insert(id, tx.clone());
+                    let (me, writer, running) = (me.clone(), writer.clone(), running.clone());
+                    thread::spawn(move || {
+                        let output = me.eval_with(req, tx, rx);
+                        running.lock().unwrap().remove(&id);
+                        let mut writer = match writer.lock() {
+                            Ok(writer) => writer,
+                            Err(poisoned) => poisoned.into_inner()
+                        };
+                        if let Err(e) = protocol::write_reply(&mut *writer, id, &output) {
+                            error!("failed to write response: {}", e);
+                        }
+                    });
+                }
+                Frame::Cancel => if let Some(tx) = running.lock().unwrap().remove(&id) {
+                    let _ = tx.send(Outcome::Cancelled);
+                }
+            }
+        }
+    }
+
+    /// Serves version 1 requests on one connection until the bot closes it.
+    pub fn handle<S: Read + Write>(&self, stream: S) {
+        self.handle_after(stream, None)
+    }
+
+    fn handle_after<S: Read + Write>(&self, mut stream: S, mut first: Option<u32>) {
+        loop {
+            let req = match first.take() {
+                Some(timeout_ms) => protocol::read_request_after(&mut stream, timeout_ms),
+                None => protocol::read_request(&mut stream)
+            };
+            let req = match req {
+                Ok(req) => req,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+                Err(e) => {
+                    error!("failed to read request: {}", e);
+                    return;
+                }
+            };
+            let output = self.eval(req);
+            if let Err(e) = protocol::write_response(&mut stream, &output) {
+                error!("failed to write response: {}", e);
+                return;
+            }
+        }
+    }
+
+    /// Evaluates one request in its context, enforcing its timeout.
+    pub fn eval(&self, req: Request) -> String {
+        let (tx, rx) = mpsc::channel();
+        self.eval_with(req, tx, rx)
+    }
+
+    /// Forgets the context `key`, so that the next request for it starts afresh. An evaluation still
+    /// running in it finishes, but its context is then dropped.
+    pub fn reset(&self, key: &str) {
+        self.contexts.lock().unwrap().remove(key);
+    }
+
+    // the evaluation is abandoned early if `rx` gets `Cancelled` from elsewhere
+    fn eval_with(&self, req: Request, tx: mpsc::Sender<Outcome<E::Context>>, rx: mpsc::Receiver<Outcome<E::Context>>)
+        -> String {
+        if req.reset {
+            self.reset(&req.context);
+            return String::new();
+        }
+        let abandoned = self.abandoned();
+        if abandoned >= self.max_abandoned {
+            warn!("refusing to evaluate in {}: {} abandoned evaluations still running", req.context, abandoned);
+            return format!("evaluator overloaded: {} evaluations did not stop at their time limit", abandoned);
+        }
+        let slot = self.slot(&req.context);
+        let mut guard = match slot.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        let evaluator = self.evaluator.clone();
+        let mut ctx = guard.take().unwrap_or_else(|| evaluator.new_context(&req.context));
+        let timeout = req.timeout;
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        let code = req.code;
+        let state = Arc::new(AtomicU8::new(RUNNING));
+        let (finished, count) = (state.clone(), self.abandoned.clone());
+        thread::spawn(move || {
+            let output = panic::catch_unwind(AssertUnwindSafe(|| evaluator.eval(&mut ctx, &code, deadline)));
+            if finished.compare_exchange(RUNNING, FINISHED, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                let left = count.fetch_sub(1, Ordering::SeqCst) - 1;
+                info!("an abandoned evaluation finished; {} still running", left);
+            }
+            let _ = tx.send(match output {
+                Ok(output) => Outcome::Done(ctx, output),
+                Err(_) => Outcome::Panicked
+            });
+        });
+        let result = match timeout {
+            Some(t) => rx.recv_timeout(t).map_err(|e| e == mpsc::RecvTimeoutError::Timeout),
+            None => rx.recv().map_err(|_| false)
+        };
+        if let Ok(Outcome::Cancelled) | Err(true) = result {
+            // unless it finished just now, its thread keeps running
+            if state.compare_exchange(RUNNING, ABANDONED, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                let abandoned = self.abandoned.fetch_add(1, Ordering::SeqCst) + 1;
+                warn!("abandoned an evaluation in {}; {} still running", req.context, abandoned);
+                if abandoned >= self.max_abandoned {
+                    error!("{} abandoned evaluations still running; refusing new ones until they stop, \
+                        or the daemon is restarted", abandoned);
+                }
+            }
+        }
+        match result {
+            Ok(Outcome::Done(ctx, output)) => {
+                *guard = Some(ctx);
+                output
+            }
+            // the context stays empty, so the next request starts afresh
+            Ok(Outcome::Cancelled) => "cancelled".to_owned(),
+            Err(true) => "time limit exceeded".to_owned(),
+            Ok(Outcome::Panicked) | Err(false) => "evaluator panicked".to_owned()
+        }
+    }
+}
+
+struct Stdio<R, W>(R, W);
+
+impl<R: Read, W> Read for Stdio<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R, W: Write> Write for Stdio<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.1.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.1.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    struct Counter;
+
+    impl Evaluator for Counter {
+        type Context = usize;
+
+        fn new_context(&self, _: &str) -> usize {
+            0
+        }
+
+        fn eval(&self, n: &mut usize, code: &str, _: Option<Instant>) -> String {
+            match code {
+                "sleep" => thread::sleep(Duration::from_millis(200)),
+                "panic" => panic!("asked to"),
+                _ => ()
+            }
+            *n += 1;
+            format!("{}:{}", n, code)
+        }
+    }
+
+    fn req(context: &str, code: &str, timeout_ms: u64) -> Request {
+        Request {
+            timeout: Some(Duration::from_millis(timeout_ms)).filter(|_| timeout_ms != 0),
+            context: context.to_owned(),
+            code: code.to_owned(),
+            reset: false
+        }
+    }
+
+    #[test]
+    fn test_contexts() {
+        let server = Server::new(Counter);
+        assert_eq!(server.eval(req("a", "x", 0)), "1:x");
+        assert_eq!(server.eval(req("a", "y", 1000)), "2:y");
+        assert_eq!(server.eval(req("b", "z", 0)), "1:z");
+        assert_eq!(server.eval(req("a", "sleep", 10)), "time limit exceeded");
+        assert_eq!(server.eval(req("a", "x", 0)), "1:x");
+        assert_eq!(server.eval(req("b", "panic", 0)), "evaluator panicked");
+        assert_eq!(server.eval(req("b", "z", 0)), "1:z");
+        assert_eq!(server.eval(req("b", "z", 0)), "2:z");
+        assert_eq!(server.eval(Request { reset: true, ..req("b", "", 0) }), "");
+        assert_eq!(server.eval(req("b", "z", 0)), "1:z");
+        assert_eq!(server.eval(req("a", "y", 0)), "2:y");
+    }
+
+    #[test]
+    fn test_abandoned() {
+        let server = Server::new(Counter).max_abandoned(1);
+        assert_eq!(server.eval(req("a", "sleep", 10)), "time limit exceeded");
+        assert_eq!(server.abandoned(), 1);
+        assert_eq!(server.eval(req("b", "x", 0)), "evaluator overloaded: 1 evaluations did not stop at their time limit");
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(server.abandoned(), 0);
+        assert_eq!(server.eval(req("b", "x", 0)), "1:x");
+    }
+
+    #[test]
+    fn test_socket() {
+        let path = env::temp_dir().join(format!("evaldaemon-test-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || Server::new(Counter).serve(listener));
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        for (i, code) in ["a", "b"].iter().enumerate() {
+            protocol::write_request(&mut stream, &req("c", code, 1000)).unwrap();
+            assert_eq!(protocol::read_response(&mut stream).unwrap(), format!("{}:{}", i + 1, code));
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_multiplexed() {
+        let path = env::temp_dir().join(format!("evaldaemon-test-v2-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || Server::new(Counter).serve(listener));
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        protocol::write_hello(&mut stream, protocol::VERSION).unwrap();
+        assert_eq!(protocol::read_u32(&mut stream).unwrap(), protocol::HELLO);
+        assert_eq!(protocol::read_hello(&mut stream).unwrap(), 2);
+        protocol::write_frame(&mut stream, 1, &Frame::Eval(req("a", "sleep", 1000))).unwrap();
+        protocol::write_frame(&mut stream, 2, &Frame::Eval(req("b", "x", 1000))).unwrap();
+        assert_eq!(protocol::read_reply(&mut stream).unwrap(), (2, "1:x".to_owned()));
+        assert_eq!(protocol::read_reply(&mut stream).unwrap(), (1, "1:sleep".to_owned()));
+
+        protocol::write_frame(&mut stream, 3, &Frame::Eval(req("a", "sleep", 0))).unwrap();
+        protocol::write_frame(&mut stream, 3, &Frame::Cancel).unwrap();
+        assert_eq!(protocol::read_reply(&mut stream).unwrap(), (3, "cancelled".to_owned()));
+        // the cancelled evaluation's context was discarded
+        protocol::write_frame(&mut stream, 4, &Frame::Eval(req("a", "x", 0))).unwrap();
+        assert_eq!(protocol::read_reply(&mut stream).unwrap(), (4, "1:x".to_owned()));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_stdio() {
+        let mut input = Vec::new();
+        for code in &["a", "b"] {
+            protocol::write_request(&mut input, &req("c", code, 0)).unwrap();
+        }
+        let mut output = Vec::new();
+        Server::new(Counter).handle(Stdio(io::Cursor::new(input), &mut output));
+        let mut output = io::Cursor::new(output);
+        assert_eq!(protocol::read_response(&mut output).unwrap(), "1:a");
+        assert_eq!(protocol::read_response(&mut output).unwrap(), "2:b");
+    }
+}
@@ -0,0 +1,182 @@
+//! Framing of the persistent evaluator protocol described in the README.
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// Sent by the bot in place of the first request's timeout to switch a connection to version 2, in
+/// which every request carries an ID and replies may come in any order.
+pub const HELLO: u32 = 0xFFFF_FFFF;
+/// The highest protocol version spoken here.
+pub const VERSION: u32 = 2;
+
+/// Sent in place of a request's timeout, in either version, to discard the request's context instead of
+/// evaluating. The code is empty and so is the response.
+pub const RESET: u32 = 0xFFFF_FFFE;
+
+pub const OP_EVAL: u32 = 0;
+pub const OP_CANCEL: u32 = 1;
+
+/// One evaluation request as sent by the bot.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Request {
+    /// `None` if the bot asked for no time limit.
+    pub timeout: Option<Duration>,
+    pub context: String,
+    pub code: String,
+    /// Discard the context rather than evaluate.
+    pub reset: bool
+}
+
+pub(crate) fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_string<R: Read>(r: &mut R, len: u32) -> io::Result<String> {
+    let mut b = vec![0u8; len as usize];
+    r.read_exact(&mut b)?;
+    Ok(String::from_utf8_lossy(&b).into_owned())
+}
+
+pub fn read_request<R: Read>(r: &mut R) -> io::Result<Request> {
+    let timeout_ms = read_u32(r)?;
+    read_request_after(r, timeout_ms)
+}
+
+/// Reads the rest of a request whose timeout has already been read.
+pub fn read_request_after<R: Read>(r: &mut R, timeout_ms: u32) -> io::Result<Request> {
+    let ctxlen = read_u32(r)?;
+    let codelen = read_u32(r)?;
+    Ok(Request {
+        timeout: match timeout_ms {
+            0 | RESET => None,
+            ms => Some(Duration::from_millis(u64::from(ms)))
+        },
+        context: read_string(r, ctxlen)?,
+        code: read_string(r, codelen)?,
+        reset: timeout_ms == RESET
+    })
+}
+
+pub fn write_request<W: Write>(w: &mut W, req: &Request) -> io::Result<()> {
+    let timeout_ms = match req.timeout {
+        _ if req.reset => RESET,
+        Some(t) => t.as_millis() as u32,
+        None => 0
+    };
+    w.write_all(&timeout_ms.to_le_bytes())?;
+    w.write_all(&(req.context.len() as u32).to_le_bytes())?;
+    w.write_all(&(req.code.len() as u32).to_le_bytes())?;
+    w.write_all(req.context.as_bytes())?;
+    w.write_all(req.code.as_bytes())?;
+    w.flush()
+}
+
+pub fn read_response<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = read_u32(r)?;
+    read_string(r, len)
+}
+
+pub fn write_response<W: Write>(w: &mut W, output: &str) -> io::Result<()> {
+    w.write_all(&(output.len() as u32).to_le_bytes())?;
+    w.write_all(output.as_bytes())?;
+    w.flush()
+}
+
+/// A version 2 message from the bot.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Frame {
+    Eval(Request),
+    /// Stop evaluating the request with the frame's ID, which is then answered with "cancelled".
+    Cancel
+}
+
+/// Reads the version the bot asks for after `HELLO`.
+pub fn read_hello<R: Read>(r: &mut R) -> io::Result<u32> {
+    read_u32(r)
+}
+
+pub fn write_hello<W: Write>(w: &mut W, version: u32) -> io::Result<()> {
+    w.write_all(&HELLO.to_le_bytes())?;
+    w.write_all(&version.to_le_bytes())?;
+    w.flush()
+}
+
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<(u32, Frame)> {
+    let id = read_u32(r)?;
+    match read_u32(r)? {
+        OP_EVAL => Ok((id, Frame::Eval(read_request(r)?))),
+        OP_CANCEL => Ok((id, Frame::Cancel)),
+        // the length of an unknown frame is unknown too, so the connection can't continue
+        op => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown op {}", op)))
+    }
+}
+
+pub fn write_frame<W: Write>(w: &mut W, id: u32, frame: &Frame) -> io::Result<()> {
+    w.write_all(&id.to_le_bytes())?;
+    match *frame {
+        Frame::Eval(ref req) => {
+            w.write_all(&OP_EVAL.to_le_bytes())?;
+            write_request(w, req)
+        }
+        Frame::Cancel => {
+            w.write_all(&OP_CANCEL.to_le_bytes())?;
+            w.flush()
+        }
+    }
+}
+
+/// Reads a version 2 reply, as the ID of the request it answers and the output.
+pub fn read_reply<R: Read>(r: &mut R) -> io::Result<(u32, String)> {
+    let id = read_u32(r)?;
+    Ok((id, read_response(r)?))
+}
+
+/// Writes a version 2 reply in one call, so replies written from several threads don't interleave
+/// when each holds the stream's lock.
+pub fn write_reply<W: Write>(w: &mut W, id: u32, output: &str) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(8 + output.len());
+    buf.extend_from_slice(&id.to_le_bytes());
+    write_response(&mut buf, output)?;
+    w.write_all(&buf)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_roundtrip() {
+        let req = Request {
+            timeout: Some(Duration::from_secs(2)),
+            context: "tg-1".to_owned(),
+            code: "print(1)".to_owned(),
+            reset: false
+        };
+        let mut buf = Vec::new();
+        write_request(&mut buf, &req).unwrap();
+        assert_eq!(&buf[..4], &[0xd0, 0x07, 0, 0]);
+        assert_eq!(read_request(&mut Cursor::new(buf)).unwrap(), req);
+
+        let mut buf = Vec::new();
+        write_response(&mut buf, "1\n").unwrap();
+        assert_eq!(buf, b"\x02\0\0\x001\n");
+        assert_eq!(read_response(&mut Cursor::new(buf)).unwrap(), "1\n");
+
+        let reset = Request { timeout: None, code: String::new(), reset: true, ..req.clone() };
+        let mut buf = Vec::new();
+        write_request(&mut buf, &reset).unwrap();
+        assert_eq!(&buf[..4], &[0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(read_request(&mut Cursor::new(buf)).unwrap(), reset);
+
+        let mut buf = Vec::new();
+        write_frame(&mut buf, 7, &Frame::Eval(req.clone())).unwrap();
+        assert_eq!(read_frame(&mut Cursor::new(buf)).unwrap(), (7, Frame::Eval(req)));
+        let mut buf = Vec::new();
+        write_reply(&mut buf, 7, "1\n").unwrap();
+        assert_eq!(read_reply(&mut Cursor::new(buf)).unwrap(), (7, "1\n".to_owned()));
+    }
+}
@@ -47,6 +47,9 @@ namespace cseval {
     }
 
     class Program {
+        // sent in place of the timeout to discard a context
+        private const uint RESET = 0xFFFFFFFE;
+
         private static readonly ScriptOptions SCRIPT_OPTIONS = ScriptOptions.Default
             .WithEmitDebugInformation(false)
             .WithFilePath("-")
@@ -104,6 +107,12 @@ namespace cseval {
                 string conkey = Encoding.UTF8.GetString(buf, 0, (int) h.ContextKeyLength);
                 string code = Encoding.UTF8.GetString(buf, (int) h.ContextKeyLength, (int) h.CodeLength);
 
+                if (h.Timeout == RESET) {
+                    _contexts.Remove(conkey);
+                    await ns.WriteAsync(IntToBytes(0), 0, 4, CancellationToken.None).ConfigureAwait(false);
+                    return;
+                }
+
                 // TODO
                 string resp = "unknown error";
                 ScriptState<object> res = _contexts.GetValueOrDefault(conkey, null);
@@ -13,6 +13,8 @@ using Mono.Unix.Native;
 
 namespace FSEval {
     public static class Program {
+        // sent in place of the timeout to discard a context
+        private const int RESET = unchecked((int) 0xFFFFFFFE);
         private static readonly StringReader DummyInput = new StringReader("");
         private static readonly StringWriter EvalOutput = new StringWriter();
         private static readonly Dictionary<string, Shell.FsiEvaluationSession> _evaluators = new Dictionary<string, Shell.FsiEvaluationSession>();
@@ -65,6 +67,12 @@ namespace FSEval {
             string key = conn.ReadUTF8(keylen);
             string work = conn.ReadUTF8(codelen).Trim();
 
+            if (timeout == RESET) {
+                _evaluators.Remove(key);
+                ReturnWork("", conn);
+                return;
+            }
+
             if (work == "") {
                 ReturnWork("", conn);
                 return;
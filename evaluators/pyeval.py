@@ -3,6 +3,9 @@ import io, sys, struct, os, traceback, socketserver, socket, fcntl
 import contextlib
 from code import InteractiveInterpreter
 
+# sent in place of the timeout to discard a context
+RESET = 0xFFFFFFFE
+
 class PyEval(InteractiveInterpreter):
     def __init__(self, locals=None):
         InteractiveInterpreter.__init__(self, locals)
@@ -52,6 +55,12 @@ class RequestHandler(socketserver.StreamRequestHandler):
         global codebufs, etors
 
         timeout, key, codefragment = readinput(self.rfile)
+        if timeout == RESET:
+            etors.pop(key, None)
+            codebufs.pop(key, None)
+            writeoutput(self.wfile, "")
+            return
+
         codebuf = codebufs.setdefault(key, [])
         etor = etors.setdefault(key, PyEval())
 
@@ -2,23 +2,31 @@ extern crate evalbotlib as backend;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate log;
+extern crate serde_json;
 extern crate toml;
 extern crate futures;
 extern crate tokio;
 extern crate telebot;
 extern crate env_logger;
 
-use backend::{EvalService, Language, util};
+use backend::{EvalService, shutdown, smoke, util};
+use backend::chat::{AccessRequest, Command, Dispatcher, Frontend, MessageSource, Reply, ReplyFuture, ReplySink,
+    Whitelist};
+use backend::notify::{Event, Notifier, NotifyCfg};
+use backend::quota::{QuotaCfg, Quotas, Usage};
 
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, RwLock};
+use std::env;
+use std::process;
+use std::sync::Arc;
 use std::borrow::Cow;
+use std::time::{Duration, Instant};
 
 use futures::{Future, Stream, IntoFuture};
+use tokio::runtime::Runtime;
 use telebot::RcBot;
 use telebot::functions::*;
 use telebot::objects::*;
-use futures::future::Either;
 
 macro_rules! nullify_future {
     ($task:expr, $fut:expr) => ($fut.map(|_| ())
@@ -26,57 +34,106 @@ macro_rules! nullify_future {
 }
 
 static WHITELIST_FILENAME: &'static str = "tgwhitelist.toml";
+static QUOTA_FILENAME: &'static str = "tgquota.toml";
+// how often lapsed whitelist entries are removed
+const WHITELIST_SWEEP: Duration = Duration::from_secs(60);
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 struct TgCfg {
     owners: HashSet<String>,
+    /// Chat to send `notify` events and access requests to.
     msg_owner_id: Option<i64>,
     bot_id: String,
-    lang_subst: HashMap<String, String>
+    /// Extra command names for languages whose own names Telegram won't accept, e.g. `cpp` for `c++`.
+    #[serde(default)]
+    lang_subst: HashMap<String, String>,
+    /// Seconds to let running evaluations finish after SIGTERM or SIGINT.
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout: u64,
+    quota: Option<QuotaCfg>,
+    notify: Option<NotifyCfg>
 }
 
-#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
-struct TgWhitelist {
-    priv_enabled: bool,
-    group_enabled: bool,
-    allowed: HashSet<i64>,
-    blocked: HashSet<i64>
+fn default_shutdown_timeout() -> u64 {
+    20
 }
 
-impl TgWhitelist {
-    fn priv_ok(&self, id: i64) -> bool {
-        (!self.priv_enabled || self.allowed.contains(&id)) && !self.blocked.contains(&id)
+struct TgFrontend;
+
+impl Frontend for TgFrontend {
+    fn context_prefix(&self) -> &str {
+        "tg"
     }
 
-    fn group_ok(&self, id: i64) -> bool {
-        (!self.group_enabled || self.allowed.contains(&id)) && !self.blocked.contains(&id)
+    fn command_prefix(&self) -> &str {
+        "/"
     }
 
-    fn allow(&mut self, id: i64) {
-        self.allowed.insert(id);
+    // users' IDs are positive, groups' negative
+    fn is_group_id(&self, id: i64) -> bool {
+        id < 0
     }
+}
 
-    fn unallow(&mut self, id: i64) {
-        self.allowed.remove(&id);
+struct TgSink {
+    tgbot: RcBot,
+    chat_id: i64,
+    msg_id: i64,
+    group: bool,
+    /// Whether there is an owner chat to send access requests to.
+    can_request: bool
+}
+
+impl ReplySink for TgSink {
+    fn reply(&self, reply: Reply) -> ReplyFuture {
+        let msg = match reply {
+            Reply::Output(r) => self.tgbot.message(self.chat_id, telegram_wrap_result(&r, self.group))
+                .parse_mode(ParseMode::HTML),
+            Reply::NotAllowed(ref t) if self.can_request => return call(&self.tgbot, "sendMessage", &SendMessage {
+                chat_id: self.chat_id,
+                text: t.clone(),
+                reply_to_message_id: Some(self.msg_id),
+                reply_markup: access_keyboard(self.chat_id)
+            }),
+            Reply::Text(t) | Reply::NotAllowed(t) => self.tgbot.message(self.chat_id, t)
+        };
+        Box::new(nullify_future!("sending message", msg.reply_to_message_id(self.msg_id).send()))
     }
 
-    fn block(&mut self, id: i64) {
-        self.blocked.insert(id);
+    fn leave_chat(&self, chat_id: i64) -> ReplyFuture {
+        Box::new(nullify_future!("leaving group", self.tgbot.leave_chat(chat_id).send()))
     }
 
-    fn unblock(&mut self, id: i64) {
-        self.blocked.remove(&id);
+    fn is_admin(&self, chat_id: i64, user_id: i64) -> Box<dyn Future<Item = bool, Error = ()> + Send> {
+        Box::new(self.tgbot.get_chat_member(chat_id, user_id).send()
+            .map(|(_, member)| member.status == "creator" || member.status == "administrator")
+            .or_else(|e| {
+                error!("error getting chat member: {}", e);
+                Ok(false)
+            }))
     }
 
-    fn save<'a, 'b>(&'a self, path: &'static str) -> impl Future<Item = (), Error = ()> + 'b {
-        nullify_future!("saving whitelist", util::encode(self, path))
+    fn chat_name(&self, id: i64) -> Box<dyn Future<Item = Option<String>, Error = ()> + Send> {
+        Box::new(self.tgbot.get_chat(id).send()
+            .map(|(_, chat)| match (chat.title, chat.username) {
+                (Some(title), Some(name)) => Some(format!("{} (@{})", title, name)),
+                (Some(title), None) => Some(title),
+                (None, Some(name)) => Some(format!("@{}", name)),
+                (None, None) => chat.first_name
+            })
+            // only chats the bot is in, and users who have talked to it, can be looked up
+            .or_else(|e| {
+                debug!("error getting chat: {}", e);
+                Ok(None)
+            }))
     }
 }
 
 struct TgSvc {
     config: TgCfg,
-    whitelist: RwLock<TgWhitelist>,
-    service: EvalService,
+    bot: RcBot,
+    dispatcher: Arc<Dispatcher<TgFrontend>>,
+    notifier: Option<Arc<Notifier>>
 }
 
 fn telegram_wrap_result(s: &str, group: bool) -> String {
@@ -105,171 +162,198 @@ fn telegram_wrap_result(s: &str, group: bool) -> String {
     }
 }
 
-fn is_from_owner(msg: &Message, tgsvc: &TgSvc) -> bool {
-    msg.from.as_ref().and_then(|u| u.username.as_ref().map(|un| tgsvc.is_owner(un))).unwrap_or(false)
+// 1-32 lowercase letters, digits and underscores
+fn is_command_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 32
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
 }
 
-fn handle_update((tgbot, update): (RcBot, Update), tgsvc: &Arc<TgSvc>) -> Result<(), ()> {
-    if let Some((chat, Some(new_user))) = update.message.map(|m| (m.chat, m.new_chat_member)) {
-        let chat_id = chat.id;
-        if let (Ok(id), Ok(wl)) = (tgbot.inner.id.read(), tgsvc.whitelist.read()) {
-            if *id == Some(new_user.id) && !wl.group_ok(chat_id) {
-                tokio::spawn(nullify_future!("leaving group",
-                    tgbot.message(chat_id, format!("You or this group is not on the whitelist. Seek help. ID: {}", chat_id)).send()
-                        .and_then(move |(tgbot, _)| tgbot.leave_chat(chat_id).send())));
-            }
+#[derive(Serialize)]
+struct BotCommand {
+    command: String,
+    description: String
+}
+
+#[derive(Serialize)]
+struct SetMyCommands {
+    commands: Vec<BotCommand>
+}
+
+#[derive(Serialize)]
+struct InlineButton {
+    text: String,
+    callback_data: String
+}
+
+#[derive(Serialize)]
+struct InlineKeyboard {
+    inline_keyboard: Vec<Vec<InlineButton>>
+}
+
+#[derive(Serialize)]
+struct SendMessage {
+    chat_id: i64,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to_message_id: Option<i64>,
+    reply_markup: InlineKeyboard
+}
+
+// without reply_markup, which also removes the buttons
+#[derive(Serialize)]
+struct EditMessageText {
+    chat_id: i64,
+    message_id: i64,
+    text: String
+}
+
+#[derive(Serialize)]
+struct AnswerCallbackQuery {
+    callback_query_id: String,
+    text: String
+}
+
+/// Calls a Bot API method that telebot doesn't cover.
+fn call<T: serde::Serialize>(bot: &RcBot, method: &'static str, params: &T) -> ReplyFuture {
+    match serde_json::to_string(params) {
+        Ok(json) => Box::new(nullify_future!(method, bot.inner.fetch_json(method, &json))),
+        Err(e) => {
+            error!("error serialising {}: {}", method, e);
+            Box::new(Ok(()).into_future())
         }
     }
-    Ok(())
 }
 
-fn handle_eval(tgsvc: &Arc<TgSvc>, tgbot: RcBot, msg: Message, lang: &Arc<Language>, is_hash: bool)
-    -> impl Future<Item = (), Error = ()> {
-    let chat_id = msg.chat.id;
-    let group = msg.chat.kind != "private";
-    if let Ok(wl) = tgsvc.whitelist.read() {
-        if group && !wl.group_ok(chat_id)
-            || !group && !wl.priv_ok(chat_id) {
-            tokio::spawn(nullify_future!("sending message",
-                tgbot.message(chat_id, format!("You or this group is not on the whitelist. Seek help. ID: {}", chat_id)).send()));
-            return Ok(()).into_future();
-        }
-    } else {
-        error!("Failed to acquire RwLock");
-        tokio::spawn(nullify_future!("sending message",
-                tgbot.message(chat_id, "Internal error occurred".to_owned()).send()));
-        return Ok(()).into_future();
+// callback data is "<action>:<chat ID>", with actions access, approve and deny
+fn buttons(buttons: &[(&str, &str)], chat_id: i64) -> InlineKeyboard {
+    InlineKeyboard {
+        inline_keyboard: vec![buttons.iter()
+            .map(|&(text, action)| InlineButton {
+                text: text.to_owned(),
+                callback_data: format!("{}:{}", action, chat_id)
+            })
+            .collect()]
     }
+}
 
-    let no_limit = is_hash && is_from_owner(&msg, tgsvc);
-    let msg_id = msg.message_id;
-    info!("({}) evaluating from {:?}: {:?}", msg_id, msg.from, msg.text.as_ref().map(|x| x.as_str()).unwrap_or(""));
-    let code = msg.text.map(|x| {
-        let mut r = x.trim_left().to_owned();
-        r.push_str("\n");
-        r
-    }).unwrap_or_else(|| "".to_owned());
-    tokio::spawn(nullify_future!("sending message",
-        lang.eval(code, if no_limit { Some(0) } else { None }, Some(format!("tg{}", chat_id)))
-            .then(move |e| {
-                let ok = e.is_ok();
-                info!("({}) result: {:?}", msg_id, e.as_ref().map(|x| x.as_str()).unwrap_or(""));
-                let msg = tgbot.message(chat_id, e.map(|r| telegram_wrap_result(&r, group)).unwrap_or_else(|e| e));
-                if ok {
-                    msg.parse_mode(ParseMode::HTML)
-                } else {
-                    msg
-                }.reply_to_message_id(msg_id).send()
-            })));
+fn access_keyboard(chat_id: i64) -> InlineKeyboard {
+    buttons(&[("Request access", "access")], chat_id)
+}
 
-    Ok(()).into_future()
+fn parse_callback(data: &str) -> Option<(&str, i64)> {
+    let mut parts = data.splitn(2, ':');
+    let action = parts.next()?;
+    let chat_id = parts.next()?.parse().ok()?;
+    Some((action, chat_id))
 }
 
-#[derive(Clone, Copy)]
-enum WhitelistToggleOp {
-    TogglePrivate,
-    ToggleGroup
+// Telegram shows at most 100 commands, each described in at most 256 characters
+fn bot_commands(commands: &[(String, Command)]) -> SetMyCommands {
+    SetMyCommands {
+        commands: commands.iter()
+            .filter(|(name, cmd)| is_command_name(name) && !cmd.owner_only())
+            .take(100)
+            .map(|(name, cmd)| BotCommand {
+                command: name.clone(),
+                description: cmd.description().chars().take(256).collect()
+            })
+            .collect()
+    }
 }
 
-#[derive(Clone, Copy)]
-enum WhitelistModOp {
-    Allow,
-    Unallow,
-    Block,
-    Unblock
+// what Telegram puts in `from` for anonymous group admins and for messages posted as a channel
+static ANONYMOUS_SENDERS: [i64; 2] = [1087968824, 136817688];
+
+fn message_source(msg: &Message) -> MessageSource {
+    // anonymous senders are nobody in particular, which puts them last in the queue
+    let from = msg.from.as_ref().filter(|u| !ANONYMOUS_SENDERS.contains(&u.id));
+    MessageSource {
+        chat_id: msg.chat.id,
+        user_id: from.map(|u| u.id),
+        user_name: from.and_then(|u| u.username.clone()),
+        group: msg.chat.kind != "private"
+    }
 }
 
-fn handle_whitelist_toggle(me: &Arc<TgSvc>, tgbot: RcBot, msg: Message, op: WhitelistToggleOp)
-    -> impl Future<Item = (), Error = ()> {
-    if !is_from_owner(&msg, me) {
-        return Either::A(Ok(()).into_future());
+fn describe_user(user: &User) -> String {
+    match user.username {
+        Some(ref name) => format!("@{} ({})", name, user.id),
+        None => format!("{} ({})", user.first_name, user.id)
     }
+}
 
-    let resp = match me.whitelist.write() {
-        Ok(mut wl) => match op {
-            WhitelistToggleOp::TogglePrivate => {
-                wl.priv_enabled = !wl.priv_enabled;
-                tokio::spawn(wl.save(WHITELIST_FILENAME));
-                format!("Private whitelist enabled: {}", wl.priv_enabled)
-            }
-            WhitelistToggleOp::ToggleGroup => {
-                wl.group_enabled = !wl.group_enabled;
-                tokio::spawn(wl.save(WHITELIST_FILENAME));
-                format!("Group whitelist enabled: {}", wl.group_enabled)
-            }
-        }
-        Err(err) => {
-            error!("error while acquiring RwLock: {}", err);
-            "error acquiring RwLock".to_owned()
-        }
+fn handle_callback(tgbot: &RcBot, query: CallbackQuery, tgsvc: &Arc<TgSvc>) {
+    let (action, chat_id) = match query.data.as_ref().and_then(|data| parse_callback(data)) {
+        Some(parsed) => parsed,
+        None => return
+    };
+    let answer = match action {
+        "access" => tgsvc.request_access(tgbot, &query, chat_id),
+        "approve" => tgsvc.decide_access(tgbot, &query, chat_id, true),
+        "deny" => tgsvc.decide_access(tgbot, &query, chat_id, false),
+        _ => return
     };
-    Either::B(nullify_future!("sending message", tgbot.message(msg.chat.id, resp)
-        .reply_to_message_id(msg.message_id)
-        .send()))
+    tokio::spawn(call(tgbot, "answerCallbackQuery", &AnswerCallbackQuery {
+        callback_query_id: query.id.clone(),
+        text: answer
+    }));
 }
 
-fn handle_whitelist_mod(me: &Arc<TgSvc>, tgbot: RcBot, msg: Message, op: WhitelistModOp)
-    -> impl Future<Item = (), Error = ()> {
-    if !is_from_owner(&msg, me) {
-        return Either::A(Ok(()).into_future());
+fn handle_update((tgbot, update): (RcBot, Update), tgsvc: &Arc<TgSvc>) -> Result<(), ()> {
+    if let Some(query) = update.callback_query {
+        handle_callback(&tgbot, query, tgsvc);
+        return Ok(());
     }
-
-    let arg = msg.text.as_ref().and_then(|t| t.trim().split_whitespace()
-        .nth(0).and_then(|arg| arg.parse().ok()));
-    let resp = match (arg, me.whitelist.write()) {
-        (Some(id), Ok(mut wl)) => match op {
-            WhitelistModOp::Allow => {
-                wl.allow(id);
-                tokio::spawn(wl.save(WHITELIST_FILENAME));
-                format!("Allowed {}", id)
-            }
-            WhitelistModOp::Unallow => {
-                wl.unallow(id);
-                tokio::spawn(wl.save(WHITELIST_FILENAME));
-                format!("Unallowed {}", id)
-            }
-            WhitelistModOp::Block => {
-                wl.block(id);
-                tokio::spawn(wl.save(WHITELIST_FILENAME));
-                format!("Blocked {}", id)
-            }
-            WhitelistModOp::Unblock => {
-                wl.unblock(id);
-                tokio::spawn(wl.save(WHITELIST_FILENAME));
-                format!("Unblocked {}", id)
+    let msg = match update.message {
+        Some(msg) => msg,
+        None => return Ok(())
+    };
+    // sent to the new supergroup; the old group gets migrate_to_chat_id, which would be the same move
+    if let Some(from) = msg.migrate_from_chat_id {
+        tgsvc.dispatcher.migrate_chat(from, msg.chat.id);
+    }
+    if let Some(ref new_user) = msg.new_chat_member {
+        let chat_id = msg.chat.id;
+        if let (Ok(id), Ok(wl)) = (tgbot.inner.id.read(), tgsvc.dispatcher.whitelist().read()) {
+            if *id == Some(new_user.id) && !wl.group_ok(chat_id) {
+                // stay until the owners decide, if the group can ask them
+                let stay = tgsvc.config.msg_owner_id.is_some() && !wl.blocked.contains(&chat_id);
+                tgsvc.notify(Event::GroupAdded, &chat_id.to_string(), format!(
+                    "Added to {} (ID: {}) by {}, which is not on the whitelist; {}",
+                    msg.chat.title.as_ref().map(String::as_str).unwrap_or("a group"), chat_id,
+                    msg.from.as_ref().map(describe_user).unwrap_or_else(|| "somebody".to_owned()),
+                    if stay { "offering to request access" } else { "leaving" }));
+                let text = format!("You or this group is not on the whitelist. Seek help. ID: {}", chat_id);
+                if stay {
+                    tgsvc.dispatcher.offer_access(chat_id);
+                    tokio::spawn(call(&tgbot, "sendMessage", &SendMessage {
+                        chat_id,
+                        text,
+                        reply_to_message_id: None,
+                        reply_markup: access_keyboard(chat_id)
+                    }));
+                } else {
+                    tokio::spawn(nullify_future!("leaving group", tgbot.message(chat_id, text).send()
+                        .and_then(move |(tgbot, _)| tgbot.leave_chat(chat_id).send())));
+                }
             }
         }
-        (None, _) => "Invalid ID".to_owned(),
-        (_, Err(err)) => {
-            error!("error while acquiring RwLock: {}", err);
-            "error acquiring RwLock".to_owned()
-        }
-    };
-
-    Either::B(nullify_future!("sending message", tgbot.message(msg.chat.id, resp)
-        .reply_to_message_id(msg.message_id)
-        .send()))
+    }
+    Ok(())
 }
 
-fn handle_leave(me: &Arc<TgSvc>, tgbot: RcBot, msg: Message)
+fn handle_command(me: &Arc<TgSvc>, tgbot: RcBot, msg: Message, cmd: Command)
     -> impl Future<Item = (), Error = ()> {
-    if !is_from_owner(&msg, me) {
-        return Either::A(Ok(()).into_future());
-    }
-
-    let arg = msg.text.as_ref().and_then(|t| t.trim().split_whitespace()
-        .nth(0).and_then(|arg| arg.parse().ok()));
-    let resp = match arg {
-        Some(id) => {
-            tokio::spawn(nullify_future!("leaving group", tgbot.leave_chat(id).send()));
-            "OK"
-        }
-        None => "Invalid ID"
-    }.to_owned();
-
-    Either::B(nullify_future!("sending message", tgbot.message(msg.chat.id, resp)
-        .reply_to_message_id(msg.message_id)
-        .send()))
+    let sink = TgSink {
+        tgbot,
+        chat_id: msg.chat.id,
+        msg_id: msg.message_id,
+        group: msg.chat.kind != "private",
+        can_request: me.config.msg_owner_id.is_some()
+    };
+    tokio::spawn(Dispatcher::dispatch(&me.dispatcher, cmd, message_source(&msg),
+        msg.text.as_ref().map(|x| x.as_str()).unwrap_or(""), sink));
+    Ok(()).into_future()
 }
 
 impl TgSvc {
@@ -282,72 +366,212 @@ impl TgSvc {
             .map_err(|e| {
                 error!("failed to read evalbot.tg.toml: {}", e);
             });
-        let wlf = util::decode::<TgWhitelist, _>(WHITELIST_FILENAME).or_else(|e| {
-            warn!("failed to read whitelist: {}; using empty whitelist", e);
-            Ok(TgWhitelist {
-                priv_enabled: false,
-                group_enabled: false,
-                allowed: HashSet::new(),
-                blocked: HashSet::new(),
-            }).into_future()
-        });
-        cfgf.join(wlf).join(EvalService::from_toml_file("evalbot.toml")
-            .map_err(|e| {
-                error!("failed to read evalbot.toml: {}", e);
-            }))
-            .map(|((cfg, wl), es)| TgSvc {
-                config: cfg,
-                whitelist: RwLock::new(wl),
-                service: es
+        cfgf.join3(Whitelist::load(WHITELIST_FILENAME), Usage::load(QUOTA_FILENAME))
+            .join(EvalService::from_toml_file("evalbot.toml")
+                .map_err(|e| {
+                    error!("failed to read evalbot.toml: {}", e);
+                }))
+            .map(|((cfg, wl, usage), mut es)| {
+                for (alias, target) in &cfg.lang_subst {
+                    if !is_command_name(alias) {
+                        warn!("ignoring alias {}: not a valid Telegram command", alias);
+                    } else if Command::builtin(alias).is_some() {
+                        warn!("ignoring alias {}: already a command", alias);
+                    } else if let Err(e) = es.alias(alias, target) {
+                        warn!("ignoring alias {}: {}", alias, e);
+                    }
+                }
+                let bot = RcBot::new(&cfg.bot_id).expect("Failed to initialise Telegram bot");
+                let notifier = cfg.msg_owner_id.map(|owner_chat| {
+                    let tgbot = bot.clone();
+                    Arc::new(Notifier::new(cfg.notify.clone().unwrap_or_default(), move |text| -> ReplyFuture {
+                        Box::new(nullify_future!("notifying owner", tgbot.message(owner_chat, text).send()))
+                    }))
+                });
+                if let Some(ref notifier) = notifier {
+                    // last in the chain, so that it sees what the backend said
+                    if let Err(e) = es.attach(None, i32::MAX, notifier.clone()) {
+                        warn!("failed to count evaluation errors: {}", e);
+                    }
+                }
+                es.start();
+                let mut dispatcher = Dispatcher::new(TgFrontend, es, cfg.owners.clone(), wl,
+                    WHITELIST_FILENAME.to_owned());
+                if let Some(ref quota) = cfg.quota {
+                    dispatcher = dispatcher.with_quotas(Quotas::new(quota.clone(), usage, QUOTA_FILENAME.to_owned()));
+                }
+                TgSvc {
+                    config: cfg,
+                    bot,
+                    dispatcher: Arc::new(dispatcher),
+                    notifier
+                }
             })
             .and_then(TgSvc::handle)
     }
 
+    /// Tells the owner about `event` in the background, if anyone is to be told.
+    fn notify(&self, event: Event, subject: &str, text: String) {
+        if let Some(ref notifier) = self.notifier {
+            tokio::spawn(notifier.notify(event, subject, text));
+        }
+    }
+
+    /// Sends a chat's request for access, made with its "Request access" button, to the owner chat with
+    /// buttons to approve or deny it. Returns what to tell whoever pressed the button.
+    fn request_access(&self, tgbot: &RcBot, query: &CallbackQuery, chat_id: i64) -> String {
+        let owner_chat = match self.config.msg_owner_id {
+            Some(owner_chat) => owner_chat,
+            None => return "There is nobody to ask. Seek help.".to_owned()
+        };
+        // the button has to be in the chat it asks for
+        let chat = match query.message {
+            Some(ref msg) if msg.chat.id == chat_id => &msg.chat,
+            _ => return "Invalid request".to_owned()
+        };
+        let requester = describe_user(&query.from);
+        let text = match chat.title {
+            Some(ref title) => format!("{} asks for access for {} (ID: {})", requester, title, chat_id),
+            None => format!("{} asks for access (ID: {})", requester, chat_id)
+        };
+        match self.dispatcher.request_access(AccessRequest {
+            chat_id,
+            group: chat.kind != "private",
+            requester,
+            at: Instant::now()
+        }) {
+            Ok(()) => {
+                tokio::spawn(call(tgbot, "sendMessage", &SendMessage {
+                    chat_id: owner_chat,
+                    text,
+                    reply_to_message_id: None,
+                    reply_markup: buttons(&[("Approve", "approve"), ("Deny", "deny")], chat_id)
+                }));
+                "Asked the owners for access".to_owned()
+            }
+            Err(e) => e
+        }
+    }
+
+    /// Approves or denies a request from the owner chat, and tells the chat that asked. Groups that are
+    /// denied are left.
+    fn decide_access(&self, tgbot: &RcBot, query: &CallbackQuery, chat_id: i64, approve: bool) -> String {
+        let from = MessageSource {
+            chat_id: query.from.id,
+            user_id: Some(query.from.id),
+            user_name: query.from.username.clone(),
+            group: false
+        };
+        if !self.dispatcher.is_owner(&from) {
+            return "Only owners can decide on access requests".to_owned();
+        }
+        let req = match self.dispatcher.decide_access(chat_id, approve) {
+            Ok(req) => req,
+            Err(e) => return e
+        };
+        let decision = if approve { "approved" } else { "denied" };
+        if let Some(ref msg) = query.message {
+            tokio::spawn(call(tgbot, "editMessageText", &EditMessageText {
+                chat_id: msg.chat.id,
+                message_id: msg.message_id,
+                text: format!("{}\n{} by {}", msg.text.as_deref().unwrap_or("Access request"), decision,
+                    describe_user(&query.from))
+            }));
+        }
+        let told = tgbot.message(chat_id, format!("Your access request was {}", decision)).send();
+        if approve || !req.group {
+            tokio::spawn(nullify_future!("sending message", told));
+        } else {
+            tokio::spawn(nullify_future!("leaving group",
+                told.and_then(move |(tgbot, _)| tgbot.leave_chat(chat_id).send())));
+        }
+        format!("Access {} for {}", decision, chat_id)
+    }
+
     fn handle(self) -> impl Future<Item = (), Error = ()> {
-        let bot = RcBot::new(&self.config.bot_id).expect("Failed to initialise Telegram bot");
+        let bot = self.bot.clone();
         let me = Arc::new(self);
         bot.resolve_name();
 
-        macro_rules! handle {
-            ($cmd:expr, $handler:ident $(,$params:expr)*) => {{
-                let me = me.clone();
-                bot.register(bot.new_cmd($cmd)
-                    .map_err(|e| error!("error in command processing: {}", e))
-                    .and_then(move |(tgbot, msg)| $handler(&me, tgbot, msg $(,$params)*)));
-            }};
+        let commands = me.dispatcher.commands();
+        tokio::spawn(call(&bot, "setMyCommands", &bot_commands(&commands)));
+        for (name, cmd) in commands {
+            let me = me.clone();
+            bot.register(bot.new_cmd(&name)
+                .map_err(|e| error!("error in command processing: {}", e))
+                .and_then(move |(tgbot, msg)| handle_command(&me, tgbot, msg, cmd.clone())));
         }
 
-        for (name, lang) in me.service.langs() {
-            {
-                let lang = lang.clone();
-                handle!(name, handle_eval, &lang, false);
-            }
-            {
-                let lang = lang.clone();
-                handle!(&format!("{}#", name), handle_eval, &lang, true);
-            }
+        if let Some(ref notifier) = me.notifier {
+            tokio::spawn(Notifier::watch_health(notifier, me.dispatcher.service()));
         }
-
-        handle!("privwl", handle_whitelist_toggle, WhitelistToggleOp::TogglePrivate);
-        handle!("groupwl", handle_whitelist_toggle, WhitelistToggleOp::ToggleGroup);
-        handle!("allow", handle_whitelist_mod, WhitelistModOp::Allow);
-        handle!("unallow", handle_whitelist_mod, WhitelistModOp::Unallow);
-        handle!("block", handle_whitelist_mod, WhitelistModOp::Block);
-        handle!("unblock", handle_whitelist_mod, WhitelistModOp::Unblock);
-        handle!("leave", handle_leave);
-
+        let reloading = me.clone();
+        tokio::spawn(shutdown::hangups().for_each(move |_| {
+            let me = reloading.clone();
+            Dispatcher::reload_whitelist(&me.dispatcher).then(move |r| {
+                match r {
+                    Ok(()) => info!("reloaded whitelist"),
+                    Err(e) => {
+                        error!("failed to reload whitelist: {}", e);
+                        me.notify(Event::ReloadFailed, WHITELIST_FILENAME,
+                            format!("Failed to reload {}, keeping the old one: {}", WHITELIST_FILENAME, e));
+                    }
+                }
+                Ok(())
+            })
+        }));
+        let expiring = me.clone();
+        tokio::spawn(Dispatcher::whitelist_expiries(&me.dispatcher, WHITELIST_SWEEP).for_each(move |expiry| {
+            let me = &expiring;
+            let id = expiry.id;
+            let leave = !expiry.blocked && TgFrontend.is_group_id(id)
+                && me.dispatcher.whitelist().read().map(|wl| !wl.group_ok(id)).unwrap_or(false);
+            me.notify(Event::WhitelistExpired, &id.to_string(), format!("{} of {} expired{}",
+                if expiry.blocked { "Block" } else { "Allowance" }, id, if leave { "; leaving" } else { "" }));
+            if leave {
+                me.dispatcher.cancel_chat(id);
+                tokio::spawn(nullify_future!("leaving group", me.bot.leave_chat(id).send()));
+            }
+            Ok(())
+        }));
+        // groups that stayed to ask for access, but didn't or weren't answered in time
+        let unanswered = me.clone();
+        tokio::spawn(Dispatcher::access_expiries(&me.dispatcher, WHITELIST_SWEEP).for_each(move |id| {
+            info!("leaving {}: no access granted within a day", id);
+            unanswered.dispatcher.cancel_chat(id);
+            tokio::spawn(nullify_future!("leaving group", unanswered.bot
+                .message(id, "This group was not given access within a day; leaving".to_owned()).send()
+                .and_then(move |(tgbot, _)| tgbot.leave_chat(id).send())));
+            Ok(())
+        }));
+        me.notify(Event::Startup, "", format!("Started with {} languages", me.dispatcher.service().langs().count()));
+
+        let dispatcher = me.dispatcher.clone();
+        let deadline = Duration::from_secs(me.config.shutdown_timeout);
+        let notifier = me.notifier.clone();
         bot.get_stream()
             .map_err(|e| error!("{}", e))
             .for_each(move |tuple| handle_update(tuple, &me))
             .into_future()
-    }
-
-    fn is_owner(&self, name: &str) -> bool {
-        self.config.owners.contains(name)
+            .select2(shutdown::signal())
+            .then(move |_| -> ReplyFuture {
+                match notifier {
+                    Some(ref notifier) => notifier.notify(Event::Shutdown, "", "Shutting down".to_owned()),
+                    None => Box::new(Ok(()).into_future())
+                }
+            })
+            .then(move |_| Dispatcher::shutdown(&dispatcher, deadline))
     }
 }
 
 fn main() {
     env_logger::init();
-    tokio::run(TgSvc::run());
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("smoke") {
+        process::exit(smoke::run_cli("evalbot.toml", &args[1..]));
+    }
+    let mut rt = Runtime::new().expect("failed to start runtime");
+    let r = rt.block_on(TgSvc::run());
+    // background tasks such as health probes never finish by themselves
+    process::exit(if r.is_ok() { 0 } else { 1 });
 }